@@ -0,0 +1,35 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc, Weekday};
+use std::fmt;
+
+/// The reason a user has been refused access to a door
+#[derive(Debug, Clone, PartialEq)]
+pub enum Denied {
+    /// The user is disabled or their key is on the revocation list
+    Revoked,
+    /// The guest link has expired
+    Expired(DateTime<Utc>),
+    /// The guest link has been used as often as allowed
+    UsedUp,
+    NotAuthorized,
+    NotYetValid(NaiveDate),
+    NoLongerValid(NaiveDate),
+    Weekday(Weekday),
+    TimeOfDay(NaiveTime),
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Denied::Revoked => write!(f, "revoked"),
+            Denied::Expired(time) => write!(f, "guest link has expired at {time}"),
+            Denied::UsedUp => write!(f, "guest link has been used up"),
+            Denied::NotAuthorized => write!(f, "user is not authorized for door"),
+            Denied::NotYetValid(date) => write!(f, "schedule is not valid until {date}"),
+            Denied::NoLongerValid(date) => write!(f, "schedule has ended on {date}"),
+            Denied::Weekday(weekday) => write!(f, "access is not scheduled on {weekday}"),
+            Denied::TimeOfDay(time) => {
+                write!(f, "access is not scheduled at {}", time.format("%H:%M"))
+            }
+        }
+    }
+}
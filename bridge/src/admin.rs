@@ -0,0 +1,425 @@
+//! Local admin API to manage users and doors, served on a unix domain socket.
+//!
+//! Every connection can send json requests, one per line, and receives one json
+//! response per line. Access is controlled by the permissions of the socket.
+//! Keypairs for users are generated by the client, only public keys are sent.
+use crate::config::{self, Config};
+use crate::errors::*;
+use d3xs_protocol::crypto;
+use serde::{Deserialize, Serialize};
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{watch, Mutex};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    ListUsers,
+    ListDoors,
+    AddUser {
+        name: String,
+        public_key: String,
+        #[serde(default)]
+        authorize: Vec<String>,
+    },
+    UpdateUser {
+        name: String,
+        user: config::User,
+    },
+    RemoveUser {
+        name: String,
+    },
+    /// Replace the list of doors the user is authorized for
+    Authorize {
+        name: String,
+        doors: Vec<String>,
+    },
+    /// Replace the public key of the user, the old link stops working
+    RotateKey {
+        name: String,
+        public_key: String,
+    },
+    /// Add a door or replace an existing one
+    SetDoor {
+        id: String,
+        door: config::Door,
+    },
+    RemoveDoor {
+        id: String,
+    },
+}
+
+impl Request {
+    fn is_read_only(&self) -> bool {
+        matches!(self, Request::ListUsers | Request::ListDoors)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok {
+        #[serde(default)]
+        data: serde_json::Value,
+    },
+    Error {
+        error: String,
+    },
+}
+
+fn check_doors(config: &Config, doors: &[String]) -> Result<()> {
+    for door in doors {
+        if !config.doors.contains_key(door) {
+            bail!("Door does not exist: {door:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Apply a request to the config, returns the data for the response
+pub fn apply(config: &mut Config, request: Request) -> Result<serde_json::Value> {
+    let data = match request {
+        Request::ListUsers => serde_json::to_value(&config.users)?,
+        Request::ListDoors => serde_json::to_value(&config.doors)?,
+        Request::AddUser {
+            name,
+            public_key,
+            authorize,
+        } => {
+            if config.users.contains_key(&name) {
+                bail!("User already exists: {name:?}");
+            }
+            check_doors(config, &authorize)?;
+            crypto::public_key(&public_key).map_err(|_| anyhow!("Failed to decode public key"))?;
+
+            let user = config::User {
+                public_key,
+                authorize,
+                schedules: vec![],
+                disabled: false,
+                expires: None,
+                uses: None,
+            };
+            config.users.insert(name, user);
+            serde_json::Value::Null
+        }
+        Request::UpdateUser { name, user } => {
+            crypto::public_key(&user.public_key)
+                .map_err(|_| anyhow!("Failed to decode public key"))?;
+            check_doors(config, &user.authorize)?;
+            let current = config
+                .users
+                .get_mut(&name)
+                .with_context(|| anyhow!("User does not exist: {name:?}"))?;
+            *current = user;
+            serde_json::Value::Null
+        }
+        Request::RemoveUser { name } => {
+            config
+                .users
+                .remove(&name)
+                .with_context(|| anyhow!("User does not exist: {name:?}"))?;
+            serde_json::Value::Null
+        }
+        Request::Authorize { name, doors } => {
+            check_doors(config, &doors)?;
+            let user = config
+                .users
+                .get_mut(&name)
+                .with_context(|| anyhow!("User does not exist: {name:?}"))?;
+            user.authorize = doors;
+            serde_json::Value::Null
+        }
+        Request::RotateKey { name, public_key } => {
+            crypto::public_key(&public_key).map_err(|_| anyhow!("Failed to decode public key"))?;
+            let user = config
+                .users
+                .get_mut(&name)
+                .with_context(|| anyhow!("User does not exist: {name:?}"))?;
+            user.public_key = public_key;
+            serde_json::Value::Null
+        }
+        Request::SetDoor { id, door } => {
+            if let Some(public_key) = &door.public_key {
+                crypto::public_key(public_key)
+                    .map_err(|_| anyhow!("Failed to decode public key of door"))?;
+            }
+            config.doors.insert(id, door);
+            serde_json::Value::Null
+        }
+        Request::RemoveDoor { id } => {
+            config
+                .doors
+                .remove(&id)
+                .with_context(|| anyhow!("Door does not exist: {id:?}"))?;
+            for user in config.users.values_mut() {
+                user.authorize.retain(|door| *door != id);
+                // a schedule without doors applies to all of them, so drop it instead of emptying it
+                user.schedules.retain_mut(|schedule| {
+                    if schedule.doors.is_empty() {
+                        return true;
+                    }
+                    schedule.doors.retain(|door| *door != id);
+                    !schedule.doors.is_empty()
+                });
+            }
+            serde_json::Value::Null
+        }
+    };
+    Ok(data)
+}
+
+struct Admin {
+    config_path: PathBuf,
+    config_tx: Arc<watch::Sender<Config>>,
+    // only one change is written to disk at a time
+    lock: Mutex<()>,
+}
+
+impl Admin {
+    async fn handle(&self, request: Request) -> Result<serde_json::Value> {
+        if request.is_read_only() {
+            let mut config = self.config_tx.borrow().clone();
+            return apply(&mut config, request);
+        }
+
+        let _lock = self.lock.lock().await;
+        let mut config = self.config_tx.borrow().clone();
+        info!("Received admin request: {request:?}");
+        let data = apply(&mut config, request)?;
+
+        config.save_to_path(&self.config_path).await?;
+        // push the new config to the webserver right away, without waiting for the file watcher
+        self.config_tx.send_replace(config);
+
+        Ok(data)
+    }
+
+    async fn serve_client(&self, stream: UnixStream) -> Result<()> {
+        let (rx, mut tx) = stream.into_split();
+        let mut lines = BufReader::new(rx).lines();
+
+        while let Some(line) = lines.next_line().await? {
+            let response = match serde_json::from_str::<Request>(&line) {
+                Ok(request) => match self.handle(request).await {
+                    Ok(data) => Response::Ok { data },
+                    Err(err) => Response::Error {
+                        error: format!("{err:#}"),
+                    },
+                },
+                Err(err) => Response::Error {
+                    error: format!("Failed to parse request: {err:#}"),
+                },
+            };
+
+            let mut buf = serde_json::to_vec(&response)?;
+            buf.push(b'\n');
+            tx.write_all(&buf).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Serve the admin API on a unix domain socket, only accessible by the current user
+pub async fn serve(
+    socket: &Path,
+    config_path: PathBuf,
+    config_tx: Arc<watch::Sender<Config>>,
+) -> Result<()> {
+    // remove the socket of a previous run
+    match fs::remove_file(socket).await {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(|| anyhow!("Failed to remove {socket:?}")),
+    }
+    // bind to a temporary path and only move the socket in place once its permissions are set
+    let tmp = socket.with_extension("tmp");
+    match fs::remove_file(&tmp).await {
+        Ok(()) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(|| anyhow!("Failed to remove {tmp:?}")),
+    }
+    let listener =
+        UnixListener::bind(&tmp).with_context(|| anyhow!("Failed to bind admin socket {tmp:?}"))?;
+    fs::set_permissions(&tmp, Permissions::from_mode(0o600)).await?;
+    fs::rename(&tmp, socket)
+        .await
+        .with_context(|| anyhow!("Failed to move admin socket to {socket:?}"))?;
+    info!("Serving admin api at {socket:?}");
+
+    let admin = Arc::new(Admin {
+        config_path,
+        config_tx,
+        lock: Mutex::new(()),
+    });
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let admin = admin.clone();
+        tokio::spawn(async move {
+            if let Err(err) = admin.serve_client(stream).await {
+                warn!("Admin connection error: {err:#}");
+            }
+        });
+    }
+}
+
+/// Send a single request to the admin API of a running bridge
+pub async fn request(socket: &Path, request: &Request) -> Result<serde_json::Value> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| anyhow!("Failed to connect to admin socket {socket:?}"))?;
+    let (rx, mut tx) = stream.into_split();
+
+    let mut buf = serde_json::to_vec(request)?;
+    buf.push(b'\n');
+    tx.write_all(&buf).await?;
+
+    let line = BufReader::new(rx)
+        .lines()
+        .next_line()
+        .await?
+        .context("Bridge has closed the connection")?;
+    match serde_json::from_str(&line)? {
+        Response::Ok { data } => Ok(data),
+        Response::Error { error } => Err(anyhow!("{error}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::BASE64;
+
+    fn config() -> Config {
+        Config::parse(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.alice]
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+authorize = ["home", "building"]
+
+[[users.alice.schedules]]
+doors = ["building"]
+weekdays = ["Mon"]
+
+[[users.alice.schedules]]
+doors = ["home", "building"]
+times = ["08:00-17:00"]
+
+[[users.alice.schedules]]
+valid_until = "2030-01-01"
+
+[doors.home]
+label = "Home"
+
+[doors.building]
+label = "Building"
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn manage_users() -> Result<()> {
+        let mut config = config();
+        let public_key = BASE64.encode(
+            crypto::generate_secret_key::<crypto::Random>()
+                .public_key()
+                .as_bytes(),
+        );
+
+        apply(
+            &mut config,
+            Request::AddUser {
+                name: "bob".to_string(),
+                public_key: public_key.clone(),
+                authorize: vec!["home".to_string()],
+            },
+        )?;
+        assert_eq!(config.users["bob"].public_key, public_key);
+        assert_eq!(config.users["bob"].authorize, vec!["home".to_string()]);
+
+        assert!(apply(
+            &mut config,
+            Request::RotateKey {
+                name: "bob".to_string(),
+                public_key: "invalid".to_string(),
+            },
+        )
+        .is_err());
+        let next = BASE64.encode(
+            crypto::generate_secret_key::<crypto::Random>()
+                .public_key()
+                .as_bytes(),
+        );
+        let data = apply(
+            &mut config,
+            Request::RotateKey {
+                name: "bob".to_string(),
+                public_key: next.clone(),
+            },
+        )?;
+        // secret keys are never sent back
+        assert!(data.is_null());
+        assert_eq!(config.users["bob"].public_key, next);
+
+        assert!(apply(
+            &mut config,
+            Request::Authorize {
+                name: "bob".to_string(),
+                doors: vec!["garage".to_string()],
+            },
+        )
+        .is_err());
+        apply(
+            &mut config,
+            Request::RemoveUser {
+                name: "bob".to_string(),
+            },
+        )?;
+        assert!(!config.users.contains_key("bob"));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_door() -> Result<()> {
+        let mut config = config();
+        apply(
+            &mut config,
+            Request::RemoveDoor {
+                id: "building".to_string(),
+            },
+        )?;
+        assert!(!config.doors.contains_key("building"));
+        assert_eq!(config.users["alice"].authorize, vec!["home".to_string()]);
+
+        // schedules that only applied to the door are gone, instead of applying to all doors
+        let schedules = &config.users["alice"].schedules;
+        assert_eq!(schedules.len(), 2);
+        assert_eq!(schedules[0].doors, vec!["home".to_string()]);
+        assert!(schedules[1].doors.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_request() -> Result<()> {
+        let request = serde_json::from_str::<Request>(
+            r#"{"type":"authorize","name":"alice","doors":["home"]}"#,
+        )?;
+        assert_eq!(
+            request,
+            Request::Authorize {
+                name: "alice".to_string(),
+                doors: vec!["home".to_string()],
+            }
+        );
+        Ok(())
+    }
+}
@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[derive(Debug, clap::Parser)]
@@ -12,8 +13,14 @@ pub struct Args {
 #[derive(Debug, clap::Subcommand)]
 pub enum SubCommand {
     Open(Open),
+    Command(SendCommand),
+    Provision(Provision),
     Connect(Connect),
     Keygen(Keygen),
+    Audit(Audit),
+    Admin(Admin),
+    Check(Check),
+    Rotate(Rotate),
 }
 
 /// Connect to a door and open it
@@ -22,6 +29,70 @@ pub struct Open {
     pub mac: String,
     pub public_key: String,
     pub secret_key: String,
+    /// Which output of the door to trigger, for doors with multiple relays
+    #[arg(long)]
+    pub output: Option<u8>,
+    /// How many seconds until the operation times out (0 for no limit)
+    #[arg(short, long, default_value = "15")]
+    pub timeout: u64,
+}
+
+/// Send an authenticated command to a door
+#[derive(Debug, clap::Parser)]
+pub struct SendCommand {
+    pub mac: String,
+    pub public_key: String,
+    pub secret_key: String,
+    /// What the door should do
+    pub action: CommandAction,
+    /// The output of the door, for `open`, `hold-open` and `lock`
+    #[arg(long, default_value = "0")]
+    pub output: u8,
+    /// How many seconds to open the door for (the door's configured time by default)
+    #[arg(long)]
+    pub seconds: Option<u16>,
+    /// How many seconds until the operation times out (0 for no limit)
+    #[arg(short, long, default_value = "15")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum CommandAction {
+    /// Switch on the output for some seconds
+    Open,
+    /// Switch on the output until it's locked again
+    HoldOpen,
+    /// Switch off the output
+    Lock,
+    /// Read the status of the door
+    Status,
+    /// Blink the led of the door controller
+    Identify,
+}
+
+/// Update the keys and settings stored on a door
+#[derive(Debug, clap::Parser)]
+pub struct Provision {
+    pub mac: String,
+    /// The current public key of the door
+    pub public_key: String,
+    /// The bridge secret key the door currently trusts
+    pub secret_key: String,
+    /// Replace the secret key of the door
+    #[arg(long)]
+    pub door_key: Option<String>,
+    /// Replace the bridge public key the door trusts
+    #[arg(long)]
+    pub bridge_key: Option<String>,
+    /// Replace the bluetooth name of the door
+    #[arg(long)]
+    pub ble_name: Option<String>,
+    /// Replace the outputs of the door, in the format of `D3XS_OUTPUTS`
+    #[arg(long)]
+    pub outputs: Option<String>,
+    /// Replace the door contact sensor, in the format of `D3XS_CONTACT` (`none` to remove it)
+    #[arg(long)]
+    pub contact: Option<String>,
     /// How many seconds until the operation times out (0 for no limit)
     #[arg(short, long, default_value = "15")]
     pub timeout: u64,
@@ -37,6 +108,29 @@ pub struct Connect {
     /// How many seconds until the bluetooth operation times out (0 for no limit)
     #[arg(short, long, default_value = "15")]
     pub timeout: u64,
+    /// How to talk to the doors
+    #[arg(long, value_enum, default_value_t = BleBackend::Btleplug)]
+    pub ble_backend: BleBackend,
+    /// Add a simulated door for `--ble-backend=sim` (can be used multiple times)
+    #[arg(long = "sim-door", value_name = "MAC=SECRET_KEY")]
+    pub sim_doors: Vec<String>,
+    /// Serve prometheus metrics on this address, e.g. `127.0.0.1:9100`
+    #[arg(long, env = "D3XS_METRICS_BIND")]
+    pub metrics: Option<SocketAddr>,
+    /// Check every n seconds if the doors are reachable (0 to disable)
+    #[arg(long, default_value = "300")]
+    pub health_interval: u64,
+    /// Serve the admin api on this unix domain socket
+    #[arg(long, env = "D3XS_ADMIN_SOCKET")]
+    pub admin_socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum BleBackend {
+    /// Use the bluetooth adapter of the system
+    Btleplug,
+    /// Simulate doors in-process, for testing without bluetooth hardware
+    Sim,
 }
 
 /// Generate a keypair
@@ -59,4 +153,108 @@ pub struct Keygen {
     /// Read secret key from stdin instead of generating
     #[arg(long)]
     pub stdin: bool,
+    /// Doors the user is authorized for (can be used multiple times)
+    #[arg(long = "door")]
+    pub doors: Vec<String>,
+    /// Generate a guest link that's only valid for a limited time or number of uses
+    #[arg(long)]
+    pub guest: bool,
+    /// How long the guest link is valid, e.g. `30m`, `2h` or `7d`
+    #[arg(long, requires = "guest", value_parser = crate::guests::parse_duration)]
+    pub expires: Option<chrono::Duration>,
+    /// How often the guest link can be used to open a door
+    #[arg(long, requires = "guest")]
+    pub uses: Option<u32>,
+}
+
+/// Check the config for mistakes, like keys that are not valid or unknown doors
+#[derive(Debug, clap::Parser)]
+pub struct Check {
+    #[arg(short, long, env = "D3XS_CONFIG")]
+    pub config: PathBuf,
+}
+
+/// Guide the rotation of the bridge key, run it again for the next step
+#[derive(Debug, clap::Parser)]
+pub struct Rotate {
+    #[arg(short, long, env = "D3XS_CONFIG")]
+    pub config: PathBuf,
+}
+
+/// Inspect the access audit log
+#[derive(Debug, clap::Parser)]
+pub struct Audit {
+    #[command(subcommand)]
+    pub subcommand: AuditCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum AuditCommand {
+    Verify(AuditVerify),
+}
+
+/// Verify the hash chain of the audit log, this detects accidental corruption
+#[derive(Debug, clap::Parser)]
+pub struct AuditVerify {
+    /// Path to the audit log (read from config if not provided)
+    pub path: Option<PathBuf>,
+    #[arg(short, long, env = "D3XS_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+/// Manage users and doors of a running bridge
+#[derive(Debug, clap::Parser)]
+pub struct Admin {
+    /// Path to the admin socket of the bridge
+    #[arg(short, long, env = "D3XS_ADMIN_SOCKET")]
+    pub socket: PathBuf,
+    /// Url of the webserver, used to print links for new keys
+    #[arg(long)]
+    pub url: Option<String>,
+    #[command(subcommand)]
+    pub subcommand: AdminCommand,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum AdminCommand {
+    /// List all users
+    Users,
+    /// List all doors
+    Doors,
+    /// Add a user, a keypair is generated unless a public key is provided
+    AddUser {
+        name: String,
+        #[arg(long)]
+        public_key: Option<String>,
+        /// Doors the user is authorized for (can be used multiple times)
+        #[arg(long = "door")]
+        doors: Vec<String>,
+    },
+    /// Remove a user
+    RemoveUser { name: String },
+    /// Replace the doors a user is authorized for
+    Authorize { name: String, doors: Vec<String> },
+    /// Generate a new keypair for a user, their old link stops working
+    RotateKey { name: String },
+    /// Add a door or replace an existing one
+    SetDoor {
+        id: String,
+        #[arg(long)]
+        label: String,
+        #[arg(long)]
+        mac: Option<String>,
+        #[arg(long)]
+        public_key: Option<String>,
+        /// Issue tokens to users so they can open the door without the bridge
+        #[arg(long)]
+        offline: bool,
+        /// The output of the door controller to trigger, if it has multiple relays
+        #[arg(long)]
+        output: Option<u8>,
+        /// Raise an alert if the door is left open for longer than this (in seconds)
+        #[arg(long)]
+        left_open_after: Option<u64>,
+    },
+    /// Remove a door, it's also removed from every user
+    RemoveDoor { id: String },
 }
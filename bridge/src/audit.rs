@@ -0,0 +1,262 @@
+use crate::errors::*;
+use chrono::{DateTime, Utc};
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Fetch,
+    Solved,
+    SolveFailed,
+    Unauthorized,
+    Open,
+    /// The door contact reported the door as opened without it being unlocked
+    ForcedEntry,
+    /// The door contact reported the door as open for too long
+    LeftOpen,
+}
+
+/// A single line of the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub event: Event,
+    pub user: String,
+    pub door: String,
+    pub outcome: String,
+    /// Hash of the previous entry
+    pub prev: String,
+    pub hash: String,
+}
+
+// everything besides the hash itself, this is what gets hashed
+#[derive(Serialize)]
+struct Unsigned<'a> {
+    time: &'a DateTime<Utc>,
+    event: Event,
+    user: &'a str,
+    door: &'a str,
+    outcome: &'a str,
+    prev: &'a str,
+}
+
+impl Entry {
+    pub fn new(
+        prev: String,
+        time: DateTime<Utc>,
+        event: Event,
+        user: String,
+        door: String,
+        outcome: String,
+    ) -> Result<Self> {
+        let mut entry = Entry {
+            time,
+            event,
+            user,
+            door,
+            outcome,
+            prev,
+            hash: String::new(),
+        };
+        entry.hash = entry.calculate_hash()?;
+        Ok(entry)
+    }
+
+    pub fn calculate_hash(&self) -> Result<String> {
+        let unsigned = serde_json::to_vec(&Unsigned {
+            time: &self.time,
+            event: self.event,
+            user: &self.user,
+            door: &self.door,
+            outcome: &self.outcome,
+            prev: &self.prev,
+        })?;
+
+        let mut hasher = Sha256::new();
+        hasher.update(&unsigned);
+        Ok(HEXLOWER.encode(&hasher.finalize()))
+    }
+}
+
+/// Append-only log of access events, each entry is chained to the previous one by hash
+///
+/// Writes are serialized internally, so the log can be shared between tasks.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<(PathBuf, Mutex<String>)>,
+}
+
+impl AuditLog {
+    pub async fn open(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let prev = match fs::read_to_string(path).await {
+            Ok(buf) => {
+                if let Some(line) = buf.lines().rfind(|l| !l.is_empty()) {
+                    let entry = serde_json::from_str::<Entry>(line)
+                        .with_context(|| anyhow!("Failed to parse last entry of {path:?}"))?;
+                    entry.hash
+                } else {
+                    GENESIS_HASH.to_string()
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => GENESIS_HASH.to_string(),
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Failed to read audit log {path:?}"))
+            }
+        };
+
+        info!("Writing audit log to {path:?}");
+        Ok(AuditLog {
+            file: Some((path.to_owned(), Mutex::new(prev))),
+        })
+    }
+
+    async fn append(&self, event: Event, user: &str, door: &str, outcome: &str) -> Result<()> {
+        let Some((path, prev)) = &self.file else {
+            return Ok(());
+        };
+        // hold the lock until the entry is written, so the chain stays in order
+        let mut prev = prev.lock().await;
+
+        let entry = Entry::new(
+            prev.clone(),
+            Utc::now(),
+            event,
+            user.to_string(),
+            door.to_string(),
+            outcome.to_string(),
+        )?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.sync_data().await?;
+
+        *prev = entry.hash;
+        Ok(())
+    }
+
+    /// Write an entry to the audit log, failures are logged but not fatal
+    pub async fn record(&self, event: Event, user: &str, door: &str, outcome: &str) {
+        if let Err(err) = self.append(event, user, door, outcome).await {
+            error!("Failed to write to audit log: {err:#}");
+        }
+    }
+}
+
+/// Verify the hash chain of an audit log, returns the number of entries
+pub fn verify(buf: &str) -> Result<usize> {
+    let mut prev = GENESIS_HASH.to_string();
+    let mut count = 0;
+
+    for (idx, line) in buf.lines().enumerate() {
+        let lineno = idx + 1;
+        if line.is_empty() {
+            continue;
+        }
+
+        let entry = serde_json::from_str::<Entry>(line)
+            .with_context(|| anyhow!("Failed to parse entry in line {lineno}"))?;
+        if entry.prev != prev {
+            bail!("Entry in line {lineno} is not chained to the previous entry");
+        }
+        if entry.calculate_hash()? != entry.hash {
+            bail!("Entry in line {lineno} has been modified (hash mismatch)");
+        }
+
+        prev = entry.hash;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+pub async fn verify_file(path: &Path) -> Result<usize> {
+    let buf = fs::read_to_string(path)
+        .await
+        .with_context(|| anyhow!("Failed to read audit log {path:?}"))?;
+    verify(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("d3xs-{}-{name}", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        path
+    }
+
+    #[tokio::test]
+    async fn write_and_verify() -> Result<()> {
+        let path = temp_path("audit-verify.jsonl");
+
+        let audit = AuditLog::open(Some(&path)).await?;
+        audit
+            .append(Event::Fetch, "alice", "home", "issued")
+            .await?;
+        audit.append(Event::Solved, "alice", "home", "ok").await?;
+
+        // reopening continues the existing chain
+        let audit = AuditLog::open(Some(&path)).await?;
+        audit.append(Event::Open, "alice", "home", "opened").await?;
+
+        assert_eq!(verify_file(&path).await?, 3);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn detect_tampering() -> Result<()> {
+        let path = temp_path("audit-tamper.jsonl");
+
+        let audit = AuditLog::open(Some(&path)).await?;
+        audit
+            .append(Event::Fetch, "alice", "home", "issued")
+            .await?;
+        audit
+            .append(
+                Event::Unauthorized,
+                "mallory",
+                "home",
+                "user is not authorized for door",
+            )
+            .await?;
+        audit.append(Event::Fetch, "bob", "home", "issued").await?;
+        let buf = std::fs::read_to_string(&path)?;
+        std::fs::remove_file(&path)?;
+
+        let modified = buf.replace("mallory", "alice");
+        let err = verify(&modified).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Entry in line 2 has been modified (hash mismatch)"
+        );
+
+        let mut lines = buf.lines().collect::<Vec<_>>();
+        lines.remove(1);
+        let err = verify(&lines.join("\n")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Entry in line 2 is not chained to the previous entry"
+        );
+        Ok(())
+    }
+}
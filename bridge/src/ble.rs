@@ -1,17 +1,34 @@
 use crate::errors::*;
+use crate::metrics::{Metrics, METRICS};
+use crate::transport::{self, DoorError, Transport};
+use bluez_async::BluetoothError;
 use btleplug::api::{
     bleuuid::uuid_from_u16, BDAddr, Central, CentralEvent, Characteristic, Manager as _,
-    Peripheral as _, ScanFilter, WriteType,
+    Peripheral as _, ScanFilter, ValueNotification, WriteType,
 };
 use btleplug::platform::{Adapter, Manager, Peripheral};
-use d3xs_protocol::crypto;
-use futures_util::StreamExt;
+use d3xs_protocol::chall::WriteResult;
+use d3xs_protocol::command::{Command, Response};
+use d3xs_protocol::status::Status;
+use d3xs_protocol::{crypto, provision};
+use futures_util::{Stream, StreamExt};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::{mpsc, Mutex};
 use tokio::time;
 use uuid::Uuid;
 
 const SERVICE_UUID: Uuid = uuid_from_u16(0xFFFF);
 const CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xAAAA);
+const PROVISION_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xAAAC);
+const STATUS_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xAAAD);
+const COMMAND_CHARACTERISTIC_UUID: Uuid = uuid_from_u16(0xAAAE);
 const BLE_SOLVE_ATTEMPTS: u8 = 4;
+/// How often a subscribed door is checked for a disconnect while it's quiet
+const SUBSCRIBE_POLL_INTERVAL: time::Duration = time::Duration::from_secs(30);
+
+/// Held while talking to a door, the health checks and opens share the same adapter
+static BLE_LOCK: Mutex<()> = Mutex::const_new(());
 
 async fn find_by_mac(central: &Adapter, mac: &BDAddr) -> Result<Option<Peripheral>> {
     for p in central.peripherals().await? {
@@ -22,8 +39,42 @@ async fn find_by_mac(central: &Adapter, mac: &BDAddr) -> Result<Option<Periphera
     Ok(None)
 }
 
+// bluez reports rejected writes as a dbus error "Operation failed with ATT error: 0x80"
+fn att_error(err: &btleplug::Error) -> Option<u8> {
+    let btleplug::Error::Other(err) = err else {
+        return None;
+    };
+    let BluetoothError::DbusError(err) = err.downcast_ref::<BluetoothError>()? else {
+        return None;
+    };
+    let (_, code) = err.message()?.split_once("ATT error: 0x")?;
+    u8::from_str_radix(code.get(..2)?, 16).ok()
+}
+
+/// Write with response, so the result the door has responded with is known
+async fn write_acked(
+    peripheral: &Peripheral,
+    characteristic: &Characteristic,
+    buf: &[u8],
+) -> Result<()> {
+    let Err(err) = peripheral
+        .write(characteristic, buf, WriteType::WithResponse)
+        .await
+    else {
+        return Ok(());
+    };
+    match att_error(&err)
+        .and_then(WriteResult::from_code)
+        .and_then(DoorError::from_result)
+    {
+        Some(err) => Err(err.into()),
+        None => Err(err.into()),
+    }
+}
+
 async fn try_solve_service(
-    salsa: &crypto::SalsaBox,
+    keys: &[crypto::SalsaBox],
+    output: Option<u8>,
     peripheral: Peripheral,
     characteristic: Characteristic,
 ) -> Result<()> {
@@ -35,19 +86,15 @@ async fn try_solve_service(
         bail!("Challenge can't be empty");
     }
 
-    let mut decrypted = [0u8; 4096];
-    let decrypted = crypto::decrypt(salsa, &chall, &mut decrypted)
-        .map_err(|_| anyhow!("Failed to decrypt solution"))?;
+    let solution = transport::solve(keys, &chall, output)?;
 
-    info!("Sending solution");
-    peripheral
-        .write(&characteristic, decrypted, WriteType::WithoutResponse)
-        .await?;
+    info!("Sending solution (output={output:?})");
+    write_acked(&peripheral, &characteristic, &solution).await?;
 
     Ok(())
 }
 
-async fn try_solve(salsa: &crypto::SalsaBox, peripheral: Peripheral) -> Result<()> {
+async fn find_characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Characteristic> {
     let mac = peripheral.address();
 
     info!("Connecting to peripheral (mac={mac:?})");
@@ -57,18 +104,111 @@ async fn try_solve(salsa: &crypto::SalsaBox, peripheral: Peripheral) -> Result<(
     peripheral.discover_services().await?;
 
     debug!("Enumerating characteristics...");
-    let characteristic = peripheral
+    let characteristic = discovered_characteristic(peripheral, uuid)?;
+    debug!("Found characteristic with matching uuid: {characteristic:?}");
+
+    Ok(characteristic)
+}
+
+// find a characteristic of a peripheral that is already connected
+fn discovered_characteristic(peripheral: &Peripheral, uuid: Uuid) -> Result<Characteristic> {
+    peripheral
         .characteristics()
         .into_iter()
         .filter(|chr| chr.service_uuid == SERVICE_UUID)
-        .find(|chr| chr.uuid == CHARACTERISTIC_UUID)
-        .context("Failed to find service")?;
-    debug!("Found characteristic with matching uuid: {characteristic:?}");
+        .find(|chr| chr.uuid == uuid)
+        .context("Failed to find service")
+}
+
+async fn try_solve(
+    keys: &[crypto::SalsaBox],
+    output: Option<u8>,
+    peripheral: Peripheral,
+) -> Result<()> {
+    let characteristic = find_characteristic(&peripheral, CHARACTERISTIC_UUID).await?;
+    try_solve_service(keys, output, peripheral, characteristic).await
+}
+
+async fn try_provision(
+    salsa: &crypto::SalsaBox,
+    settings: &provision::Settings,
+    peripheral: Peripheral,
+) -> Result<()> {
+    let characteristic = find_characteristic(&peripheral, PROVISION_CHARACTERISTIC_UUID).await?;
+
+    info!("Requesting provisioning challenge");
+    let chall = peripheral.read(&characteristic).await?;
+    if chall.is_empty() {
+        bail!("Challenge can't be empty");
+    }
+
+    let mut decrypted = [0u8; 4096];
+    let decrypted = crypto::decrypt(salsa, &chall, &mut decrypted)
+        .map_err(|_| anyhow!("Failed to decrypt challenge"))?;
+    let encrypted = settings
+        .seal::<crypto::Random>(salsa, decrypted)
+        .map_err(|_| anyhow!("Failed to encrypt settings"))?;
+
+    info!("Sending settings");
+    write_acked(&peripheral, &characteristic, &encrypted).await?;
+
+    Ok(())
+}
+
+async fn try_status(peripheral: Peripheral) -> Result<Status> {
+    let characteristic = find_characteristic(&peripheral, STATUS_CHARACTERISTIC_UUID).await?;
+    let buf = peripheral.read(&characteristic).await?;
+    let status = Status::decode(&buf).map_err(|err| anyhow!("Failed to decode status: {err}"))?;
+    peripheral.disconnect().await.ok();
+    Ok(status)
+}
+
+type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
+
+async fn try_subscribe(peripheral: Peripheral) -> Result<(Peripheral, Notifications)> {
+    let characteristic = find_characteristic(&peripheral, STATUS_CHARACTERISTIC_UUID).await?;
+    let notifications = peripheral.notifications().await?;
+    peripheral.subscribe(&characteristic).await?;
+    Ok((peripheral, notifications))
+}
+
+async fn try_command(
+    keys: &[crypto::SalsaBox],
+    command: Command,
+    peripheral: Peripheral,
+) -> Result<Response> {
+    let characteristic = find_characteristic(&peripheral, CHARACTERISTIC_UUID).await?;
+    let command_characteristic =
+        discovered_characteristic(&peripheral, COMMAND_CHARACTERISTIC_UUID)
+            .context("Door does not support commands")?;
 
-    try_solve_service(salsa, peripheral, characteristic).await
+    info!("Requesting challenge");
+    let chall = peripheral.read(&characteristic).await?;
+    if chall.is_empty() {
+        bail!("Challenge can't be empty");
+    }
+    let (salsa, request, encrypted) = transport::command(keys, &chall, command)?;
+
+    info!("Sending command: {command:?}");
+    write_acked(&peripheral, &command_characteristic, &encrypted).await?;
+    let result = peripheral.read(&command_characteristic).await?;
+    peripheral.disconnect().await.ok();
+    transport::command_result(salsa, &request, &result)
 }
 
-async fn try_open(salsa: &crypto::SalsaBox, central: &Adapter, mac: &BDAddr) -> Result<()> {
+// scan for the peripheral and run `f` on it, until it succeeds or the attempts are used up
+//
+// only attempts to open a door are counted in the metrics, not health checks
+async fn try_peripheral<T, F, Fut>(
+    central: &Adapter,
+    mac: &BDAddr,
+    count_attempts: bool,
+    f: F,
+) -> Result<T>
+where
+    F: Fn(Peripheral) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
     let mut events = central.events().await?;
     central.start_scan(ScanFilter::default()).await?;
 
@@ -80,15 +220,23 @@ async fn try_open(salsa: &crypto::SalsaBox, central: &Adapter, mac: &BDAddr) ->
                 .await
                 .context("Failed to enumerate peripherals")?
             {
-                match try_solve(salsa, peripheral).await {
-                    Ok(_) => {
-                        return Ok(());
+                if count_attempts {
+                    Metrics::inc(&METRICS.ble_attempts);
+                }
+                match f(peripheral).await {
+                    Ok(value) => {
+                        return Ok(value);
                     }
                     Err(err) => {
-                        error!("Failed to solve challenge: {err:#}");
+                        error!("Failed to talk to door: {err:#}");
+                        // the door has checked the solution or output and refused it, trying
+                        // again won't help
+                        if err.downcast_ref::<DoorError>() == Some(&DoorError::Rejected) {
+                            return Err(err);
+                        }
                         attempts -= 1;
                         if attempts == 0 {
-                            bail!("Failed to open, too many failed attempts");
+                            bail!("Failed to talk to door, too many failed attempts");
                         }
                     }
                 }
@@ -99,8 +247,19 @@ async fn try_open(salsa: &crypto::SalsaBox, central: &Adapter, mac: &BDAddr) ->
     bail!("Event stream disconnected")
 }
 
-pub async fn open(salsa: &crypto::SalsaBox, mac: &str, timeout: u64) -> Result<()> {
+async fn with_peripheral<T, F, Fut>(
+    mac: &str,
+    timeout: u64,
+    count_attempts: bool,
+    f: F,
+) -> Result<T>
+where
+    F: Fn(Peripheral) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
     let mac = BDAddr::from_str_delim(mac)?;
+    // scanning and connecting concurrently on the same adapter interferes with each other
+    let _lock = BLE_LOCK.lock().await;
     let manager = Manager::new().await.unwrap();
 
     let adapters = manager.adapters().await?;
@@ -109,17 +268,169 @@ pub async fn open(salsa: &crypto::SalsaBox, mac: &str, timeout: u64) -> Result<(
         .next()
         .context("No bluetooth adapters found")?;
 
-    let future = try_open(salsa, &central, &mac);
+    let future = try_peripheral(&central, &mac, count_attempts, f);
 
     if timeout == 0 {
-        future.await?;
+        future.await
     } else {
         let timeout = time::Duration::from_secs(timeout);
         time::timeout(timeout, future)
             .await
+            .inspect_err(|_| Metrics::inc(&METRICS.ble_timeouts))
             .context("Operation has timed out")?
-            .context("Operation has failed")?;
+            .context("Operation has failed")
     }
+}
+
+pub async fn open(
+    keys: &[crypto::SalsaBox],
+    mac: &str,
+    output: Option<u8>,
+    timeout: u64,
+) -> Result<()> {
+    with_peripheral(mac, timeout, true, |peripheral| {
+        try_solve(keys, output, peripheral)
+    })
+    .await
+}
 
+/// Read the status of a door
+pub async fn status(mac: &str, timeout: u64) -> Result<Status> {
+    with_peripheral(mac, timeout, false, try_status).await
+}
+
+/// Send an authenticated command to a door
+pub async fn command(
+    keys: &[crypto::SalsaBox],
+    mac: &str,
+    command: Command,
+    timeout: u64,
+) -> Result<Response> {
+    with_peripheral(mac, timeout, false, |peripheral| {
+        try_command(keys, command, peripheral)
+    })
+    .await
+}
+
+/// Stay connected to a door and forward the status it notifies about, until it disconnects
+pub async fn subscribe(mac: &str, timeout: u64, tx: mpsc::Sender<Status>) -> Result<()> {
+    // the bluetooth lock is only held while connecting
+    let (peripheral, mut notifications) =
+        with_peripheral(mac, timeout, false, try_subscribe).await?;
+    info!("Subscribed to status of door (mac={mac:?})");
+
+    loop {
+        match time::timeout(SUBSCRIBE_POLL_INTERVAL, notifications.next()).await {
+            Ok(Some(notification)) => {
+                if notification.uuid != STATUS_CHARACTERISTIC_UUID {
+                    continue;
+                }
+                match Status::decode(&notification.value) {
+                    Ok(status) => {
+                        if tx.send(status).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => warn!("Failed to decode status notification: {err}"),
+                }
+            }
+            Ok(None) => break,
+            // the stream doesn't necessarily end when the door disconnects
+            Err(_) => {
+                if !peripheral.is_connected().await? {
+                    break;
+                }
+            }
+        }
+    }
+
+    info!("Door has disconnected (mac={mac:?})");
     Ok(())
 }
+
+/// Update the keys and settings of a door, `salsa` needs to use the bridge key the door currently has
+pub async fn provision(
+    salsa: &crypto::SalsaBox,
+    mac: &str,
+    settings: &provision::Settings,
+    timeout: u64,
+) -> Result<()> {
+    with_peripheral(mac, timeout, false, |peripheral| {
+        try_provision(salsa, settings, peripheral)
+    })
+    .await
+}
+
+/// Talk to doors over bluetooth low energy, using btleplug
+pub struct Btleplug;
+
+impl Transport for Btleplug {
+    async fn open(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        output: Option<u8>,
+        timeout: u64,
+    ) -> Result<()> {
+        open(keys, mac, output, timeout).await
+    }
+
+    async fn status(&self, mac: &str, timeout: u64) -> Result<Status> {
+        status(mac, timeout).await
+    }
+
+    async fn command(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        command: Command,
+        timeout: u64,
+    ) -> Result<Response> {
+        self::command(keys, mac, command, timeout).await
+    }
+
+    async fn subscribe(&self, mac: &str, timeout: u64, tx: mpsc::Sender<Status>) -> Result<()> {
+        subscribe(mac, timeout, tx).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_att_error() {
+        let err = |name: &str, msg: &str| {
+            btleplug::Error::from(BluetoothError::DbusError(dbus::Error::new_custom(
+                name, msg,
+            )))
+        };
+        assert_eq!(
+            att_error(&err(
+                "org.bluez.Error.Failed",
+                "Operation failed with ATT error: 0x80"
+            )),
+            Some(0x80)
+        );
+        assert_eq!(
+            att_error(&err(
+                "org.bluez.Error.Failed",
+                "Operation failed with ATT error: 0x81"
+            ))
+            .and_then(WriteResult::from_code),
+            Some(WriteResult::NoChallenge)
+        );
+        assert_eq!(
+            att_error(&err("org.bluez.Error.Failed", "Not connected")),
+            None
+        );
+        assert_eq!(att_error(&btleplug::Error::NotConnected), None);
+        // only errors reported by bluez are considered
+        assert_eq!(
+            att_error(&btleplug::Error::Other(
+                "Operation failed with ATT error: 0x80".into()
+            )),
+            None
+        );
+    }
+}
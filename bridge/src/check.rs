@@ -0,0 +1,351 @@
+//! Find mistakes in the config that would otherwise only show up at runtime.
+use crate::config::Config;
+use crate::errors::*;
+use crate::notify;
+use btleplug::api::BDAddr;
+use d3xs_protocol::crypto;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use toml::Spanned;
+
+// a view of the config that keeps track of where each value is located in the file
+#[derive(Deserialize)]
+struct SpannedConfig {
+    system: SpannedSystem,
+    #[serde(default)]
+    users: BTreeMap<String, Spanned<SpannedUser>>,
+    #[serde(default)]
+    doors: BTreeMap<String, Spanned<SpannedDoor>>,
+    #[serde(default)]
+    notify: Vec<SpannedNotify>,
+}
+
+#[derive(Deserialize)]
+struct SpannedSystem {
+    secret_key: Spanned<String>,
+    next_secret_key: Option<Spanned<String>>,
+    #[serde(default)]
+    revoked_keys: Vec<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+struct SpannedUser {
+    public_key: Spanned<String>,
+    #[serde(default)]
+    authorize: Vec<Spanned<String>>,
+    #[serde(default)]
+    schedules: Vec<SpannedSchedule>,
+}
+
+#[derive(Deserialize)]
+struct SpannedSchedule {
+    #[serde(default)]
+    doors: Vec<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+struct SpannedDoor {
+    mac: Option<Spanned<String>>,
+    public_key: Option<Spanned<String>>,
+    #[serde(default)]
+    offline: bool,
+    output: Option<Spanned<u8>>,
+    left_open_after: Option<Spanned<u64>>,
+}
+
+#[derive(Deserialize)]
+struct SpannedNotify {
+    url: Spanned<String>,
+    template: Option<Spanned<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+struct Checker<'a> {
+    buf: &'a str,
+    problems: Vec<Problem>,
+}
+
+impl Checker<'_> {
+    fn report<T>(&mut self, spanned: &Spanned<T>, message: String) {
+        let offset = spanned.span().start;
+        let line = self.buf[..offset].matches('\n').count() + 1;
+        self.problems.push(Problem { line, message });
+    }
+}
+
+/// Check the config, returns all problems that have been found
+pub fn check(buf: &str) -> Result<Vec<Problem>> {
+    // make sure the config loads at all, this reports syntax errors and values of the wrong type
+    Config::parse(buf)?;
+    let config = toml::from_str::<SpannedConfig>(buf).context("Failed to load toml as config")?;
+
+    let mut checker = Checker {
+        buf,
+        problems: Vec::new(),
+    };
+
+    let secret_key = &config.system.secret_key;
+    if crypto::secret_key(secret_key.get_ref()).is_err() {
+        checker.report(
+            secret_key,
+            "secret key of the bridge is not valid".to_string(),
+        );
+    }
+    if let Some(next) = &config.system.next_secret_key {
+        if crypto::secret_key(next.get_ref()).is_err() {
+            checker.report(
+                next,
+                "next secret key of the bridge is not valid".to_string(),
+            );
+        } else if next.get_ref() == secret_key.get_ref() {
+            checker.report(
+                next,
+                "next secret key of the bridge is the same as the current one".to_string(),
+            );
+        }
+    }
+    for key in &config.system.revoked_keys {
+        if crypto::public_key(key.get_ref()).is_err() {
+            checker.report(
+                key,
+                format!("revoked key is not valid: {:?}", key.get_ref()),
+            );
+        }
+    }
+
+    let mut keys = HashMap::new();
+    for (name, user) in &config.users {
+        let user = user.get_ref();
+        let public_key = &user.public_key;
+        if crypto::public_key(public_key.get_ref()).is_err() {
+            checker.report(
+                public_key,
+                format!("public key of user {name:?} is not valid"),
+            );
+        }
+        if let Some(other) = keys.insert(public_key.get_ref(), name) {
+            checker.report(
+                public_key,
+                format!("user {name:?} has the same public key as user {other:?}"),
+            );
+        }
+
+        let doors = user
+            .authorize
+            .iter()
+            .chain(user.schedules.iter().flat_map(|s| &s.doors));
+        for door in doors {
+            if !config.doors.contains_key(door.get_ref()) {
+                checker.report(
+                    door,
+                    format!("user {name:?} refers to unknown door {:?}", door.get_ref()),
+                );
+            }
+        }
+    }
+
+    let mut outputs = HashMap::new();
+    for (id, spanned) in &config.doors {
+        let door = spanned.get_ref();
+        if let Some(mac) = &door.mac {
+            let output = door.output.as_ref().map(|o| *o.get_ref()).unwrap_or(0);
+            if let Some(other) = outputs.insert((mac.get_ref().to_lowercase(), output), id) {
+                let message =
+                    format!("door {id:?} uses the same output {output} as door {other:?}");
+                match &door.output {
+                    Some(output) => checker.report(output, message),
+                    None => checker.report(spanned, message),
+                }
+            }
+        }
+        if let (Some(output), true) = (&door.output, door.offline) {
+            if *output.get_ref() != 0 {
+                checker.report(
+                    output,
+                    format!(
+                        "door {id:?} is marked as offline, but offline tokens are only issued for output 0"
+                    ),
+                );
+            }
+        }
+        if let Some(mac) = &door.mac {
+            if BDAddr::from_str_delim(mac.get_ref()).is_err() {
+                checker.report(
+                    mac,
+                    format!("mac of door {id:?} is not valid: {:?}", mac.get_ref()),
+                );
+            }
+        }
+        if let Some(public_key) = &door.public_key {
+            if crypto::public_key(public_key.get_ref()).is_err() {
+                checker.report(
+                    public_key,
+                    format!("public key of door {id:?} is not valid"),
+                );
+            }
+        }
+
+        match (&door.mac, &door.public_key) {
+            (Some(mac), None) => checker.report(
+                mac,
+                format!("door {id:?} has a mac but no public key, it can't be opened"),
+            ),
+            (None, _) if door.offline => checker.report(
+                spanned,
+                format!("door {id:?} is marked as offline but has no mac"),
+            ),
+            _ => (),
+        }
+        if let (Some(left_open_after), None) = (&door.left_open_after, &door.mac) {
+            checker.report(
+                left_open_after,
+                format!("door {id:?} has no mac, the door contact can't be checked"),
+            );
+        }
+        if door.offline && door.public_key.is_none() {
+            checker.report(
+                spanned,
+                format!("door {id:?} is marked as offline but has no public key"),
+            );
+        }
+    }
+
+    for target in &config.notify {
+        let url = &target.url;
+        let scheme = url.get_ref().parse::<hyper::Uri>().ok().and_then(|uri| {
+            uri.host()?;
+            Some(uri.scheme_str()?.to_string())
+        });
+        if !matches!(scheme.as_deref(), Some("http" | "https")) {
+            checker.report(
+                url,
+                format!(
+                    "webhook url is not a valid http(s) url: {:?}",
+                    url.get_ref()
+                ),
+            );
+        }
+        if let Some(template) = &target.template {
+            let event = notify::Event::new(
+                notify::EventKind::Opened,
+                Some("alice"),
+                Some("home"),
+                "alice opened Home".to_string(),
+            );
+            if let Err(err) = event.render(Some(template.get_ref())) {
+                checker.report(template, format!("{err:#}"));
+            }
+        }
+    }
+
+    let mut problems = checker.problems;
+    problems.sort_by_key(|p| p.line);
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_example() -> Result<()> {
+        let buf = std::fs::read_to_string("../example.toml")?;
+        assert_eq!(check(&buf)?, vec![]);
+        Ok(())
+    }
+
+    #[test]
+    fn report_problems() -> Result<()> {
+        let problems = check(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.alice]
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+authorize = ["home", "garage"]
+
+[users.bob]
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+
+[users.mallory]
+public_key = "not base64"
+
+[doors.home]
+label = "Home"
+mac = "ec:da:3b:ff:ff"
+
+[doors.front]
+label = "Front door"
+mac = "EC:DA:3B:FF:FF:FF"
+public_key = "6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q="
+offline = true
+output = 1
+
+[doors.gate]
+label = "Gate"
+mac = "ec:da:3b:ff:ff:ff"
+public_key = "6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q="
+output = 1
+
+[doors.shed]
+label = "Shed"
+left_open_after = 600
+
+[[notify]]
+url = "chat.example.com/hook"
+template = '{"text": {{message}}}'
+"#,
+        )?;
+        let problems = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec![
+                "line 6: user \"alice\" refers to unknown door \"garage\"",
+                "line 9: user \"bob\" has the same public key as user \"alice\"",
+                "line 12: public key of user \"mallory\" is not valid",
+                "line 16: mac of door \"home\" is not valid: \"ec:da:3b:ff:ff\"",
+                "line 16: door \"home\" has a mac but no public key, it can't be opened",
+                "line 23: door \"front\" is marked as offline, but offline tokens are only issued for output 0",
+                "line 29: door \"gate\" uses the same output 1 as door \"front\"",
+                "line 33: door \"shed\" has no mac, the door contact can't be checked",
+                "line 36: webhook url is not a valid http(s) url: \"chat.example.com/hook\"",
+                "line 37: Webhook template is not valid json: expected value at line 1 column 10",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_next_key() -> Result<()> {
+        let problems = check(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+next_secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+"#,
+        )?;
+        let problems = problems.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            vec!["line 3: next secret key of the bridge is the same as the current one"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn report_syntax_errors() {
+        assert!(check("[system]\nsecret_key = ").is_err());
+        assert!(check("[users.alice]\npublic_key = \"\"").is_err());
+    }
+}
@@ -1,11 +1,80 @@
+use crate::access::Denied;
 use crate::errors::*;
+use crate::guests::Guests;
+use crate::notify;
+use crate::schedule::Schedule;
+use chrono::{DateTime, Utc};
+use d3xs_protocol::chall;
 use d3xs_protocol::crypto;
 use d3xs_protocol::ipc;
+use d3xs_protocol::offline;
 use data_encoding::BASE64;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(3);
+const OFFLINE_TOKEN_DAYS: i64 = 7;
+
+// compare two values by their content, ignoring formatting and comments
+fn same_value(a: &toml_edit::Value, b: &toml_edit::Value) -> bool {
+    let parse =
+        |value: &toml_edit::Value| toml::from_str::<toml::Table>(&format!("v = {value}")).ok();
+    parse(a).is_some_and(|a| Some(a) == parse(b))
+}
+
+/// Update `doc` to match `new`, entries that didn't change keep their formatting and comments
+fn merge_table(doc: &mut toml_edit::Table, new: &toml_edit::Table) {
+    let removed = doc
+        .iter()
+        .map(|(key, _)| key.to_string())
+        .filter(|key| !new.contains_key(key))
+        .collect::<Vec<_>>();
+    for key in removed {
+        doc.remove(&key);
+    }
+
+    for (key, item) in new.iter() {
+        match (doc.get_mut(key), item) {
+            (Some(toml_edit::Item::Table(current)), toml_edit::Item::Table(item)) => {
+                merge_table(current, item)
+            }
+            (
+                Some(toml_edit::Item::ArrayOfTables(current)),
+                toml_edit::Item::ArrayOfTables(item),
+            ) if current.len() == item.len() => {
+                for (current, item) in current.iter_mut().zip(item.iter()) {
+                    merge_table(current, item);
+                }
+            }
+            (Some(toml_edit::Item::Value(current)), toml_edit::Item::Value(value)) => {
+                if !same_value(current, value) {
+                    let decor = current.decor().clone();
+                    *current = value.clone();
+                    *current.decor_mut() = decor;
+                }
+            }
+            _ => {
+                doc.insert(key, item.clone());
+            }
+        }
+    }
+}
+
+/// Offline tokens expire at midnight (UTC), so they only change once per day
+fn offline_token_expiry(now: DateTime<Utc>) -> u64 {
+    let day = 24 * 60 * 60;
+    let today = now.timestamp().div_euclid(day);
+    ((today + OFFLINE_TOKEN_DAYS + 1) * day) as u64
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
@@ -14,6 +83,8 @@ pub struct Config {
     pub users: HashMap<String, User>,
     #[serde(default)]
     pub doors: HashMap<String, Door>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notify: Vec<Notify>,
 }
 
 impl Config {
@@ -30,33 +101,206 @@ impl Config {
         Ok(config)
     }
 
-    pub fn to_shared_config(&self) -> Result<ipc::Config> {
-        let secret_key = crypto::secret_key(&self.system.secret_key)
+    /// The label of a door for messages, falls back to the id
+    pub fn door_label<'a>(&'a self, id: &'a str) -> &'a str {
+        self.doors.get(id).map_or(id, |door| door.label.as_str())
+    }
+
+    pub fn challenge_ttl(&self) -> u64 {
+        self.system.challenge_ttl.unwrap_or(chall::DEFAULT_TTL)
+    }
+
+    /// Write the config to disk, the file is replaced atomically
+    ///
+    /// An existing file is edited, so comments and formatting of unchanged entries are kept.
+    pub async fn save_to_path<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        // make sure the webserver config can still be generated
+        self.to_shared_config(Utc::now(), &Guests::default())?;
+        let buf = toml::to_string_pretty(self).context("Failed to serialize config")?;
+        let buf = match fs::read_to_string(path).await {
+            Ok(current) => {
+                let mut doc = current
+                    .parse::<toml_edit::Document>()
+                    .with_context(|| anyhow!("Failed to parse config at {path:?}"))?;
+                let new = buf
+                    .parse::<toml_edit::Document>()
+                    .context("Failed to parse serialized config")?;
+                merge_table(doc.as_table_mut(), new.as_table());
+                doc.to_string()
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => buf,
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Failed to read config at {path:?}"))
+            }
+        };
+
+        // the config contains the bridge secret key, keep the permissions of the current file
+        let permissions = match fs::metadata(path).await {
+            Ok(metadata) => metadata.permissions(),
+            Err(_) => Permissions::from_mode(0o600),
+        };
+
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .await
+            .with_context(|| anyhow!("Failed to create {tmp:?}"))?;
+        file.set_permissions(permissions)
+            .await
+            .with_context(|| anyhow!("Failed to set permissions of {tmp:?}"))?;
+        file.write_all(buf.as_bytes())
+            .await
+            .with_context(|| anyhow!("Failed to write config to {tmp:?}"))?;
+        file.flush()
+            .await
+            .with_context(|| anyhow!("Failed to write config to {tmp:?}"))?;
+        fs::rename(&tmp, path)
+            .await
+            .with_context(|| anyhow!("Failed to replace config at {path:?}"))?;
+        Ok(())
+    }
+
+    pub async fn reload_from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config = Self::load_from_path(path).await?;
+        // make sure the new config is usable before replacing the active one
+        config.to_shared_config(Utc::now(), &Guests::default())?;
+        Ok(config)
+    }
+
+    pub fn is_revoked(&self, user: &User) -> bool {
+        user.disabled || self.system.revoked_keys.contains(&user.public_key)
+    }
+
+    /// Checks that don't depend on the door, the revocation list and limits of guest links
+    pub fn check_user(
+        &self,
+        user: &User,
+        now: DateTime<Utc>,
+        guests: &Guests,
+    ) -> Result<(), Denied> {
+        if self.is_revoked(user) {
+            return Err(Denied::Revoked);
+        }
+        if let Some(expires) = user.expires {
+            if now >= expires {
+                return Err(Denied::Expired(expires));
+            }
+        }
+        if let Some(uses) = user.uses {
+            if guests.used(&user.public_key) >= uses {
+                return Err(Denied::UsedUp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if the user is allowed to access the door
+    pub fn check_access(
+        &self,
+        user: &User,
+        door: &str,
+        now: DateTime<Utc>,
+        guests: &Guests,
+    ) -> Result<(), Denied> {
+        self.check_user(user, now, guests)?;
+        user.check_access(door, now)
+    }
+
+    /// The bridge secret key, followed by the next key if one is configured
+    pub fn bridge_keys(&self) -> Result<Vec<crypto::SecretKey>> {
+        let mut keys = vec![crypto::secret_key(&self.system.secret_key)
             .ok()
-            .context("Failed to decode secret key")?;
+            .context("Failed to decode secret key")?];
+        if let Some(next) = &self.system.next_secret_key {
+            keys.push(
+                crypto::secret_key(next)
+                    .ok()
+                    .context("Failed to decode next secret key")?,
+            );
+        }
+        Ok(keys)
+    }
+
+    /// Generate the config for the webserver, doors outside of their schedule are left out
+    pub fn to_shared_config(&self, now: DateTime<Utc>, guests: &Guests) -> Result<ipc::Config> {
+        let keys = self.bridge_keys()?;
+        let (secret_key, next_secret_key) = (&keys[0], keys.get(1));
         let public_key = secret_key.public_key();
         let public_key = BASE64.encode(public_key.as_bytes());
 
-        let users = self
-            .users
-            .iter()
-            .map(|(k, v)| {
-                (
-                    k.to_string(),
-                    ipc::User {
-                        authorize: v.authorize.clone(),
-                    },
-                )
-            })
-            .collect();
+        let expires = offline_token_expiry(now);
+        let mut users = HashMap::new();
+        for (name, user) in &self.users {
+            // revoked users and invalid guest links are removed from the webserver entirely
+            if self.check_user(user, now, guests).is_err() {
+                continue;
+            }
+
+            let authorize = user
+                .authorize
+                .iter()
+                .filter(|door| user.check_access(door, now).is_ok())
+                .cloned()
+                .collect::<Vec<_>>();
+
+            let mut offline = HashMap::new();
+            let mut offline_next = HashMap::new();
+            // the door can't enforce the limits of guest links
+            let offline_authorize = if user.is_guest() {
+                &[][..]
+            } else {
+                &authorize[..]
+            };
+            for id in offline_authorize {
+                let Some(door) = self.doors.get(id) else {
+                    continue;
+                };
+                // schedules can't be enforced by the door itself
+                if user.schedules.iter().any(|s| s.applies_to(id)) {
+                    continue;
+                }
+                if let Some(token) = door.issue_offline_token(secret_key, user, expires)? {
+                    offline.insert(id.to_string(), token);
+                }
+                // during a key rotation the door may already trust the next key
+                if let Some(next_secret_key) = next_secret_key {
+                    if let Some(token) = door.issue_offline_token(next_secret_key, user, expires)? {
+                        offline_next.insert(id.to_string(), token);
+                    }
+                }
+            }
+
+            users.insert(
+                name.to_string(),
+                ipc::User {
+                    authorize,
+                    offline,
+                    offline_next,
+                    expires: user.expires.map(|expires| expires.timestamp() as u64),
+                },
+            );
+        }
+
         let doors = self
             .doors
             .iter()
             .map(|(k, v)| {
+                let public_key = if v.offline {
+                    v.public_key.clone()
+                } else {
+                    None
+                };
                 (
                     k.to_string(),
                     ipc::Door {
                         label: v.label.clone(),
+                        public_key,
+                        unreachable: false,
                     },
                 )
             })
@@ -69,10 +313,69 @@ impl Config {
     }
 }
 
+async fn modified(path: &Path) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).await.ok()?;
+    metadata.modified().ok()
+}
+
+/// Reload the config whenever the file is modified or SIGHUP is received
+pub async fn watch<P: AsRef<Path>>(path: P, tx: &watch::Sender<Config>) -> Result<()> {
+    let path = path.as_ref();
+    let mut sighup = signal(SignalKind::hangup()).context("Failed to register SIGHUP handler")?;
+    let mut interval = time::interval(RELOAD_POLL_INTERVAL);
+    let mut mtime = modified(path).await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let current = modified(path).await;
+                if current == mtime {
+                    continue;
+                }
+                mtime = current;
+                debug!("Config file has changed on disk: {path:?}");
+            }
+            _ = sighup.recv() => info!("Received SIGHUP, reloading config"),
+        }
+
+        match Config::reload_from_path(path).await {
+            Ok(config) => {
+                let modified = tx.send_if_modified(|current| {
+                    if *current != config {
+                        *current = config;
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if modified {
+                    info!("Loaded new config from {path:?}");
+                } else {
+                    debug!("Config has not changed");
+                }
+            }
+            Err(err) => error!("Failed to reload config, keeping previous one: {err:#}"),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Bridge {
     pub secret_key: String,
+    /// The key that is going to replace `secret_key`, both are accepted during the rotation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_secret_key: Option<String>,
     pub url: Option<String>,
+    /// Append access events to this file as hash-chained json lines
+    pub audit_log: Option<PathBuf>,
+    /// Remember how often guest links have been used, so they stay used up across restarts
+    pub guest_state: Option<PathBuf>,
+    /// Public keys of users that are refused access, even if they are still configured
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revoked_keys: Vec<String>,
+    /// How many seconds a challenge can be solved after it has been issued
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub challenge_ttl: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -80,6 +383,37 @@ pub struct User {
     pub public_key: String,
     #[serde(default)]
     pub authorize: Vec<String>,
+    /// If any schedule applies to a door, access is only granted within one of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedules: Vec<Schedule>,
+    /// Refuse access without removing the user from the config
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disabled: bool,
+    /// Guest links stop working after this point in time
+    pub expires: Option<DateTime<Utc>>,
+    /// Guest links stop working after opening a door this many times
+    pub uses: Option<u32>,
+}
+
+impl User {
+    pub fn is_guest(&self) -> bool {
+        self.expires.is_some() || self.uses.is_some()
+    }
+
+    pub fn check_access(&self, door: &str, now: DateTime<Utc>) -> Result<(), Denied> {
+        if self.authorize.iter().all(|d| d != door) {
+            return Err(Denied::NotAuthorized);
+        }
+
+        let mut result = Ok(());
+        for schedule in self.schedules.iter().filter(|s| s.applies_to(door)) {
+            match schedule.check(now) {
+                Ok(()) => return Ok(()),
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -87,6 +421,62 @@ pub struct Door {
     pub label: String,
     pub mac: Option<String>,
     pub public_key: Option<String>,
+    /// Issue tokens to users so they can open the door without the bridge
+    #[serde(default)]
+    pub offline: bool,
+    /// The output of the door controller to trigger, if it has multiple relays
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<u8>,
+    /// Raise an alert if the door contact reports the door open for longer than this (in seconds)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub left_open_after: Option<u64>,
+}
+
+impl Door {
+    pub fn issue_offline_token(
+        &self,
+        secret_key: &crypto::SecretKey,
+        user: &User,
+        expires: u64,
+    ) -> Result<Option<String>> {
+        let (true, Some(public_key)) = (self.offline, &self.public_key) else {
+            return Ok(None);
+        };
+        // the firmware always opens output 0 for offline tokens
+        if self.output.unwrap_or(0) != 0 {
+            return Ok(None);
+        }
+        let public_key = crypto::public_key(public_key)
+            .map_err(|_| anyhow!("Failed to decode public key of door"))?;
+        let Ok(user) = crypto::public_key(&user.public_key) else {
+            return Ok(None);
+        };
+
+        let salsa = crypto::SalsaBox::new(&public_key, secret_key);
+        let token = offline::Token { user, expires }
+            .seal(&salsa)
+            .map_err(|_| anyhow!("Failed to encrypt offline token"))?;
+        Ok(Some(BASE64.encode(&token)))
+    }
+}
+
+fn default_retries() -> u32 {
+    notify::DEFAULT_RETRIES
+}
+
+/// A webhook that is sent events as json
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Notify {
+    pub url: String,
+    /// Only send these events, all of them if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<notify::EventKind>,
+    /// The json payload to send, with placeholders like `{{message}}`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// How often a failed delivery is retried
+    #[serde(default = "default_retries")]
+    pub retries: u32,
 }
 
 #[cfg(test)]
@@ -105,10 +495,16 @@ secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
             Config {
                 system: Bridge {
                     secret_key: "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g=".to_string(),
+                    next_secret_key: None,
                     url: None,
+                    audit_log: None,
+                    guest_state: None,
+                    revoked_keys: vec![],
+                    challenge_ttl: None,
                 },
                 users: HashMap::new(),
                 doors: HashMap::new(),
+                notify: vec![],
             }
         );
         Ok(())
@@ -122,7 +518,12 @@ secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
             Config {
                 system: Bridge {
                     secret_key: "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g=".to_string(),
+                    next_secret_key: None,
                     url: None,
+                    audit_log: None,
+                    guest_state: None,
+                    revoked_keys: vec![],
+                    challenge_ttl: None,
                 },
                 users: {
                     let mut m = HashMap::new();
@@ -131,6 +532,10 @@ secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
                         User {
                             public_key: "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc=".to_string(),
                             authorize: vec!["home".to_string(), "building".to_string()],
+                            schedules: vec![],
+                            disabled: false,
+                            expires: None,
+                            uses: None,
                         },
                     );
                     m.insert(
@@ -138,6 +543,10 @@ secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
                         User {
                             public_key: "7Pb0/x8UgjvcInZFy8FX+o/8pgMQHc2G42BftKnsBUo=".to_string(),
                             authorize: vec![],
+                            schedules: vec![],
+                            disabled: false,
+                            expires: None,
+                            uses: None,
                         },
                     );
                     m
@@ -150,6 +559,9 @@ secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
                             label: "Home".to_string(),
                             mac: None,
                             public_key: None,
+                            offline: false,
+                            output: None,
+                            left_open_after: None,
                         },
                     );
                     m.insert(
@@ -160,12 +572,324 @@ secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
                             public_key: Some(
                                 "6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q=".to_string(),
                             ),
+                            offline: false,
+                            output: None,
+                            left_open_after: None,
                         },
                     );
                     m
                 },
+                notify: vec![],
             }
         );
         Ok(())
     }
+
+    #[tokio::test]
+    async fn save_and_load() -> Result<()> {
+        let config = Config::load_from_path("../example.toml").await?;
+        let path = std::env::temp_dir().join(format!("d3xs-{}-config.toml", std::process::id()));
+        config.save_to_path(&path).await?;
+        let loaded = Config::load_from_path(&path).await?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(loaded, config);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_keeps_permissions() -> Result<()> {
+        let config = Config::load_from_path("../example.toml").await?;
+        let path = std::env::temp_dir().join(format!("d3xs-{}-mode.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // new files are only readable by the owner
+        config.save_to_path(&path).await?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        std::fs::set_permissions(&path, Permissions::from_mode(0o640))?;
+        config.save_to_path(&path).await?;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        std::fs::remove_file(&path)?;
+        assert_eq!(mode & 0o777, 0o640);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn save_keeps_comments() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("d3xs-{}-comments.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"# the bridge
+[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.alice]
+# alice's phone
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+authorize = ["home", "building"] # all doors
+
+[doors.home]
+label = "Home"
+
+# the front door
+[doors.building]
+label = "Building"
+"#,
+        )?;
+        let mut config = Config::load_from_path(&path).await?;
+        config.doors.remove("home");
+        config.users.get_mut("alice").unwrap().authorize = vec!["building".to_string()];
+        config.save_to_path(&path).await?;
+
+        let buf = std::fs::read_to_string(&path)?;
+        let loaded = Config::load_from_path(&path).await?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(loaded, config);
+        assert!(buf.starts_with("# the bridge\n[system]\n"));
+        assert!(buf.contains("# alice's phone\npublic_key = "));
+        assert!(buf.contains("authorize = [\"building\"] # all doors"));
+        assert!(buf.contains("# the front door\n[doors.building]\n"));
+        assert!(!buf.contains("[doors.home]"));
+        Ok(())
+    }
+
+    #[test]
+    fn check_access_with_schedule() -> Result<()> {
+        let config = Config::parse(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.cleaner]
+public_key = "7Pb0/x8UgjvcInZFy8FX+o/8pgMQHc2G42BftKnsBUo="
+authorize = ["home", "building"]
+
+[[users.cleaner.schedules]]
+doors = ["building"]
+weekdays = ["mon", "tue"]
+times = ["06:00-09:00", "18:00-20:00"]
+timezone = "Europe/Berlin"
+valid_until = "2024-12-31"
+
+[doors.home]
+label = "Home"
+
+[doors.building]
+label = "Building"
+"#,
+        )?;
+        let user = &config.users["cleaner"];
+        // 2024-01-01 is a monday, 06:30 in Berlin
+        let monday = "2024-01-01T05:30:00Z".parse::<DateTime<Utc>>()?;
+        let tuesday_noon = "2024-01-02T11:00:00Z".parse::<DateTime<Utc>>()?;
+        let next_year = "2025-01-06T05:30:00Z".parse::<DateTime<Utc>>()?;
+
+        assert_eq!(user.check_access("building", monday), Ok(()));
+        assert!(user.check_access("building", tuesday_noon).is_err());
+        assert!(user.check_access("building", next_year).is_err());
+        // no schedule applies to this door
+        assert_eq!(user.check_access("home", tuesday_noon), Ok(()));
+        assert_eq!(
+            user.check_access("garage", monday),
+            Err(Denied::NotAuthorized)
+        );
+
+        let ipc = config.to_shared_config(tuesday_noon, &Guests::default())?;
+        assert_eq!(ipc.users["cleaner"].authorize, vec!["home".to_string()]);
+        let ipc = config.to_shared_config(monday, &Guests::default())?;
+        assert_eq!(
+            ipc.users["cleaner"].authorize,
+            vec!["home".to_string(), "building".to_string()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn revoke_users() -> Result<()> {
+        let config = Config::parse(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+revoked_keys = ["7Pb0/x8UgjvcInZFy8FX+o/8pgMQHc2G42BftKnsBUo="]
+
+[users.alice]
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+authorize = ["home"]
+
+[users.bob]
+public_key = "7Pb0/x8UgjvcInZFy8FX+o/8pgMQHc2G42BftKnsBUo="
+authorize = ["home"]
+
+[users.mallory]
+public_key = "uI8Tc7YbtR3WewfwOUta8CimjmVvOxRydjf3EA1LhUQ="
+authorize = ["home"]
+disabled = true
+
+[doors.home]
+label = "Home"
+"#,
+        )?;
+        let now = Utc::now();
+        assert_eq!(
+            config.check_access(&config.users["alice"], "home", now, &Guests::default()),
+            Ok(())
+        );
+        assert_eq!(
+            config.check_access(&config.users["bob"], "home", now, &Guests::default()),
+            Err(Denied::Revoked)
+        );
+        assert_eq!(
+            config.check_access(&config.users["mallory"], "home", now, &Guests::default()),
+            Err(Denied::Revoked)
+        );
+
+        let ipc = config.to_shared_config(now, &Guests::default())?;
+        assert_eq!(ipc.users.keys().collect::<Vec<_>>(), vec!["alice"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn guest_links() -> Result<()> {
+        let config = Config::parse(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.delivery]
+public_key = "7Pb0/x8UgjvcInZFy8FX+o/8pgMQHc2G42BftKnsBUo="
+authorize = ["building"]
+expires = "2024-01-01T12:00:00Z"
+uses = 1
+
+[doors.building]
+label = "Building"
+mac = "ec:da:3b:ff:ff:ff"
+public_key = "6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q="
+offline = true
+"#,
+        )?;
+        let user = &config.users["delivery"];
+        let before = "2024-01-01T10:00:00Z".parse::<DateTime<Utc>>()?;
+        let after = "2024-01-01T12:00:00Z".parse::<DateTime<Utc>>()?;
+        let mut guests = Guests::default();
+
+        assert_eq!(
+            config.check_access(user, "building", before, &guests),
+            Ok(())
+        );
+        let ipc = config.to_shared_config(before, &guests)?;
+        assert_eq!(ipc.users["delivery"].expires, Some(1704110400));
+        // guest links can't be used offline
+        assert!(ipc.users["delivery"].offline.is_empty());
+
+        assert_eq!(
+            config.check_access(user, "building", after, &guests),
+            Err(Denied::Expired(after))
+        );
+        assert!(config.to_shared_config(after, &guests)?.users.is_empty());
+
+        guests.record_use(&user.public_key).await?;
+        assert_eq!(
+            config.check_access(user, "building", before, &guests),
+            Err(Denied::UsedUp)
+        );
+        assert!(config.to_shared_config(before, &guests)?.users.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn issue_offline_tokens() -> Result<()> {
+        let config = Config::parse(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.alice]
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+authorize = ["home", "building"]
+
+[users.cleaner]
+public_key = "7Pb0/x8UgjvcInZFy8FX+o/8pgMQHc2G42BftKnsBUo="
+authorize = ["building"]
+
+[[users.cleaner.schedules]]
+times = ["06:00-09:00"]
+timezone = "UTC"
+
+[doors.home]
+label = "Home"
+
+[doors.building]
+label = "Building"
+mac = "ec:da:3b:ff:ff:ff"
+public_key = "6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q="
+offline = true
+"#,
+        )?;
+        let door_key = crypto::generate_secret_key::<crypto::Random>();
+        let door_public_key = BASE64.encode(door_key.public_key().as_bytes());
+        let mut config = config;
+        config.doors.get_mut("building").unwrap().public_key = Some(door_public_key.clone());
+
+        let now = "2024-01-01T07:00:00Z".parse::<DateTime<Utc>>()?;
+        let ipc = config.to_shared_config(now, &Guests::default())?;
+
+        assert_eq!(ipc.doors["building"].public_key, Some(door_public_key));
+        assert_eq!(ipc.doors["home"].public_key, None);
+        assert!(ipc.users["cleaner"].offline.is_empty());
+
+        let alice = &ipc.users["alice"];
+        assert_eq!(alice.offline.keys().collect::<Vec<_>>(), vec!["building"]);
+
+        // the door is able to decrypt the token
+        let bridge_key = crypto::secret_key(&config.system.secret_key)
+            .unwrap()
+            .public_key();
+        let token = BASE64.decode(alice.offline["building"].as_bytes())?;
+        let salsa = crypto::SalsaBox::new(&bridge_key, &door_key);
+        let token = offline::Token::open(&salsa, &token).unwrap();
+        let user_key = crypto::public_key(&config.users["alice"].public_key).unwrap();
+        assert_eq!(token.user, user_key);
+        assert_eq!(token.expires, 1704067200 + 8 * 24 * 60 * 60);
+
+        assert!(alice.offline_next.is_empty());
+
+        // tokens are stable for the same day
+        let later = "2024-01-01T08:30:00Z".parse::<DateTime<Utc>>()?;
+        assert_eq!(config.to_shared_config(later, &Guests::default())?, ipc);
+
+        // while rotating, doors already moved to the next key get a token too
+        let next_key = crypto::generate_secret_key::<crypto::Random>();
+        config.system.next_secret_key = Some(BASE64.encode(&next_key.to_bytes()));
+        let ipc = config.to_shared_config(now, &Guests::default())?;
+        let alice = &ipc.users["alice"];
+        let token = BASE64.decode(alice.offline_next["building"].as_bytes())?;
+        let salsa = crypto::SalsaBox::new(&next_key.public_key(), &door_key);
+        let token = offline::Token::open(&salsa, &token).unwrap();
+        assert_eq!(token.user, user_key);
+        Ok(())
+    }
+
+    #[test]
+    fn no_offline_tokens_for_other_outputs() -> Result<()> {
+        let door_key = crypto::generate_secret_key::<crypto::Random>();
+        let config = Config::parse(&format!(
+            r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[users.alice]
+public_key = "TpR3WQMpINCjZoLqAtNQcAZxwIqcITji+8KLJfdJEFc="
+authorize = ["gate"]
+
+[doors.gate]
+label = "Gate"
+mac = "ec:da:3b:ff:ff:ff"
+public_key = "{}"
+output = 1
+offline = true
+"#,
+            BASE64.encode(door_key.public_key().as_bytes())
+        ))?;
+        let now = "2024-01-01T07:00:00Z".parse::<DateTime<Utc>>()?;
+        let ipc = config.to_shared_config(now, &Guests::default())?;
+        assert!(ipc.users["alice"].offline.is_empty());
+        Ok(())
+    }
 }
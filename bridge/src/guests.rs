@@ -0,0 +1,108 @@
+use crate::errors::*;
+use chrono::Duration;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// How often guest links have been used, by public key
+#[derive(Debug, Default)]
+pub struct Guests {
+    path: Option<PathBuf>,
+    used: HashMap<String, u32>,
+}
+
+impl Guests {
+    pub async fn open(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+
+        let used = match fs::read(path).await {
+            Ok(buf) => serde_json::from_slice(&buf)
+                .with_context(|| anyhow!("Failed to parse guest state {path:?}"))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Failed to read guest state {path:?}"))
+            }
+        };
+
+        Ok(Guests {
+            path: Some(path.to_owned()),
+            used,
+        })
+    }
+
+    pub fn used(&self, public_key: &str) -> u32 {
+        self.used.get(public_key).copied().unwrap_or(0)
+    }
+
+    pub async fn record_use(&mut self, public_key: &str) -> Result<()> {
+        *self.used.entry(public_key.to_string()).or_default() += 1;
+
+        if let Some(path) = &self.path {
+            let buf = serde_json::to_vec(&self.used)?;
+            // write to a temporary file first, so a crash can't leave a truncated file behind
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, &buf)
+                .await
+                .with_context(|| anyhow!("Failed to write guest state {tmp:?}"))?;
+            fs::rename(&tmp, path)
+                .await
+                .with_context(|| anyhow!("Failed to replace guest state {path:?}"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a duration like `30m`, `2h` or `7d`
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (num, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid duration: {s:?}"))?;
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("Unknown unit for duration, expected s/m/h/d: {s:?}"),
+    };
+    num.checked_mul(factor)
+        .and_then(|secs| Duration::from_std(std::time::Duration::from_secs(secs)).ok())
+        .with_context(|| anyhow!("Duration is too large: {s:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_durations() -> Result<()> {
+        assert_eq!(parse_duration("90s")?, Duration::seconds(90));
+        assert_eq!(parse_duration("30m")?, Duration::minutes(30));
+        assert_eq!(parse_duration("2h")?, Duration::hours(2));
+        assert_eq!(parse_duration("7d")?, Duration::days(7));
+        assert!(parse_duration("2").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("2 weeks").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn persist_uses() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("d3xs-{}-guests.json", std::process::id()));
+        std::fs::remove_file(&path).ok();
+
+        let mut guests = Guests::open(Some(&path)).await?;
+        assert_eq!(guests.used("alice"), 0);
+        guests.record_use("alice").await?;
+        guests.record_use("alice").await?;
+
+        let guests = Guests::open(Some(&path)).await?;
+        assert_eq!(guests.used("alice"), 2);
+        assert_eq!(guests.used("bob"), 0);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+//! Periodically check if the doors are reachable, so users know about it before they walk there.
+use crate::audit::{self, AuditLog};
+use crate::config::{Config, Door};
+use crate::errors::*;
+use crate::notify::{self, EventKind};
+use crate::transport::Transport;
+use chrono::{DateTime, Utc};
+use d3xs_protocol::command::Command;
+use d3xs_protocol::status::Status;
+use d3xs_protocol::{crypto, ipc};
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Mutex;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Duration};
+
+/// How long to wait before subscribing to a door
+const SUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DoorHealth {
+    pub last_check: DateTime<Utc>,
+    /// The last time the door has responded
+    pub last_seen: Option<DateTime<Utc>>,
+    /// The status reported during the last check, if the door was reachable
+    pub status: Option<Status>,
+    /// The status reported the last time the door has responded
+    pub last_status: Option<Status>,
+}
+
+impl DoorHealth {
+    pub fn is_reachable(&self) -> bool {
+        self.status.is_some()
+    }
+}
+
+/// Result of the latest health check, by door id
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Health {
+    pub doors: BTreeMap<String, DoorHealth>,
+}
+
+impl Health {
+    /// Mark doors as unreachable in the config for the webserver
+    pub fn apply(&self, ipc: &mut ipc::Config) {
+        for (id, door) in &mut ipc.doors {
+            if let Some(health) = self.doors.get(id) {
+                door.unreachable = !health.is_reachable();
+            }
+        }
+    }
+
+    /// The health after a door has notified about its status, without a full check
+    fn notified(&self, id: &str, status: Status) -> Health {
+        let mut next = self.clone();
+        if let Some(door) = next.doors.get_mut(id) {
+            door.last_seen = Some(Utc::now());
+            door.status = Some(status.clone());
+            door.last_status = Some(status);
+        }
+        next
+    }
+
+    fn reachability(&self) -> BTreeMap<&str, bool> {
+        self.doors
+            .iter()
+            .map(|(id, health)| (id.as_str(), health.is_reachable()))
+            .collect()
+    }
+}
+
+/// Read the status of a door
+///
+/// Doors that accept offline tokens are sent an authenticated command instead, they learn the
+/// current time from it and need it to check the expiry of tokens.
+async fn read_status<T: Transport>(
+    config: &Config,
+    door: &Door,
+    mac: &str,
+    transport: &T,
+    timeout: u64,
+) -> Result<Status> {
+    let (true, Some(public_key)) = (door.offline, &door.public_key) else {
+        return transport.status(mac, timeout).await;
+    };
+    let public_key =
+        crypto::public_key(public_key).map_err(|_| anyhow!("Failed to parse public key"))?;
+    let keys = config
+        .bridge_keys()?
+        .iter()
+        .map(|key| crypto::SalsaBox::new(&public_key, key))
+        .collect::<Vec<_>>();
+    let response = transport
+        .command(&keys, mac, Command::Status, timeout)
+        .await?;
+    response.status.context("Door has not sent its status")
+}
+
+/// Read the status of every door that has a mac address
+pub async fn check<T: Transport>(
+    config: &Config,
+    prev: &Health,
+    transport: &T,
+    timeout: u64,
+) -> Health {
+    let mut doors = config
+        .doors
+        .iter()
+        .filter_map(|(id, door)| Some((id, door, door.mac.as_ref()?)))
+        .collect::<Vec<_>>();
+    doors.sort_by_key(|(id, ..)| *id);
+
+    let mut health = Health::default();
+    for (id, door, mac) in doors {
+        let prev = prev.doors.get(id);
+        let now = Utc::now();
+        let door = match read_status(config, door, mac, transport, timeout).await {
+            Ok(status) => {
+                if prev.is_some_and(|prev| !prev.is_reachable()) {
+                    info!("Door is reachable again (door={id:?})");
+                }
+                debug!("Door status (door={id:?}): {status:?}");
+                DoorHealth {
+                    last_check: now,
+                    last_seen: Some(now),
+                    status: Some(status.clone()),
+                    last_status: Some(status),
+                }
+            }
+            Err(err) => {
+                warn!("Door is unreachable (door={id:?}): {err:#}");
+                DoorHealth {
+                    last_check: now,
+                    last_seen: prev.and_then(|prev| prev.last_seen),
+                    status: None,
+                    last_status: prev.and_then(|prev| prev.last_status.clone()),
+                }
+            }
+        };
+        health.doors.insert(id.clone(), door);
+    }
+    health
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Alert {
+    /// The door has been opened without being unlocked, this many times since the last check
+    ForcedEntry { door: String, count: u32 },
+    /// The door has been open for this many seconds
+    LeftOpen { door: String, secs: u32 },
+}
+
+impl Alert {
+    /// Write the alert to the audit log and send it to webhooks
+    pub async fn report(&self, audit: &AuditLog, config: &Config) {
+        let (kind, door, message) = match self {
+            Alert::ForcedEntry { door, count } => {
+                warn!("Door has been opened without being unlocked (door={door:?}, count={count})");
+                let outcome = format!("opened without unlock ({count}x)");
+                audit
+                    .record(audit::Event::ForcedEntry, "", door, &outcome)
+                    .await;
+                let label = config.door_label(door);
+                let message = format!("{label} has been opened without being unlocked");
+                (EventKind::ForcedEntry, door, message)
+            }
+            Alert::LeftOpen { door, secs } => {
+                warn!("Door has been left open (door={door:?}, secs={secs})");
+                let outcome = format!("open for {secs}s");
+                audit
+                    .record(audit::Event::LeftOpen, "", door, &outcome)
+                    .await;
+                let label = config.door_label(door);
+                let message = format!("{label} has been open for {} minutes", secs / 60);
+                (EventKind::LeftOpen, door, message)
+            }
+        };
+        let event = notify::Event::new(kind, None, Some(door), message);
+        notify::send(&config.notify, event);
+    }
+}
+
+/// Compare the door contacts of two checks, an alert is only raised once for each event
+pub fn alerts(config: &Config, prev: &Health, next: &Health) -> Vec<Alert> {
+    let mut alerts = Vec::new();
+    for (id, health) in &next.doors {
+        let Some(status) = &health.status else {
+            continue;
+        };
+        let Some(contact) = &status.contact else {
+            continue;
+        };
+        // compare with the last status we know of, even if the door was unreachable in between
+        let prev = prev
+            .doors
+            .get(id)
+            .and_then(|prev| prev.last_status.as_ref())
+            .filter(|prev| prev.contact.is_some());
+
+        let forced = match prev.and_then(|prev| Some((prev.uptime, prev.contact.as_ref()?))) {
+            // the counter has been reset by a reboot
+            Some((uptime, _)) if status.uptime < uptime => contact.forced_entries,
+            Some((_, prev)) => contact.forced_entries.saturating_sub(prev.forced_entries),
+            // there's nothing to compare with after the bridge started
+            None => 0,
+        };
+        if forced > 0 {
+            alerts.push(Alert::ForcedEntry {
+                door: id.clone(),
+                count: forced,
+            });
+        }
+
+        let Some(threshold) = config.doors.get(id).and_then(|door| door.left_open_after) else {
+            continue;
+        };
+        let Some(secs) = contact.open_since else {
+            continue;
+        };
+        if u64::from(secs) < threshold {
+            continue;
+        }
+        // the door has been opened at the same time and we already alerted about it
+        let opened_at = status.uptime.saturating_sub(secs);
+        let reported = prev.is_some_and(|prev| {
+            let Some(prev_secs) = prev.contact.as_ref().and_then(|c| c.open_since) else {
+                return false;
+            };
+            u64::from(prev_secs) >= threshold
+                && status.uptime >= prev.uptime
+                && prev.uptime.saturating_sub(prev_secs) == opened_at
+        });
+        if !reported {
+            alerts.push(Alert::LeftOpen {
+                door: id.clone(),
+                secs,
+            });
+        }
+    }
+    alerts
+}
+
+async fn subscribe<T: Transport>(
+    transport: &T,
+    id: String,
+    mac: String,
+    timeout: u64,
+    tx: mpsc::Sender<(String, Status)>,
+) -> String {
+    // give the door a moment, the health check may have just disconnected from it
+    time::sleep(SUBSCRIBE_DELAY).await;
+
+    let (status_tx, mut status_rx) = mpsc::channel(4);
+    let forward = async {
+        while let Some(status) = status_rx.recv().await {
+            if tx.send((id.clone(), status)).await.is_err() {
+                break;
+            }
+        }
+    };
+    let (ret, ()) = tokio::join!(transport.subscribe(&mac, timeout, status_tx), forward);
+    if let Err(err) = ret {
+        warn!("Failed to subscribe to status of door (door={id:?}): {err:#}");
+    }
+    id
+}
+
+fn publish(health_tx: &watch::Sender<Health>, health: Health) {
+    health_tx.send_if_modified(|current| {
+        let changed = current.reachability() != health.reachability();
+        *current = health;
+        changed
+    });
+}
+
+/// Check the doors in an interval, subscribers are notified if a door became (un)reachable
+///
+/// Doors that report a contact are also subscribed to in between, so alerts about them are
+/// raised as soon as the door notifies about a change instead of with the next check.
+pub async fn run<T: Transport>(
+    interval: Duration,
+    timeout: u64,
+    mut config_rx: watch::Receiver<Config>,
+    transport: &T,
+    health_tx: &watch::Sender<Health>,
+    audit: &AuditLog,
+) -> Result<()> {
+    let (status_tx, mut status_rx) = mpsc::channel(16);
+    let (subscribe_tx, mut subscribe_rx) = mpsc::channel::<(String, String)>(16);
+    let subscribed = Mutex::new(HashSet::new());
+
+    // the subscriptions are polled alongside the checks, they may be waiting for each other
+    let subscriptions = async {
+        let mut subscriptions = FuturesUnordered::new();
+        loop {
+            tokio::select! {
+                door = subscribe_rx.recv() => {
+                    let Some((id, mac)) = door else {
+                        break;
+                    };
+                    let tx = status_tx.clone();
+                    subscriptions.push(subscribe(transport, id, mac, timeout, tx));
+                }
+                Some(id) = subscriptions.next() => {
+                    subscribed.lock().unwrap().remove(&id);
+                }
+            }
+        }
+    };
+
+    let checks = async {
+        let mut interval = time::interval(interval);
+        loop {
+            let config = config_rx.borrow_and_update().clone();
+            let prev = health_tx.borrow().clone();
+            tokio::select! {
+                _ = interval.tick() => {
+                    let health = check(&config, &prev, transport, timeout).await;
+                    for alert in alerts(&config, &prev, &health) {
+                        alert.report(audit, &config).await;
+                    }
+                    for (id, door) in &health.doors {
+                        // only notify once, when the door stops responding
+                        if !door.is_reachable() && prev.doors.get(id).is_none_or(|p| p.is_reachable()) {
+                            let message = format!("{} is not responding", config.door_label(id));
+                            notify::send(
+                                &config.notify,
+                                notify::Event::new(EventKind::DoorOffline, None, Some(id), message),
+                            );
+                        }
+                    }
+                    publish(health_tx, health);
+                }
+                Some((id, status)) = status_rx.recv() => {
+                    debug!("Door has notified about its status (door={id:?}): {status:?}");
+                    let health = prev.notified(&id, status);
+                    for alert in alerts(&config, &prev, &health) {
+                        alert.report(audit, &config).await;
+                    }
+                    publish(health_tx, health);
+                }
+            }
+
+            // subscribe to the doors that have a contact, while they are reachable
+            let doors = health_tx
+                .borrow()
+                .doors
+                .iter()
+                .filter(|(_, door)| door.status.as_ref().is_some_and(|s| s.contact.is_some()))
+                .filter_map(|(id, _)| Some((id.clone(), config.doors.get(id)?.mac.clone()?)))
+                .collect::<Vec<_>>();
+            for (id, mac) in doors {
+                if subscribed.lock().unwrap().insert(id.clone()) {
+                    subscribe_tx.send((id, mac)).await?;
+                }
+            }
+        }
+    };
+
+    tokio::select! {
+        ret = checks => ret,
+        () = subscriptions => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Sim, SimDoor};
+    use chrono::Utc;
+    use d3xs_protocol::{crypto, status};
+    use data_encoding::BASE64;
+
+    #[tokio::test]
+    async fn check_doors() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+        let config = Config::parse(&format!(
+            r#"[system]
+secret_key = {:?}
+
+[doors.home]
+label = "Home"
+mac = "ec:da:3b:00:00:01"
+public_key = {:?}
+offline = true
+
+[doors.garage]
+label = "Garage"
+mac = "ec:da:3b:00:00:02"
+public_key = {:?}
+
+[doors.shed]
+label = "Shed"
+"#,
+            BASE64.encode(&bridge.to_bytes()),
+            BASE64.encode(door.public_key().as_bytes()),
+            BASE64.encode(door.public_key().as_bytes()),
+        ))?;
+
+        let mut sim = Sim::default();
+        sim.add_door(
+            "ec:da:3b:00:00:01",
+            SimDoor::new(&bridge.public_key(), &door),
+        );
+
+        let health = check(&config, &Health::default(), &sim, 0).await;
+        assert_eq!(
+            health.doors.keys().collect::<Vec<_>>(),
+            vec!["garage", "home"]
+        );
+        assert!(health.doors["home"].is_reachable());
+        assert!(!health.doors["garage"].is_reachable());
+        assert_eq!(health.doors["garage"].last_seen, None);
+        // doors with offline opens have been told the time
+        assert!(sim.door("ec:da:3b:00:00:01").unwrap().time().is_some());
+
+        let mut ipc = config.to_shared_config(Utc::now(), &Default::default())?;
+        health.apply(&mut ipc);
+        assert!(!ipc.doors["home"].unreachable);
+        assert!(ipc.doors["garage"].unreachable);
+        assert!(!ipc.doors["shed"].unreachable);
+
+        // the door keeps its last_seen while it's unreachable
+        let mut sim = Sim::default();
+        sim.add_door(
+            "ec:da:3b:00:00:02",
+            SimDoor::new(&bridge.public_key(), &door),
+        );
+        let next = check(&config, &health, &sim, 0).await;
+        assert!(!next.doors["home"].is_reachable());
+        assert_eq!(next.doors["home"].last_seen, health.doors["home"].last_seen);
+        assert!(next.doors["garage"].is_reachable());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscribe_to_contact() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+        let config = Config::parse(&format!(
+            r#"[system]
+secret_key = {:?}
+
+[doors.home]
+label = "Home"
+mac = "ec:da:3b:00:00:01"
+"#,
+            BASE64.encode(&bridge.to_bytes()),
+        ))?;
+
+        let mut sim = Sim::default();
+        sim.add_door(
+            "ec:da:3b:00:00:01",
+            SimDoor::new(&bridge.public_key(), &door),
+        );
+        let sim_door = sim.door("ec:da:3b:00:00:01").unwrap();
+        sim_door.set_contact(status::Contact {
+            open_since: None,
+            forced_entries: 0,
+        });
+
+        let (_config_tx, config_rx) = watch::channel(config);
+        let (health_tx, health_rx) = watch::channel(Health::default());
+        let audit = AuditLog::open(None).await?;
+        let interval = Duration::from_secs(3600);
+        let started = time::Instant::now();
+
+        let test = async {
+            while sim_door.subscribers() == 0 {
+                time::sleep(Duration::from_secs(1)).await;
+            }
+            sim_door.set_contact(status::Contact {
+                open_since: Some(0),
+                forced_entries: 1,
+            });
+            loop {
+                let forced_entries = health_rx.borrow().doors["home"]
+                    .last_status
+                    .as_ref()
+                    .and_then(|status| Some(status.contact.as_ref()?.forced_entries));
+                if forced_entries == Some(1) {
+                    break;
+                }
+                time::sleep(Duration::from_secs(1)).await;
+            }
+        };
+        tokio::select! {
+            ret = run(interval, 0, config_rx, &sim, &health_tx, &audit) => ret?,
+            () = test => (),
+        }
+        // the notification has been processed long before the next check
+        assert!(started.elapsed() < Duration::from_secs(60));
+        Ok(())
+    }
+
+    fn with_contact(uptime: u32, open_since: Option<u32>, forced_entries: u32) -> Health {
+        let status = Status {
+            version: "0.1.0".to_string(),
+            uptime,
+            last_open: None,
+            failed_attempts: 0,
+            contact: Some(status::Contact {
+                open_since,
+                forced_entries,
+            }),
+        };
+        let mut health = Health::default();
+        health.doors.insert(
+            "home".to_string(),
+            DoorHealth {
+                last_check: Utc::now(),
+                last_seen: Some(Utc::now()),
+                status: Some(status.clone()),
+                last_status: Some(status),
+            },
+        );
+        health
+    }
+
+    #[test]
+    fn contact_alerts() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let config = Config::parse(&format!(
+            r#"[system]
+secret_key = {:?}
+
+[doors.home]
+label = "Home"
+left_open_after = 300
+"#,
+            BASE64.encode(&bridge.to_bytes()),
+        ))?;
+
+        // nothing to compare with after the bridge started
+        let first = with_contact(1000, None, 2);
+        assert_eq!(alerts(&config, &Health::default(), &first), vec![]);
+
+        let forced = with_contact(1300, Some(10), 3);
+        assert_eq!(
+            alerts(&config, &first, &forced),
+            vec![Alert::ForcedEntry {
+                door: "home".to_string(),
+                count: 1
+            }]
+        );
+
+        // the door has been open for too long, but this is only reported once
+        let left_open = with_contact(1600, Some(310), 3);
+        let alert = Alert::LeftOpen {
+            door: "home".to_string(),
+            secs: 310,
+        };
+        assert_eq!(alerts(&config, &forced, &left_open), vec![alert]);
+        let still_open = with_contact(1900, Some(610), 3);
+        assert_eq!(alerts(&config, &left_open, &still_open), vec![]);
+
+        // opened again in the meantime
+        let reopened = with_contact(2500, Some(400), 3);
+        assert_eq!(alerts(&config, &still_open, &reopened).len(), 1);
+
+        // the counter starts at zero after a reboot of the door
+        let rebooted = with_contact(60, None, 1);
+        assert_eq!(
+            alerts(&config, &reopened, &rebooted),
+            vec![Alert::ForcedEntry {
+                door: "home".to_string(),
+                count: 1
+            }]
+        );
+
+        // an unreachable door keeps the status it reported last
+        let mut unreachable = rebooted.clone();
+        unreachable.doors.get_mut("home").unwrap().status = None;
+        assert_eq!(alerts(&config, &rebooted, &unreachable), vec![]);
+        let back = with_contact(1000, None, 1);
+        assert_eq!(alerts(&config, &unreachable, &back), vec![]);
+        Ok(())
+    }
+}
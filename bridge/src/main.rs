@@ -1,18 +1,36 @@
+pub mod access;
+pub mod admin;
 pub mod args;
+pub mod audit;
 pub mod ble;
+pub mod check;
 pub mod config;
 pub mod errors;
+pub mod guests;
+pub mod health;
+pub mod metrics;
+pub mod notify;
+pub mod rotate;
+pub mod schedule;
+pub mod sim;
+pub mod transport;
 pub mod ws;
 
-use crate::args::{Args, SubCommand};
+use crate::args::{AdminCommand, Args, AuditCommand, BleBackend, CommandAction, SubCommand};
 use crate::errors::*;
+use crate::metrics::{Metrics, METRICS};
+use chrono::{SecondsFormat, Utc};
 use clap::Parser;
 use d3xs_protocol::chall;
+use d3xs_protocol::command::{Command, Outcome};
 use d3xs_protocol::crypto;
 use data_encoding::{BASE64, BASE64URL_NOPAD};
 use env_logger::Env;
+use std::sync::Arc;
+use tokio::fs;
 use tokio::io;
 use tokio::io::AsyncReadExt;
+use tokio::sync::watch;
 use tokio::time;
 
 #[tokio::main]
@@ -32,26 +50,151 @@ async fn main() -> Result<()> {
             let secret_key = crypto::secret_key(&open.secret_key)
                 .map_err(|_| anyhow!("Failed to parse secret key"))?;
             let salsa = crypto::SalsaBox::new(&public_key, &secret_key);
-            ble::open(&salsa, &open.mac, open.timeout).await?
+            ble::open(&[salsa], &open.mac, open.output, open.timeout).await?
+        }
+        SubCommand::Command(cmd) => {
+            let public_key = crypto::public_key(&cmd.public_key)
+                .map_err(|_| anyhow!("Failed to parse public key"))?;
+            let secret_key = crypto::secret_key(&cmd.secret_key)
+                .map_err(|_| anyhow!("Failed to parse secret key"))?;
+            let salsa = crypto::SalsaBox::new(&public_key, &secret_key);
+
+            let output = cmd.output;
+            let command = match cmd.action {
+                CommandAction::Open => Command::Open {
+                    output,
+                    seconds: cmd.seconds,
+                },
+                CommandAction::HoldOpen => Command::HoldOpen { output },
+                CommandAction::Lock => Command::Lock { output },
+                CommandAction::Status => Command::Status,
+                CommandAction::Identify => Command::Identify,
+            };
+            let response = ble::command(&[salsa], &cmd.mac, command, cmd.timeout).await?;
+            if response.outcome != Outcome::Ok {
+                bail!("Door has refused the command: {:?}", response.outcome);
+            }
+            if let Some(status) = response.status {
+                println!("{status:#?}");
+            }
+            info!("Command has been executed");
+        }
+        SubCommand::Provision(provision) => {
+            let public_key = crypto::public_key(&provision.public_key)
+                .map_err(|_| anyhow!("Failed to parse public key"))?;
+            let secret_key = crypto::secret_key(&provision.secret_key)
+                .map_err(|_| anyhow!("Failed to parse secret key"))?;
+            let salsa = crypto::SalsaBox::new(&public_key, &secret_key);
+
+            let door_key = provision
+                .door_key
+                .map(|key| crypto::secret_key(&key))
+                .transpose()
+                .map_err(|_| anyhow!("Failed to parse new door key"))?;
+            let bridge_key = provision
+                .bridge_key
+                .map(|key| crypto::public_key(&key))
+                .transpose()
+                .map_err(|_| anyhow!("Failed to parse new bridge key"))?;
+            let settings = d3xs_protocol::provision::Settings {
+                door_key,
+                bridge_key,
+                ble_name: provision.ble_name,
+                outputs: provision.outputs,
+                contact: provision.contact,
+            };
+
+            ble::provision(&salsa, &provision.mac, &settings, provision.timeout).await?;
+            info!("Settings have been sent, the door is going to restart");
+            if let Some(door_key) = &settings.door_key {
+                // the door has a new public key, it needs to be updated in the config
+                let public_key = BASE64.encode(door_key.public_key().as_bytes());
+                println!("# mac = {:?}", provision.mac);
+                println!("public_key = {public_key:?}");
+            }
         }
         SubCommand::Connect(connect) => {
-            let config = config::Config::load_from_path(connect.config).await?;
-            let mut challenges = chall::UserDoorMap::default();
+            let config = config::Config::load_from_path(&connect.config).await?;
+            let audit = Arc::new(audit::AuditLog::open(config.system.audit_log.as_deref()).await?);
+            let mut state = ws::State {
+                challenges: chall::UserDoorMap::default(),
+                audit: audit.clone(),
+                guests: guests::Guests::open(config.system.guest_state.as_deref()).await?,
+                connected: false,
+                config: None,
+                failures: notify::Failures::default(),
+            };
 
-            let url = if let Some(url) = &connect.url {
+            let url = if let Some(url) = connect.url {
                 url
             } else if let Some(url) = &config.system.url {
-                url
+                url.clone()
             } else {
                 bail!("Missing url to connect to");
             };
 
+            let backend = match connect.ble_backend {
+                BleBackend::Btleplug => transport::Backend::Btleplug(ble::Btleplug),
+                BleBackend::Sim => {
+                    let secret_key = crypto::secret_key(&config.system.secret_key)
+                        .map_err(|_| anyhow!("Failed to decode secret key :<"))?;
+                    let sim = sim::Sim::from_args(&secret_key.public_key(), &connect.sim_doors)?;
+                    warn!("Using simulated doors, no bluetooth hardware is used");
+                    transport::Backend::Sim(sim)
+                }
+            };
+
+            if let Some(addr) = connect.metrics {
+                tokio::spawn(async move {
+                    if let Err(err) = metrics::serve(addr).await {
+                        error!("Failed to serve metrics: {err:#}");
+                    }
+                });
+            }
+
+            let backend = Arc::new(backend);
+            let (config_tx, mut config_rx) = watch::channel(config);
+            let config_tx = Arc::new(config_tx);
+
+            let (health_tx, mut health_rx) = watch::channel(health::Health::default());
+            if connect.health_interval > 0 {
+                let interval = time::Duration::from_secs(connect.health_interval);
+                let config_rx = config_rx.clone();
+                let backend = backend.clone();
+                let timeout = connect.timeout;
+                tokio::spawn(async move {
+                    let ret =
+                        health::run(interval, timeout, config_rx, &*backend, &health_tx, &audit);
+                    if let Err(err) = ret.await {
+                        error!("Failed to check health of doors: {err:#}");
+                    }
+                });
+            }
+
+            if let Some(socket) = connect.admin_socket {
+                let config_path = connect.config.clone();
+                let config_tx = config_tx.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = admin::serve(&socket, config_path, config_tx).await {
+                        error!("Failed to serve admin api: {err:#}");
+                    }
+                });
+            }
+            tokio::spawn(async move {
+                if let Err(err) = config::watch(&connect.config, &config_tx).await {
+                    error!("Failed to watch config: {err:#}");
+                }
+            });
+
             loop {
-                if let Err(err) = ws::connect(url, &config, &mut challenges).await {
+                if let Err(err) =
+                    ws::connect(&url, &mut config_rx, &mut health_rx, &mut state, &*backend).await
+                {
                     error!("Websocket error: {err:#}");
                 }
                 time::sleep(time::Duration::from_secs(3)).await;
                 info!("Reconnecting...");
+                Metrics::inc(&METRICS.reconnects);
             }
         }
         SubCommand::Keygen(keygen) => {
@@ -98,12 +241,127 @@ async fn main() -> Result<()> {
                 } else {
                     &path
                 };
-                println!("[users.{}]", name.unwrap_or("alice"));
+                let default_name = if keygen.guest { "guest" } else { "alice" };
+                println!("[users.{}]", name.unwrap_or(default_name));
                 println!("# {url}/{path}#{secret_key}");
                 println!("public_key = {public_key:?}");
-                println!("authorize = []");
+                println!("authorize = {:?}", keygen.doors);
+                if let Some(expires) = keygen.expires {
+                    let expires = (Utc::now() + expires).to_rfc3339_opts(SecondsFormat::Secs, true);
+                    println!("expires = {expires:?}");
+                }
+                if let Some(uses) = keygen.uses {
+                    println!("uses = {uses}");
+                }
             }
         }
+        SubCommand::Admin(cmd) => {
+            // keypairs for users are generated here, the secret key is never sent to the bridge
+            let mut new_key = None;
+            let mut generate_key = || {
+                let secret_key = crypto::generate_secret_key::<crypto::Random>();
+                let public_key = BASE64.encode(secret_key.public_key().as_bytes());
+                new_key = Some(secret_key);
+                public_key
+            };
+            let request = match cmd.subcommand {
+                AdminCommand::Users => admin::Request::ListUsers,
+                AdminCommand::Doors => admin::Request::ListDoors,
+                AdminCommand::AddUser {
+                    name,
+                    public_key,
+                    doors,
+                } => {
+                    let public_key = match public_key {
+                        Some(public_key) => public_key,
+                        None => generate_key(),
+                    };
+                    admin::Request::AddUser {
+                        name,
+                        public_key,
+                        authorize: doors,
+                    }
+                }
+                AdminCommand::RemoveUser { name } => admin::Request::RemoveUser { name },
+                AdminCommand::Authorize { name, doors } => {
+                    admin::Request::Authorize { name, doors }
+                }
+                AdminCommand::RotateKey { name } => admin::Request::RotateKey {
+                    name,
+                    public_key: generate_key(),
+                },
+                AdminCommand::SetDoor {
+                    id,
+                    label,
+                    mac,
+                    public_key,
+                    offline,
+                    output,
+                    left_open_after,
+                } => admin::Request::SetDoor {
+                    id,
+                    door: config::Door {
+                        label,
+                        mac,
+                        public_key,
+                        offline,
+                        output,
+                        left_open_after,
+                    },
+                },
+                AdminCommand::RemoveDoor { id } => admin::Request::RemoveDoor { id },
+            };
+
+            let data = admin::request(&cmd.socket, &request).await?;
+            if let Some(secret_key) = new_key {
+                // print the link for the new keypair, like keygen does
+                let path = BASE64URL_NOPAD.encode(secret_key.public_key().as_bytes());
+                let secret_key = BASE64.encode(&secret_key.to_bytes());
+                let url = cmd.url.as_deref().unwrap_or("https://example.com");
+                let url: &str = url.strip_suffix('/').unwrap_or(url);
+                println!("{url}/{path}#{secret_key}");
+            } else if !data.is_null() {
+                println!("{}", serde_json::to_string_pretty(&data)?);
+            }
+        }
+        SubCommand::Check(cmd) => {
+            let path = &cmd.config;
+            let buf = fs::read_to_string(path)
+                .await
+                .with_context(|| anyhow!("Failed to load config from {path:?}"))?;
+            let problems = check::check(&buf)
+                .with_context(|| anyhow!("Failed to load config from {path:?}"))?;
+            for problem in &problems {
+                eprintln!("{}:{}: {}", path.display(), problem.line, problem.message);
+            }
+            if !problems.is_empty() {
+                bail!("Found {} problem(s) in {path:?}", problems.len());
+            }
+            info!("No problems found in {path:?}");
+        }
+        SubCommand::Rotate(cmd) => {
+            let config = config::Config::load_from_path(&cmd.config).await?;
+            let next = crypto::generate_secret_key::<crypto::Random>();
+            print!("{}", rotate::plan(&config, &next)?);
+        }
+        SubCommand::Audit(audit) => match audit.subcommand {
+            AuditCommand::Verify(verify) => {
+                let path = if let Some(path) = verify.path {
+                    path
+                } else if let Some(path) = &verify.config {
+                    let config = config::Config::load_from_path(path).await?;
+                    config
+                        .system
+                        .audit_log
+                        .context("Config has no audit log configured")?
+                } else {
+                    bail!("Missing path to audit log");
+                };
+
+                let count = audit::verify_file(&path).await?;
+                info!("Audit log is intact ({count} entries)");
+            }
+        },
     }
 
     Ok(())
@@ -0,0 +1,186 @@
+use crate::errors::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub static METRICS: Metrics = Metrics::new();
+
+// requests larger than this are not accepted by the metrics listener
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub solves_ok: AtomicU64,
+    pub solves_failed: AtomicU64,
+    auth_failures: Mutex<BTreeMap<String, u64>>,
+    pub ble_attempts: AtomicU64,
+    pub ble_timeouts: AtomicU64,
+    pub reconnects: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            solves_ok: AtomicU64::new(0),
+            solves_failed: AtomicU64::new(0),
+            auth_failures: Mutex::new(BTreeMap::new()),
+            ble_attempts: AtomicU64::new(0),
+            ble_timeouts: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self, user: &str) {
+        let mut auth_failures = self.auth_failures.lock().unwrap();
+        *auth_failures.entry(user.to_string()).or_default() += 1;
+    }
+
+    /// Render in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "d3xs_bridge_solves_total",
+            "counter",
+            "Solve attempts",
+        );
+        for (result, counter) in [("ok", &self.solves_ok), ("failed", &self.solves_failed)] {
+            let value = counter.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "d3xs_bridge_solves_total{{result=\"{result}\"}} {value}"
+            )
+            .ok();
+        }
+
+        header(
+            &mut out,
+            "d3xs_bridge_auth_failures_total",
+            "counter",
+            "Refused requests and failed solves, by user",
+        );
+        for (user, value) in self.auth_failures.lock().unwrap().iter() {
+            let user = escape(user);
+            writeln!(
+                out,
+                "d3xs_bridge_auth_failures_total{{user=\"{user}\"}} {value}"
+            )
+            .ok();
+        }
+
+        for (name, kind, help, counter) in [
+            (
+                "d3xs_bridge_ble_attempts_total",
+                "counter",
+                "Attempts to open a door over bluetooth",
+                &self.ble_attempts,
+            ),
+            (
+                "d3xs_bridge_ble_timeouts_total",
+                "counter",
+                "Bluetooth operations that have timed out",
+                &self.ble_timeouts,
+            ),
+            (
+                "d3xs_bridge_reconnects_total",
+                "counter",
+                "Reconnects to the webserver",
+                &self.reconnects,
+            ),
+        ] {
+            header(&mut out, name, kind, help);
+            let value = counter.load(Ordering::Relaxed);
+            writeln!(out, "{name} {value}").ok();
+        }
+
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").ok();
+    writeln!(out, "# TYPE {name} {kind}").ok();
+}
+
+async fn respond(mut stream: TcpStream) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            bail!("Request is too large");
+        }
+    }
+
+    let (status, body) = if buf.starts_with(b"GET /metrics ") {
+        ("200 OK", METRICS.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Serve metrics at `http://<addr>/metrics`
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| anyhow!("Failed to bind metrics listener to {addr}"))?;
+    info!("Serving metrics at http://{addr}/metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream).await {
+                debug!("Failed to serve metrics: {err:#}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        Metrics::inc(&metrics.solves_ok);
+        Metrics::inc(&metrics.ble_attempts);
+        Metrics::inc(&metrics.ble_attempts);
+        metrics.auth_failed("mallory");
+        metrics.auth_failed("mallory");
+        metrics.auth_failed("\"bob\"");
+
+        let out = metrics.render();
+        assert!(out.contains("d3xs_bridge_solves_total{result=\"ok\"} 1\n"));
+        assert!(out.contains("d3xs_bridge_solves_total{result=\"failed\"} 0\n"));
+        assert!(out.contains("d3xs_bridge_auth_failures_total{user=\"mallory\"} 2\n"));
+        assert!(out.contains("d3xs_bridge_auth_failures_total{user=\"\\\"bob\\\"\"} 1\n"));
+        assert!(out.contains("d3xs_bridge_ble_attempts_total 2\n"));
+        assert!(out.contains("d3xs_bridge_reconnects_total 0\n"));
+    }
+}
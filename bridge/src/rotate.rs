@@ -0,0 +1,131 @@
+//! Guide the rotation of the bridge key.
+//!
+//! The bridge first gets a next key and accepts both, then the doors and the webserver are
+//! moved over one by one, and finally the next key replaces the current one.
+use crate::config::Config;
+use crate::errors::*;
+use d3xs_protocol::crypto;
+use data_encoding::BASE64;
+use std::fmt::Write;
+
+fn encode_public_key(secret_key: &crypto::SecretKey) -> String {
+    BASE64.encode(secret_key.public_key().as_bytes())
+}
+
+/// Generate the instructions for the next step of the rotation.
+///
+/// If the config has no next key yet, `next` is used as the new key.
+pub fn plan(config: &Config, next: &crypto::SecretKey) -> Result<String> {
+    let keys = config.bridge_keys()?;
+    let mut out = String::new();
+
+    let next = match &keys[..] {
+        [_] => next,
+        [_, next] => {
+            let public_key = encode_public_key(next);
+            writeln!(
+                out,
+                "# All doors and the webserver need to use the next key by now,"
+            )?;
+            writeln!(
+                out,
+                "# replace the secret key and remove `next_secret_key`:"
+            )?;
+            writeln!(out, "[system]")?;
+            writeln!(out, "# public_key = {public_key:?}")?;
+            writeln!(out, "secret_key = {:?}", BASE64.encode(&next.to_bytes()))?;
+            return Ok(out);
+        }
+        _ => bail!("Unexpected number of bridge keys: {}", keys.len()),
+    };
+
+    let public_key = encode_public_key(next);
+    writeln!(
+        out,
+        "# 1. Add the next key to the config, the bridge accepts both keys from now on:"
+    )?;
+    writeln!(out, "[system]")?;
+    writeln!(out, "# public_key = {public_key:?}")?;
+    writeln!(
+        out,
+        "next_secret_key = {:?}",
+        BASE64.encode(&next.to_bytes())
+    )?;
+    writeln!(out, "#")?;
+    writeln!(out, "# 2. Move each door to the next key:")?;
+    let mut doors = config.doors.iter().collect::<Vec<_>>();
+    doors.sort_by_key(|(id, _)| *id);
+    for (id, door) in doors {
+        let (Some(mac), Some(door_key)) = (&door.mac, &door.public_key) else {
+            continue;
+        };
+        writeln!(out, "# [doors.{id}]")?;
+        writeln!(
+            out,
+            "# d3xs-bridge provision {mac} {door_key} <bridge secret key> --bridge-key {public_key}"
+        )?;
+    }
+    writeln!(out, "#")?;
+    writeln!(out, "# 3. Configure the webserver with the next key:")?;
+    writeln!(out, "# D3XS_BRIDGE_PUBLIC_KEY={public_key:?}")?;
+    writeln!(out, "#")?;
+    writeln!(
+        out,
+        "# 4. Run `d3xs-bridge rotate` again to replace the current key"
+    )?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"[system]
+secret_key = "cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="
+
+[doors.home]
+label = "Home"
+
+[doors.building]
+label = "Building"
+mac = "ec:da:3b:ff:ff:ff"
+public_key = "6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q="
+"#;
+
+    #[test]
+    fn start_rotation() -> Result<()> {
+        let config = Config::parse(CONFIG)?;
+        let next = crypto::generate_secret_key::<crypto::Random>();
+        let plan = plan(&config, &next)?;
+
+        let next_secret_key = BASE64.encode(&next.to_bytes());
+        assert!(plan.contains(&format!("\nnext_secret_key = {next_secret_key:?}\n")));
+        assert!(plan.contains("# d3xs-bridge provision ec:da:3b:ff:ff:ff 6JgMhuAy8espdQUujWW93RXDtZZBF07JZ4pTeJ2Sx1Q= <bridge secret key> --bridge-key "));
+        assert!(!plan.contains("cRbNcnt4bw49I/AQb0wcjIBoqoLBayZAneTCGuG1g9g="));
+        assert!(!plan.contains("[doors.home]"));
+
+        // the snippet can be added to the config as-is
+        let snippet = plan.split("\n#\n").next().unwrap();
+        let buf = CONFIG.replace("[system]\n", &format!("{snippet}\n"));
+        let config = Config::parse(&buf)?;
+        assert_eq!(config.system.next_secret_key, Some(next_secret_key));
+        Ok(())
+    }
+
+    #[test]
+    fn finish_rotation() -> Result<()> {
+        let next = crypto::generate_secret_key::<crypto::Random>();
+        let next_secret_key = BASE64.encode(&next.to_bytes());
+        let buf = CONFIG.replace(
+            "[system]\n",
+            &format!("[system]\nnext_secret_key = {next_secret_key:?}\n"),
+        );
+        let config = Config::parse(&buf)?;
+
+        let unused = crypto::generate_secret_key::<crypto::Random>();
+        let plan = plan(&config, &unused)?;
+        assert!(plan.contains(&format!("\nsecret_key = {next_secret_key:?}\n")));
+        assert!(!plan.contains(&BASE64.encode(&unused.to_bytes())));
+        Ok(())
+    }
+}
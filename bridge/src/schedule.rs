@@ -0,0 +1,244 @@
+use crate::access::Denied;
+use crate::errors::*;
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// The doors this schedule applies to, all doors if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub doors: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub weekdays: Vec<Weekday>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub times: Vec<TimeRange>,
+    /// Timezone to evaluate weekdays, times and dates in, the system timezone if not set
+    pub timezone: Option<Tz>,
+    pub valid_from: Option<NaiveDate>,
+    pub valid_until: Option<NaiveDate>,
+}
+
+impl Schedule {
+    pub fn applies_to(&self, door: &str) -> bool {
+        self.doors.is_empty() || self.doors.iter().any(|d| d == door)
+    }
+
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(), Denied> {
+        let now = match self.timezone {
+            Some(tz) => now.with_timezone(&tz).naive_local(),
+            None => now.with_timezone(&Local).naive_local(),
+        };
+        self.check_local(now)
+    }
+
+    fn check_local(&self, now: NaiveDateTime) -> Result<(), Denied> {
+        let date = now.date();
+        if let Some(valid_from) = self.valid_from {
+            if date < valid_from {
+                return Err(Denied::NotYetValid(valid_from));
+            }
+        }
+        if let Some(valid_until) = self.valid_until {
+            if date > valid_until {
+                return Err(Denied::NoLongerValid(valid_until));
+            }
+        }
+
+        let weekday = date.weekday();
+        if !self.weekdays.is_empty() && !self.weekdays.contains(&weekday) {
+            return Err(Denied::Weekday(weekday));
+        }
+
+        let time = now.time();
+        if !self.times.is_empty() && !self.times.iter().any(|range| range.contains(time)) {
+            return Err(Denied::TimeOfDay(time));
+        }
+
+        Ok(())
+    }
+}
+
+/// A time-of-day range like `08:00-17:30`, ranges past midnight like `22:00-06:00` are allowed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeRange {
+    pub start: NaiveTime,
+    /// The end of the range, `None` if it lasts until the end of the day (`24:00`)
+    pub end: Option<NaiveTime>,
+}
+
+impl TimeRange {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        let Some(end) = self.end else {
+            return self.start <= time;
+        };
+        if self.start <= end {
+            self.start <= time && time < end
+        } else {
+            self.start <= time || time < end
+        }
+    }
+}
+
+fn parse_time(s: &str) -> Result<NaiveTime> {
+    let s = s.trim();
+    NaiveTime::parse_from_str(s, "%H:%M").with_context(|| anyhow!("Invalid time of day: {s:?}"))
+}
+
+impl FromStr for TimeRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once('-')
+            .with_context(|| anyhow!("Time range is missing a `-`: {s:?}"))?;
+        let end = if end.trim() == "24:00" {
+            None
+        } else {
+            Some(parse_time(end)?)
+        };
+        Ok(TimeRange {
+            start: parse_time(start)?,
+            end,
+        })
+    }
+}
+
+impl TryFrom<String> for TimeRange {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<TimeRange> for String {
+    fn from(range: TimeRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for TimeRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-", self.start.format("%H:%M"))?;
+        match self.end {
+            Some(end) => write!(f, "{}", end.format("%H:%M")),
+            None => write!(f, "24:00"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        let datetime = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        Utc.from_utc_datetime(&datetime)
+    }
+
+    fn schedule() -> Schedule {
+        Schedule {
+            doors: vec![],
+            weekdays: vec![],
+            times: vec![],
+            timezone: Some(Tz::UTC),
+            valid_from: None,
+            valid_until: None,
+        }
+    }
+
+    #[test]
+    fn parse_time_range() -> Result<()> {
+        let range = "08:00-17:30".parse::<TimeRange>()?;
+        assert_eq!(range.start, NaiveTime::from_hms_opt(8, 0, 0).unwrap());
+        assert_eq!(range.end, NaiveTime::from_hms_opt(17, 30, 0));
+        assert_eq!(range.to_string(), "08:00-17:30");
+        assert!("08:00".parse::<TimeRange>().is_err());
+        assert!("08:00-25:00".parse::<TimeRange>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn time_range_whole_day() -> Result<()> {
+        let range = "00:00-24:00".parse::<TimeRange>()?;
+        assert_eq!(range.end, None);
+        assert_eq!(range.to_string(), "00:00-24:00");
+        assert!(range.contains(NaiveTime::MIN));
+        assert!(range.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        assert!(range.contains(NaiveTime::from_hms_opt(23, 59, 59).unwrap()));
+        assert!("24:00-08:00".parse::<TimeRange>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn time_range_overnight() -> Result<()> {
+        let range = "22:00-06:00".parse::<TimeRange>()?;
+        assert!(range.contains(NaiveTime::from_hms_opt(23, 0, 0).unwrap()));
+        assert!(range.contains(NaiveTime::from_hms_opt(5, 59, 0).unwrap()));
+        assert!(!range.contains(NaiveTime::from_hms_opt(6, 0, 0).unwrap()));
+        assert!(!range.contains(NaiveTime::from_hms_opt(12, 0, 0).unwrap()));
+        Ok(())
+    }
+
+    #[test]
+    fn check_weekdays_and_times() -> Result<()> {
+        let schedule = Schedule {
+            weekdays: vec![Weekday::Mon, Weekday::Wed],
+            times: vec!["08:00-12:00".parse()?],
+            ..schedule()
+        };
+        // 2024-01-01 is a monday
+        assert_eq!(schedule.check(utc("2024-01-01 09:00")), Ok(()));
+        assert_eq!(
+            schedule.check(utc("2024-01-01 13:00")),
+            Err(Denied::TimeOfDay(
+                NaiveTime::from_hms_opt(13, 0, 0).unwrap()
+            ))
+        );
+        assert_eq!(
+            schedule.check(utc("2024-01-02 09:00")),
+            Err(Denied::Weekday(Weekday::Tue))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn check_valid_dates() {
+        let schedule = Schedule {
+            valid_from: NaiveDate::from_ymd_opt(2024, 1, 1),
+            valid_until: NaiveDate::from_ymd_opt(2024, 1, 31),
+            ..schedule()
+        };
+        assert_eq!(schedule.check(utc("2024-01-01 00:00")), Ok(()));
+        assert_eq!(schedule.check(utc("2024-01-31 23:59")), Ok(()));
+        assert_eq!(
+            schedule.check(utc("2023-12-31 23:59")),
+            Err(Denied::NotYetValid(
+                NaiveDate::from_ymd_opt(2024, 1, 1).unwrap()
+            ))
+        );
+        assert_eq!(
+            schedule.check(utc("2024-02-01 00:00")),
+            Err(Denied::NoLongerValid(
+                NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()
+            ))
+        );
+    }
+
+    #[test]
+    fn check_timezone() -> Result<()> {
+        let schedule = Schedule {
+            times: vec!["08:00-09:00".parse()?],
+            timezone: Some(chrono_tz::Europe::Berlin),
+            ..schedule()
+        };
+        assert_eq!(schedule.check(utc("2024-01-01 07:30")), Ok(()));
+        assert!(schedule.check(utc("2024-01-01 08:30")).is_err());
+        Ok(())
+    }
+}
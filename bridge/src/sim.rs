@@ -0,0 +1,381 @@
+//! Simulated doors for testing without bluetooth hardware.
+//!
+//! Each door runs the same challenge/response logic as the firmware, but in-process.
+use crate::errors::*;
+use crate::transport::{self, DoorError, Transport};
+use d3xs_protocol::chall::Challenge;
+use d3xs_protocol::command::{Command, Outcome, Request, Response};
+use d3xs_protocol::status::{Contact, Status};
+use d3xs_protocol::{crypto, outputs};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::{broadcast, mpsc};
+
+pub struct SimDoor {
+    salsa: crypto::SalsaBox,
+    pending: Mutex<Option<Challenge>>,
+    // the outputs that have been triggered, in order
+    opened: Mutex<Vec<u8>>,
+    // the outputs that are held open
+    held: Mutex<Vec<u8>>,
+    booted: Instant,
+    last_open: Mutex<Option<Instant>>,
+    failed_attempts: AtomicU32,
+    // the time the bridge has sent with its last command
+    time: Mutex<Option<u64>>,
+    contact: Mutex<Option<Contact>>,
+    // subscribers of the status
+    notify: broadcast::Sender<Status>,
+}
+
+impl SimDoor {
+    pub fn new(bridge_key: &crypto::PublicKey, secret_key: &crypto::SecretKey) -> Self {
+        SimDoor {
+            salsa: crypto::SalsaBox::new(bridge_key, secret_key),
+            pending: Mutex::new(None),
+            opened: Mutex::new(Vec::new()),
+            held: Mutex::new(Vec::new()),
+            booted: Instant::now(),
+            last_open: Mutex::new(None),
+            failed_attempts: AtomicU32::new(0),
+            time: Mutex::new(None),
+            contact: Mutex::new(None),
+            notify: broadcast::channel(16).0,
+        }
+    }
+
+    /// Read the characteristic, this returns a fresh encrypted challenge
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let chall = Challenge::generate::<crypto::Random>(&self.salsa)
+            .map_err(|_| anyhow!("Failed to generate challenge"))?;
+        let encrypted = chall.encrypted.to_vec();
+        *self.pending.lock().unwrap() = Some(chall);
+        Ok(encrypted)
+    }
+
+    /// Write the solution to the characteristic, the challenge can only be used once
+    pub fn write(&self, buf: &[u8]) -> Result<()> {
+        let chall = self
+            .pending
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(DoorError::NoChallenge)?;
+        let output = outputs::verify(&self.salsa, &chall, buf).map_err(|_| {
+            self.failed_attempts.fetch_add(1, Ordering::Relaxed);
+            DoorError::Rejected
+        })?;
+        self.opened.lock().unwrap().push(output);
+        *self.last_open.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Write a command, the encrypted result is returned like the firmware would on a read
+    pub fn command(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let chall = self
+            .pending
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(DoorError::NoChallenge)?;
+        let request = Request::verify(&self.salsa, &chall, buf).map_err(|_| {
+            self.failed_attempts.fetch_add(1, Ordering::Relaxed);
+            DoorError::Rejected
+        })?;
+        *self.time.lock().unwrap() = Some(request.time);
+
+        let mut status = None;
+        match request.command {
+            Command::Open { output, .. } => {
+                self.opened.lock().unwrap().push(output);
+                *self.last_open.lock().unwrap() = Some(Instant::now());
+            }
+            Command::HoldOpen { output } => {
+                self.opened.lock().unwrap().push(output);
+                self.held.lock().unwrap().push(output);
+                *self.last_open.lock().unwrap() = Some(Instant::now());
+            }
+            Command::Lock { output } => self.held.lock().unwrap().retain(|o| *o != output),
+            Command::Status => status = Some(self.status()),
+            Command::Identify => info!("Simulated door is blinking its led"),
+        }
+
+        request
+            .respond(Outcome::Ok, status)
+            .seal::<crypto::Random>(&self.salsa)
+            .map_err(|_| anyhow!("Failed to encrypt result"))
+    }
+
+    /// Read the status characteristic
+    pub fn status(&self) -> Status {
+        let secs = |instant: Instant| instant.elapsed().as_secs() as u32;
+        Status {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime: secs(self.booted),
+            last_open: self.last_open.lock().unwrap().map(secs),
+            failed_attempts: self.failed_attempts.load(Ordering::Relaxed),
+            contact: self.contact.lock().unwrap().clone(),
+        }
+    }
+
+    /// Update the state of the door contact and notify subscribers, like the firmware does
+    pub fn set_contact(&self, contact: Contact) {
+        *self.contact.lock().unwrap() = Some(contact);
+        // there may be no subscribers
+        self.notify.send(self.status()).ok();
+    }
+
+    /// How many subscribers are waiting for status notifications
+    pub fn subscribers(&self) -> usize {
+        self.notify.receiver_count()
+    }
+
+    /// The unix time the door has learned from the bridge
+    pub fn time(&self) -> Option<u64> {
+        *self.time.lock().unwrap()
+    }
+
+    /// How often the door has been opened
+    pub fn opened(&self) -> usize {
+        self.opened.lock().unwrap().len()
+    }
+
+    /// The outputs that have been triggered, in order
+    pub fn outputs(&self) -> Vec<u8> {
+        self.opened.lock().unwrap().clone()
+    }
+
+    /// The outputs that are held open
+    pub fn held(&self) -> Vec<u8> {
+        self.held.lock().unwrap().clone()
+    }
+}
+
+#[derive(Default)]
+pub struct Sim {
+    doors: HashMap<String, SimDoor>,
+}
+
+impl Sim {
+    /// Setup doors from `<mac>=<secret key>` arguments
+    pub fn from_args(bridge_key: &crypto::PublicKey, args: &[String]) -> Result<Self> {
+        let mut sim = Sim::default();
+        for arg in args {
+            let (mac, secret_key) = arg
+                .split_once('=')
+                .with_context(|| anyhow!("Simulated door is missing a `=`: {arg:?}"))?;
+            let secret_key = crypto::secret_key(secret_key)
+                .map_err(|_| anyhow!("Failed to parse secret key of simulated door {mac:?}"))?;
+            sim.add_door(mac, SimDoor::new(bridge_key, &secret_key));
+        }
+        Ok(sim)
+    }
+
+    pub fn add_door(&mut self, mac: &str, door: SimDoor) {
+        self.doors.insert(mac.to_lowercase(), door);
+    }
+
+    pub fn door(&self, mac: &str) -> Option<&SimDoor> {
+        self.doors.get(&mac.to_lowercase())
+    }
+}
+
+impl Transport for Sim {
+    async fn open(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        output: Option<u8>,
+        _timeout: u64,
+    ) -> Result<()> {
+        let door = self
+            .door(mac)
+            .with_context(|| anyhow!("No simulated door with this mac: {mac:?}"))?;
+
+        let chall = door.read()?;
+        let solution = transport::solve(keys, &chall, output)?;
+        door.write(&solution)?;
+
+        info!("Simulated door has opened (mac={mac:?}, output={output:?})");
+        Ok(())
+    }
+
+    async fn status(&self, mac: &str, _timeout: u64) -> Result<Status> {
+        let door = self
+            .door(mac)
+            .with_context(|| anyhow!("No simulated door with this mac: {mac:?}"))?;
+        Ok(door.status())
+    }
+
+    async fn command(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        command: Command,
+        _timeout: u64,
+    ) -> Result<Response> {
+        let door = self
+            .door(mac)
+            .with_context(|| anyhow!("No simulated door with this mac: {mac:?}"))?;
+
+        let chall = door.read()?;
+        let (salsa, request, encrypted) = transport::command(keys, &chall, command)?;
+        let result = door.command(&encrypted)?;
+        transport::command_result(salsa, &request, &result)
+    }
+
+    async fn subscribe(&self, mac: &str, _timeout: u64, tx: mpsc::Sender<Status>) -> Result<()> {
+        let mut rx = self
+            .door(mac)
+            .with_context(|| anyhow!("No simulated door with this mac: {mac:?}"))?
+            .notify
+            .subscribe();
+        while let Ok(status) = rx.recv().await {
+            if tx.send(status).await.is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn open_simulated_door() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+
+        let mut sim = Sim::default();
+        sim.add_door(
+            "EC:DA:3B:FF:FF:FF",
+            SimDoor::new(&bridge.public_key(), &door),
+        );
+
+        let keys = [crypto::SalsaBox::new(&door.public_key(), &bridge)];
+        sim.open(&keys, "ec:da:3b:ff:ff:ff", None, 0).await?;
+        assert_eq!(sim.door("ec:da:3b:ff:ff:ff").unwrap().opened(), 1);
+
+        // a bridge with the wrong key can't open the door
+        let mallory = crypto::generate_secret_key::<crypto::Random>();
+        let keys = [crypto::SalsaBox::new(&door.public_key(), &mallory)];
+        assert!(sim.open(&keys, "ec:da:3b:ff:ff:ff", None, 0).await.is_err());
+        assert!(sim.open(&keys, "ec:da:3b:00:00:00", None, 0).await.is_err());
+        assert_eq!(sim.door("ec:da:3b:ff:ff:ff").unwrap().opened(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_solution() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = SimDoor::new(
+            &bridge.public_key(),
+            &crypto::generate_secret_key::<crypto::Random>(),
+        );
+
+        let err = door.write(&[0u8; 32]).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DoorError>(),
+            Some(&DoorError::NoChallenge)
+        );
+
+        door.read()?;
+        let err = door.write(&[0u8; 32]).unwrap_err();
+        assert_eq!(err.downcast_ref::<DoorError>(), Some(&DoorError::Rejected));
+        assert_eq!(err.to_string(), "Door rejected solution");
+        assert_eq!(door.status().failed_attempts, 1);
+        assert_eq!(door.opened(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn select_output() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+
+        let mut sim = Sim::default();
+        sim.add_door(
+            "ec:da:3b:ff:ff:ff",
+            SimDoor::new(&bridge.public_key(), &door),
+        );
+
+        let keys = [crypto::SalsaBox::new(&door.public_key(), &bridge)];
+        sim.open(&keys, "ec:da:3b:ff:ff:ff", Some(2), 0).await?;
+        sim.open(&keys, "ec:da:3b:ff:ff:ff", None, 0).await?;
+        sim.open(&keys, "ec:da:3b:ff:ff:ff", Some(1), 0).await?;
+        assert_eq!(
+            sim.door("ec:da:3b:ff:ff:ff").unwrap().outputs(),
+            vec![2, 0, 1]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accept_either_bridge_key() -> Result<()> {
+        let current = crypto::generate_secret_key::<crypto::Random>();
+        let next = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+
+        // one door has already been moved to the next key
+        let mut sim = Sim::default();
+        sim.add_door(
+            "ec:da:3b:00:00:01",
+            SimDoor::new(&current.public_key(), &door),
+        );
+        sim.add_door("ec:da:3b:00:00:02", SimDoor::new(&next.public_key(), &door));
+
+        let keys = [
+            crypto::SalsaBox::new(&door.public_key(), &current),
+            crypto::SalsaBox::new(&door.public_key(), &next),
+        ];
+        sim.open(&keys, "ec:da:3b:00:00:01", None, 0).await?;
+        sim.open(&keys, "ec:da:3b:00:00:02", Some(1), 0).await?;
+        assert_eq!(sim.door("ec:da:3b:00:00:01").unwrap().outputs(), vec![0]);
+        assert_eq!(sim.door("ec:da:3b:00:00:02").unwrap().outputs(), vec![1]);
+
+        // without the next key, the second door can't be opened anymore
+        assert!(sim
+            .open(&keys[..1], "ec:da:3b:00:00:02", None, 0)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn send_commands() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+        let mac = "ec:da:3b:ff:ff:ff";
+
+        let mut sim = Sim::default();
+        sim.add_door(mac, SimDoor::new(&bridge.public_key(), &door));
+        let keys = [crypto::SalsaBox::new(&door.public_key(), &bridge)];
+
+        let open = Command::Open {
+            output: 1,
+            seconds: Some(10),
+        };
+        let response = sim.command(&keys, mac, open, 0).await?;
+        assert_eq!(response.outcome, Outcome::Ok);
+        sim.command(&keys, mac, Command::HoldOpen { output: 0 }, 0)
+            .await?;
+        assert_eq!(sim.door(mac).unwrap().outputs(), vec![1, 0]);
+        assert_eq!(sim.door(mac).unwrap().held(), vec![0]);
+        sim.command(&keys, mac, Command::Lock { output: 0 }, 0)
+            .await?;
+        assert_eq!(sim.door(mac).unwrap().held(), Vec::<u8>::new());
+
+        let response = sim.command(&keys, mac, Command::Status, 0).await?;
+        assert!(response.status.unwrap().last_open.is_some());
+
+        // a bridge with the wrong key can't send commands
+        let mallory = crypto::generate_secret_key::<crypto::Random>();
+        let keys = [crypto::SalsaBox::new(&door.public_key(), &mallory)];
+        assert!(sim.command(&keys, mac, Command::Identify, 0).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+use crate::ble::Btleplug;
+use crate::errors::*;
+use crate::sim::Sim;
+use d3xs_protocol::chall::{self, Clock};
+use d3xs_protocol::command::{Command, Request, Response};
+use d3xs_protocol::status::Status;
+use d3xs_protocol::{crypto, outputs};
+use std::fmt;
+use std::future::Future;
+use tokio::sync::mpsc;
+
+/// The door has received a write, but refused it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DoorError {
+    /// The solution doesn't match the challenge, or the selected output doesn't exist
+    Rejected,
+    /// The door had no challenge for the connection anymore, this can be retried
+    NoChallenge,
+}
+
+impl DoorError {
+    /// Interpret the code the door has responded to a write with
+    pub fn from_result(result: chall::WriteResult) -> Option<Self> {
+        match result {
+            chall::WriteResult::Ok => None,
+            chall::WriteResult::Rejected => Some(DoorError::Rejected),
+            chall::WriteResult::NoChallenge => Some(DoorError::NoChallenge),
+        }
+    }
+}
+
+impl fmt::Display for DoorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DoorError::Rejected => write!(f, "Door rejected solution"),
+            DoorError::NoChallenge => write!(f, "Door has no pending challenge for the solution"),
+        }
+    }
+}
+
+impl std::error::Error for DoorError {}
+
+/// How the bridge talks to doors
+pub trait Transport {
+    /// Connect to the door with this mac address, solve its challenge and open it
+    ///
+    /// `keys` has a box for each bridge key the door may trust, the first one that can decrypt
+    /// the challenge is used. If `output` is set, the door is asked to trigger this output
+    /// instead of its first one.
+    fn open(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        output: Option<u8>,
+        timeout: u64,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Connect to the door with this mac address and read its status
+    fn status(&self, mac: &str, timeout: u64) -> impl Future<Output = Result<Status>> + Send;
+
+    /// Connect to the door with this mac address and send an authenticated command
+    fn command(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        command: Command,
+        timeout: u64,
+    ) -> impl Future<Output = Result<Response>> + Send;
+
+    /// Stay connected to the door with this mac address and send each status it notifies about
+    ///
+    /// Returns once the door has disconnected.
+    fn subscribe(
+        &self,
+        mac: &str,
+        timeout: u64,
+        tx: mpsc::Sender<Status>,
+    ) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// The transport selected on the command line
+pub enum Backend {
+    Btleplug(Btleplug),
+    Sim(Sim),
+}
+
+impl Transport for Backend {
+    async fn open(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        output: Option<u8>,
+        timeout: u64,
+    ) -> Result<()> {
+        match self {
+            Backend::Btleplug(ble) => ble.open(keys, mac, output, timeout).await,
+            Backend::Sim(sim) => sim.open(keys, mac, output, timeout).await,
+        }
+    }
+
+    async fn status(&self, mac: &str, timeout: u64) -> Result<Status> {
+        match self {
+            Backend::Btleplug(ble) => ble.status(mac, timeout).await,
+            Backend::Sim(sim) => sim.status(mac, timeout).await,
+        }
+    }
+
+    async fn command(
+        &self,
+        keys: &[crypto::SalsaBox],
+        mac: &str,
+        command: Command,
+        timeout: u64,
+    ) -> Result<Response> {
+        match self {
+            Backend::Btleplug(ble) => ble.command(keys, mac, command, timeout).await,
+            Backend::Sim(sim) => sim.command(keys, mac, command, timeout).await,
+        }
+    }
+
+    async fn subscribe(&self, mac: &str, timeout: u64, tx: mpsc::Sender<Status>) -> Result<()> {
+        match self {
+            Backend::Btleplug(ble) => ble.subscribe(mac, timeout, tx).await,
+            Backend::Sim(sim) => sim.subscribe(mac, timeout, tx).await,
+        }
+    }
+}
+
+// try each key until one can decrypt the challenge
+fn decrypt_challenge<'a>(
+    keys: &'a [crypto::SalsaBox],
+    chall: &[u8],
+) -> Result<(&'a crypto::SalsaBox, [u8; chall::CHALL_SIZE])> {
+    if chall.len() > chall::CHALL_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE {
+        bail!("Challenge is too large");
+    }
+    keys.iter()
+        .find_map(|salsa| {
+            let mut buf = [0u8; chall::CHALL_SIZE];
+            let decrypted = crypto::decrypt(salsa, chall, &mut buf).ok()?;
+            let code = decrypted.try_into().ok()?;
+            Some((salsa, code))
+        })
+        .context("Failed to decrypt solution")
+}
+
+/// Decrypt the challenge of a door and build the solution for it
+pub fn solve(keys: &[crypto::SalsaBox], chall: &[u8], output: Option<u8>) -> Result<Vec<u8>> {
+    let (salsa, code) = decrypt_challenge(keys, chall)?;
+    outputs::solution::<crypto::Random>(salsa, &code, output)
+        .map_err(|_| anyhow!("Failed to encrypt solution"))
+}
+
+/// Decrypt the challenge of a door and encrypt a command for it
+///
+/// Returns the box that has been used, the result from the door needs to be opened with it.
+pub fn command<'a>(
+    keys: &'a [crypto::SalsaBox],
+    chall: &[u8],
+    command: Command,
+) -> Result<(&'a crypto::SalsaBox, Request, Vec<u8>)> {
+    let (salsa, code) = decrypt_challenge(keys, chall)?;
+    // the door learns the time from this, to check the expiry of offline tokens
+    let time = chall::SystemClock::now();
+    let request = Request {
+        code,
+        command,
+        time,
+    };
+    let encrypted = request
+        .seal::<crypto::Random>(salsa)
+        .map_err(|_| anyhow!("Failed to encrypt command"))?;
+    Ok((salsa, request, encrypted))
+}
+
+/// Decrypt the result of a command, it needs to belong to the command that has been sent
+pub fn command_result(
+    salsa: &crypto::SalsaBox,
+    request: &Request,
+    encrypted: &[u8],
+) -> Result<Response> {
+    Response::open(salsa, request, encrypted)
+        .map_err(|err| anyhow!("Failed to decrypt result of command: {err}"))
+}
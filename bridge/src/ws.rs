@@ -1,46 +1,131 @@
-use crate::ble;
+use crate::audit::{self, AuditLog};
 use crate::config;
 use crate::errors::*;
+use crate::guests::Guests;
+use crate::health::Health;
+use crate::metrics::{Metrics, METRICS};
+use crate::notify::{self, EventKind};
+use crate::transport::{DoorError, Transport};
+use chrono::Utc;
+use d3xs_protocol::auth;
 use d3xs_protocol::chall;
 use d3xs_protocol::crypto;
 use d3xs_protocol::ipc;
 use data_encoding::BASE64;
 use futures_util::{SinkExt, StreamExt};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
 use tokio_tungstenite::{
     connect_async, tungstenite::protocol::Message, MaybeTlsStream, WebSocketStream,
 };
 
 // when working with a websocket, the timeout is much shorter to avoid hanging
 const WS_BLE_TIMEOUT: u64 = 5;
+// how often to check if doors entered or left a user's schedule
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
 
 type Stream = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
 
+/// State that is kept across reconnects
+#[derive(Default)]
+pub struct State {
+    pub challenges: chall::UserDoorMap,
+    pub audit: Arc<AuditLog>,
+    pub guests: Guests,
+    /// If a connection has been established before, the next one is a reconnect
+    pub connected: bool,
+    /// The config challenges have been issued under, compared to the current one on reconnect
+    pub config: Option<config::Config>,
+    /// Unauthorized attempts, these are only reported to webhooks once they repeat
+    pub failures: notify::Failures,
+}
+
 async fn send_ws(ws_stream: &mut Stream, msg: &ipc::BridgeResponse) -> Result<()> {
     let ipc = serde_json::to_string(&msg)?;
     ws_stream.send(Message::Text(ipc)).await?;
     Ok(())
 }
 
+async fn send_result(
+    ws_stream: &mut Stream,
+    user: &str,
+    door: &str,
+    outcome: ipc::Outcome,
+) -> Result<()> {
+    let result = ipc::OpenResult {
+        user: user.to_string(),
+        door: door.to_string(),
+        outcome,
+    };
+    send_ws(ws_stream, &ipc::BridgeResponse::OpenResult(result)).await
+}
+
+fn notify(config: &config::Config, kind: EventKind, user: &str, door: &str, message: String) {
+    let event = notify::Event::new(kind, Some(user), Some(door), message);
+    notify::send(&config.notify, event);
+}
+
+// anybody can cause these, so they are only sent once they repeat and at a limited rate
+fn notify_unauthorized(
+    config: &config::Config,
+    failures: &mut notify::Failures,
+    user: &str,
+    door: &str,
+    message: String,
+) {
+    if let Some(count) = failures.record(user, door, time::Instant::now()) {
+        let message = format!("{message} ({count} attempts)");
+        notify(config, EventKind::Unauthorized, user, door, message);
+    }
+}
+
 async fn process_fetch(
     ws_stream: &mut Stream,
     config: &config::Config,
     secret_key: &crypto::SecretKey,
-    challenges: &mut chall::UserDoorMap,
+    state: &mut State,
     fetch: ipc::Fetch,
 ) -> Result<()> {
+    let State {
+        challenges,
+        audit,
+        guests,
+        failures,
+        ..
+    } = state;
     let Some(user) = fetch.user else {
         return Ok(());
     };
     let door = fetch.door;
 
     info!("Challenge has been requested (user={user:?}, door={door:?}");
-    let userdata = config
-        .users
-        .get(&user)
-        .with_context(|| anyhow!("Failed to find user: {user:?}"))?;
+    let Some(userdata) = config.users.get(&user) else {
+        METRICS.auth_failed(&user);
+        audit
+            .record(audit::Event::Unauthorized, &user, &door, "user not found")
+            .await;
+        let message = format!(
+            "Unknown user {user:?} tried to open {}",
+            config.door_label(&door)
+        );
+        notify_unauthorized(config, failures, &user, &door, message);
+        bail!("Failed to find user: {user:?}");
+    };
 
-    if userdata.authorize.iter().all(|d| *d != door) {
-        warn!("User is not authorized for door (user={user:?}, door={door:?}");
+    if let Err(reason) = config.check_access(userdata, &door, Utc::now(), guests) {
+        warn!("Refusing to issue challenge (user={user:?}, door={door:?}): {reason}");
+        METRICS.auth_failed(&user);
+        audit
+            .record(
+                audit::Event::Unauthorized,
+                &user,
+                &door,
+                &reason.to_string(),
+            )
+            .await;
+        let message = format!("{user} can't open {}: {reason}", config.door_label(&door));
+        notify_unauthorized(config, failures, &user, &door, message);
         return Ok(());
     }
 
@@ -48,79 +133,250 @@ async fn process_fetch(
         .map_err(|_| anyhow!("Failed to decode public key"))?;
 
     let salsa = crypto::SalsaBox::new(&public_key, secret_key);
-    let chall = challenges.generate_next::<crypto::Random>(user.clone(), door, &salsa);
+    let chall = challenges
+        .generate_next::<crypto::Random, chall::SystemClock>(
+            user.clone(),
+            door.clone(),
+            &salsa,
+            &secret_key.public_key(),
+        )
+        .map_err(|_| anyhow!("Failed to generate challenge"))?;
+    audit
+        .record(audit::Event::Fetch, &user, &door, "issued")
+        .await;
 
     let chall = ipc::Challenge {
         user,
-        challenge: BASE64.encode(&chall.encrypted),
+        challenge: BASE64.encode(&chall.message()),
     };
     send_ws(ws_stream, &ipc::BridgeResponse::Challenge(chall)).await?;
 
     Ok(())
 }
 
-async fn process_solve(
-    _ws_stream: &mut Stream,
+async fn process_solve<T: Transport>(
+    ws_stream: &mut Stream,
     config: &config::Config,
-    secret_key: &crypto::SecretKey,
-    challenges: &mut chall::UserDoorMap,
+    keys: &[crypto::SecretKey],
+    state: &mut State,
+    transport: &T,
     solve: ipc::Solve,
 ) -> Result<()> {
+    let State {
+        challenges,
+        audit,
+        guests,
+        failures,
+        ..
+    } = state;
     debug!("Received solve attempt: {solve:?}");
     let Some(user) = solve.user else {
         return Ok(());
     };
     let Ok(code) = BASE64.decode(solve.code.as_bytes()) else {
-        return Ok(());
+        return send_result(ws_stream, &user, &solve.door, ipc::Outcome::InvalidSolution).await;
     };
 
-    let userdata = config
-        .users
-        .get(&user)
-        .with_context(|| anyhow!("Failed to find user: {user:?}"))?;
+    let Some(userdata) = config.users.get(&user) else {
+        METRICS.auth_failed(&user);
+        audit
+            .record(
+                audit::Event::Unauthorized,
+                &user,
+                &solve.door,
+                "user not found",
+            )
+            .await;
+        let label = config.door_label(&solve.door);
+        let message = format!("Unknown user {user:?} tried to open {label}");
+        notify_unauthorized(config, failures, &user, &solve.door, message);
+        bail!("Failed to find user: {user:?}");
+    };
 
-    let public_key = crypto::public_key(&userdata.public_key)
-        .map_err(|_| anyhow!("Failed to decode public key"))?;
+    if let Err(reason) = config.check_access(userdata, &solve.door, Utc::now(), guests) {
+        warn!(
+            "Refusing solve attempt (user={user:?}, door={:?}): {reason}",
+            solve.door
+        );
+        METRICS.auth_failed(&user);
+        audit
+            .record(
+                audit::Event::Unauthorized,
+                &user,
+                &solve.door,
+                &reason.to_string(),
+            )
+            .await;
+        let label = config.door_label(&solve.door);
+        let message = format!("{user} can't open {label}: {reason}");
+        notify_unauthorized(config, failures, &user, &solve.door, message);
+        return send_result(ws_stream, &user, &solve.door, ipc::Outcome::Unauthorized).await;
+    }
 
-    if let Ok(door) = challenges.verify(user.clone(), solve.door.clone(), &code) {
+    let solved = challenges.verify::<chall::SystemClock>(
+        user.clone(),
+        solve.door.clone(),
+        &code,
+        config.challenge_ttl(),
+    );
+    if let Ok(door) = solved {
         info!("Challenge successfully solved (user={user:?}, door={door:?})",);
-        let salsa = crypto::SalsaBox::new(&public_key, secret_key);
-        challenges.reset::<crypto::Random>(user.clone(), door.clone(), &salsa);
+        Metrics::inc(&METRICS.solves_ok);
+        audit.record(audit::Event::Solved, &user, &door, "ok").await;
+        challenges.reset(user.clone(), door.clone());
 
+        let door_id = door;
         let door = config
             .doors
-            .get(&door)
-            .with_context(|| anyhow!("Door is not known {door:?}"))?;
+            .get(&door_id)
+            .with_context(|| anyhow!("Door is not known {door_id:?}"))?;
 
         if let (Some(mac), Some(public_key)) = (&door.mac, &door.public_key) {
             let public_key = crypto::public_key(public_key)
                 .map_err(|_| anyhow!("Failed to parse public key"))?;
 
-            let salsa = crypto::SalsaBox::new(&public_key, secret_key);
-            if let Err(err) = ble::open(&salsa, mac, WS_BLE_TIMEOUT).await {
+            // during a key rotation the door may trust either bridge key
+            let salsas = keys
+                .iter()
+                .map(|key| crypto::SalsaBox::new(&public_key, key))
+                .collect::<Vec<_>>();
+            let outcome = if let Err(err) = transport
+                .open(&salsas, mac, door.output, WS_BLE_TIMEOUT)
+                .await
+            {
                 error!("Failed to open door: {err:#}");
+                audit
+                    .record(
+                        audit::Event::Open,
+                        &user,
+                        &door_id,
+                        &format!("failed: {err:#}"),
+                    )
+                    .await;
+                let message = format!("Failed to open {} for {user}: {err:#}", door.label);
+                notify(config, EventKind::Failed, &user, &door_id, message);
+                if err.downcast_ref::<time::error::Elapsed>().is_some() {
+                    ipc::Outcome::Timeout
+                } else if err.downcast_ref::<DoorError>() == Some(&DoorError::Rejected) {
+                    ipc::Outcome::Rejected
+                } else {
+                    ipc::Outcome::BleUnreachable
+                }
             } else {
                 info!("Successfully opened door");
-            }
+                audit
+                    .record(audit::Event::Open, &user, &door_id, "opened")
+                    .await;
+                let message = format!("{user} opened {}", door.label);
+                notify(config, EventKind::Opened, &user, &door_id, message);
+                if userdata.is_guest() {
+                    if let Err(err) = guests.record_use(&userdata.public_key).await {
+                        error!("Failed to record use of guest link: {err:#}");
+                    }
+                }
+                ipc::Outcome::Opened
+            };
+            send_result(ws_stream, &user, &door_id, outcome).await?;
+        } else {
+            warn!("Door has no bluetooth configured, can't open (door={door_id:?})");
+            send_result(ws_stream, &user, &door_id, ipc::Outcome::BleUnreachable).await?;
         }
     } else {
+        let reason = match solved {
+            Err(d3xs_protocol::errors::Error::ChallengeExpired) => "expired challenge",
+            _ => "invalid solution",
+        };
         warn!(
-            "Solve attempt failed (user={user:?}, door={:?})",
+            "Solve attempt failed (user={user:?}, door={:?}): {reason}",
             solve.door
         );
+        Metrics::inc(&METRICS.solves_failed);
+        METRICS.auth_failed(&user);
+        audit
+            .record(audit::Event::SolveFailed, &user, &solve.door, reason)
+            .await;
+        let label = config.door_label(&solve.door);
+        let message = format!("{user} failed to solve the challenge for {label}");
+        notify(config, EventKind::Failed, &user, &solve.door, message);
+        send_result(ws_stream, &user, &solve.door, ipc::Outcome::InvalidSolution).await?;
     }
 
     Ok(())
 }
 
-pub async fn connect(
-    url: &str,
+async fn process_auth(
+    ws_stream: &mut Stream,
+    keys: &[crypto::SecretKey],
+    auth: ipc::Auth,
+) -> Result<()> {
+    debug!("Server has requested authentication");
+    let server_key = crypto::public_key(&auth.public_key)
+        .map_err(|_| anyhow!("Failed to decode public key of server"))?;
+    let challenge = BASE64.decode(auth.challenge.as_bytes())?;
+
+    // the webserver may already use the next key during a rotation
+    let mut buf = [0u8; chall::CHALL_SIZE];
+    let code = keys
+        .iter()
+        .find_map(|key| {
+            auth::solve(key, &server_key, &challenge, &mut buf)
+                .ok()
+                .map(|code| BASE64.encode(code))
+        })
+        .context("Failed to decrypt authentication challenge")?;
+
+    let response = ipc::AuthResponse { code };
+    send_ws(ws_stream, &ipc::BridgeResponse::Auth(response)).await?;
+
+    Ok(())
+}
+
+fn shared_config(
     config: &config::Config,
+    state: &State,
+    health: &watch::Receiver<Health>,
+) -> Result<ipc::Config> {
+    let mut ipc = config.to_shared_config(Utc::now(), &state.guests)?;
+    health.borrow().apply(&mut ipc);
+    Ok(ipc)
+}
+
+/// Drop challenges that can't be solved with the new config anymore
+fn retain_challenges(
     challenges: &mut chall::UserDoorMap,
+    old: &config::Config,
+    new: &config::Config,
+) {
+    if old.system.secret_key != new.system.secret_key {
+        challenges.clear();
+        return;
+    }
+
+    challenges.retain(|user, door| {
+        let (Some(old_user), Some(new_user)) = (old.users.get(user), new.users.get(user)) else {
+            return false;
+        };
+        old_user.public_key == new_user.public_key
+            && !new.is_revoked(new_user)
+            && new_user.authorize.iter().any(|d| d == door)
+            && new.doors.contains_key(door)
+    });
+}
+
+pub async fn connect<T: Transport>(
+    url: &str,
+    config_rx: &mut watch::Receiver<config::Config>,
+    health_rx: &mut watch::Receiver<Health>,
+    state: &mut State,
+    transport: &T,
 ) -> Result<()> {
-    let ipc = config.to_shared_config()?;
-    let secret_key = crypto::secret_key(&config.system.secret_key)
-        .map_err(|_| anyhow!("Failed to decode secret key :<"))?;
+    let mut config = config_rx.borrow_and_update().clone();
+    if let Some(old) = state.config.replace(config.clone()) {
+        // the config may have changed while we were disconnected
+        retain_challenges(&mut state.challenges, &old, &config);
+    }
+    let mut ipc = shared_config(&config, state, health_rx)?;
+    let mut keys = config.bridge_keys()?;
 
     debug!("Connecting to {url:?}...");
     let (mut ws_stream, _) = connect_async(url)
@@ -128,22 +384,256 @@ pub async fn connect(
         .with_context(|| anyhow!("Failed to connect to {url:?}"))?;
 
     debug!("Connected, sending configuration...");
-    send_ws(&mut ws_stream, &ipc::BridgeResponse::Config(ipc)).await?;
+    send_ws(&mut ws_stream, &ipc::BridgeResponse::Config(ipc.clone())).await?;
+
+    if state.connected {
+        let event = notify::Event::new(
+            EventKind::BridgeReconnected,
+            None,
+            None,
+            "Bridge has reconnected to the server".to_string(),
+        );
+        notify::send(&config.notify, event);
+    }
+    state.connected = true;
 
+    let mut schedule = time::interval(SCHEDULE_INTERVAL);
+    // the server may only ask once for authentication, before anything else
+    let mut handshake = true;
     info!("Connection established, waiting for events...");
-    while let Some(msg) = ws_stream.next().await {
-        let Message::Text(text) = msg? else { continue };
-        let request = serde_json::from_str::<ipc::ClientRequest>(&text)?;
+    loop {
+        tokio::select! {
+            msg = ws_stream.next() => {
+                let Some(msg) = msg else { break };
+                let Message::Text(text) = msg? else { continue };
+                let request = serde_json::from_str::<ipc::ClientRequest>(&text)?;
+                let handshake = std::mem::replace(&mut handshake, false);
 
-        match request {
-            ipc::ClientRequest::Fetch(fetch) => {
-                process_fetch(&mut ws_stream, config, &secret_key, challenges, fetch).await?
+                match request {
+                    ipc::ClientRequest::Fetch(fetch) => {
+                        process_fetch(&mut ws_stream, &config, &keys[0], state, fetch).await?
+                    }
+                    ipc::ClientRequest::Solve(solve) => {
+                        process_solve(&mut ws_stream, &config, &keys, state, transport, solve).await?;
+
+                        // guest links may have been used up
+                        let update = shared_config(&config, state, health_rx)?;
+                        if update != ipc {
+                            info!("Guest link has been used up, sending configuration...");
+                            ipc = update;
+                            send_ws(&mut ws_stream, &ipc::BridgeResponse::Config(ipc.clone())).await?;
+                        }
+                    }
+                    ipc::ClientRequest::Auth(auth) if handshake => {
+                        process_auth(&mut ws_stream, &keys, auth).await?
+                    }
+                    ipc::ClientRequest::Auth(_) => {
+                        warn!("Server has requested authentication after the handshake, ignoring");
+                    }
+                }
+            }
+            changed = config_rx.changed() => {
+                changed.context("Config watcher has stopped")?;
+                let new = config_rx.borrow_and_update().clone();
+                ipc = shared_config(&new, state, health_rx)?;
+                keys = new.bridge_keys()?;
+                retain_challenges(&mut state.challenges, &config, &new);
+                state.config = Some(new.clone());
+                config = new;
+
+                info!("Config has been reloaded, sending configuration...");
+                send_ws(&mut ws_stream, &ipc::BridgeResponse::Config(ipc.clone())).await?;
             }
-            ipc::ClientRequest::Solve(solve) => {
-                process_solve(&mut ws_stream, config, &secret_key, challenges, solve).await?
+            Ok(()) = health_rx.changed() => {
+                ipc = shared_config(&config, state, health_rx)?;
+                info!("Door reachability has changed, sending configuration...");
+                send_ws(&mut ws_stream, &ipc::BridgeResponse::Config(ipc.clone())).await?;
+            }
+            _ = schedule.tick() => {
+                let update = shared_config(&config, state, health_rx)?;
+                if update != ipc {
+                    info!("Doors have entered or left a schedule, sending configuration...");
+                    ipc = update;
+                    send_ws(&mut ws_stream, &ipc::BridgeResponse::Config(ipc.clone())).await?;
+                }
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Sim, SimDoor};
+    use tokio::net::TcpListener;
+
+    const MAC: &str = "ec:da:3b:ff:ff:ff";
+
+    fn encode_key(key: &crypto::PublicKey) -> String {
+        BASE64.encode(key.as_bytes())
+    }
+
+    async fn recv(ws: &mut WebSocketStream<tokio::net::TcpStream>) -> Result<ipc::BridgeResponse> {
+        loop {
+            let msg = ws.next().await.context("Bridge has disconnected")??;
+            if let Message::Text(text) = msg {
+                return Ok(serde_json::from_str(&text)?);
+            }
+        }
+    }
+
+    async fn send(
+        ws: &mut WebSocketStream<tokio::net::TcpStream>,
+        request: ipc::ClientRequest,
+    ) -> Result<()> {
+        ws.send(Message::Text(serde_json::to_string(&request)?))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_door_end_to_end() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+        let alice = crypto::generate_secret_key::<crypto::Random>();
+        let (webhook, mut webhook_rx) = crate::notify::tests::webhook_server(vec![]).await?;
+
+        let config = config::Config::parse(&format!(
+            r#"[system]
+secret_key = {:?}
+
+[users.alice]
+public_key = {:?}
+authorize = ["home"]
+
+[doors.home]
+label = "Home"
+mac = {MAC:?}
+public_key = {:?}
+
+[[notify]]
+url = {webhook:?}
+events = ["opened", "failed"]
+template = {:?}
+"#,
+            BASE64.encode(&bridge.to_bytes()),
+            encode_key(&alice.public_key()),
+            encode_key(&door.public_key()),
+            r#"{"text": "{{message}}"}"#,
+        ))?;
+
+        let mut sim = Sim::default();
+        sim.add_door(MAC, SimDoor::new(&bridge.public_key(), &door));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}/", listener.local_addr()?);
+
+        let (_config_tx, mut config_rx) = watch::channel(config);
+        let config_rx_check = config_rx.clone();
+        let (health_tx, mut health_rx) = watch::channel(Health::default());
+        let mut state = State::default();
+        let bridge_task = connect(&url, &mut config_rx, &mut health_rx, &mut state, &sim);
+
+        // act as the webserver and user
+        let server = async {
+            let (stream, _) = listener.accept().await?;
+            let mut ws = tokio_tungstenite::accept_async(stream).await?;
+
+            let ipc::BridgeResponse::Config(config) = recv(&mut ws).await? else {
+                bail!("Expected config from bridge");
+            };
+            assert_eq!(config.public_key, encode_key(&bridge.public_key()));
+
+            send(
+                &mut ws,
+                ipc::ClientRequest::Fetch(ipc::Fetch {
+                    user: Some("alice".to_string()),
+                    door: "home".to_string(),
+                }),
+            )
+            .await?;
+            let ipc::BridgeResponse::Challenge(challenge) = recv(&mut ws).await? else {
+                bail!("Expected challenge from bridge");
+            };
+
+            let salsa = crypto::SalsaBox::new(&bridge.public_key(), &alice);
+            let message = BASE64.decode(challenge.challenge.as_bytes())?;
+            let (binding, code) = chall::open(&salsa, &message)
+                .map_err(|_| anyhow!("Failed to decrypt challenge"))?;
+            assert_eq!(binding.user, "alice");
+            assert_eq!(binding.door, "home");
+            assert_eq!(binding.bridge, *bridge.public_key().as_bytes());
+
+            send(
+                &mut ws,
+                ipc::ClientRequest::Solve(ipc::Solve {
+                    user: Some("alice".to_string()),
+                    door: "home".to_string(),
+                    code: BASE64.encode(&code),
+                }),
+            )
+            .await?;
+
+            let ipc::BridgeResponse::OpenResult(result) = recv(&mut ws).await? else {
+                bail!("Expected result from bridge");
+            };
+            assert_eq!(
+                result,
+                ipc::OpenResult {
+                    user: "alice".to_string(),
+                    door: "home".to_string(),
+                    outcome: ipc::Outcome::Opened,
+                }
+            );
+
+            // the challenge can't be used twice
+            send(
+                &mut ws,
+                ipc::ClientRequest::Solve(ipc::Solve {
+                    user: Some("alice".to_string()),
+                    door: "home".to_string(),
+                    code: BASE64.encode(&code),
+                }),
+            )
+            .await?;
+            let ipc::BridgeResponse::OpenResult(result) = recv(&mut ws).await? else {
+                bail!("Expected result from bridge");
+            };
+            assert_eq!(result.outcome, ipc::Outcome::InvalidSolution);
+
+            // both events have been sent to the webhook, in the background
+            let mut events = Vec::new();
+            for _ in 0..2 {
+                events.push(webhook_rx.recv().await.context("Webhook has stopped")?);
+            }
+            events.sort();
+            assert_eq!(
+                events,
+                vec![
+                    r#"{"text": "alice failed to solve the challenge for Home"}"#,
+                    r#"{"text": "alice opened Home"}"#,
+                ]
+            );
+
+            // the webserver is told once the door can't be reached anymore
+            let sim = Sim::default();
+            let config = config_rx_check.borrow().clone();
+            let health = crate::health::check(&config, &Health::default(), &sim, 0).await;
+            health_tx.send_replace(health);
+            let ipc::BridgeResponse::Config(config) = recv(&mut ws).await? else {
+                bail!("Expected config from bridge");
+            };
+            assert!(config.doors["home"].unreachable);
+            Ok(())
+        };
+
+        tokio::select! {
+            ret = bridge_task => bail!("Bridge has stopped: {ret:?}"),
+            ret = server => ret?,
+        }
+        assert_eq!(sim.door(MAC).unwrap().opened(), 1);
+        Ok(())
+    }
+}
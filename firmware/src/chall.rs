@@ -1,4 +1,13 @@
+use crate::errors::*;
+use d3xs_protocol::chall::{Challenge, Clock, WriteResult};
 use d3xs_protocol::crypto;
+use d3xs_protocol::errors::Error as ProtocolError;
+use d3xs_protocol::outputs;
+
+/// How many seconds a challenge can be solved after it has been read
+pub const CHALL_TTL: u64 = 5;
+// connections are limited by the ble stack, this is only a safety net
+const MAX_SESSIONS: usize = 8;
 
 #[cfg(not(target_os = "espidf"))]
 pub type Random = crypto::Random;
@@ -14,3 +23,282 @@ impl crypto::Rng for Random {
         }
     }
 }
+
+#[cfg(not(target_os = "espidf"))]
+pub type Uptime = d3xs_protocol::chall::SystemClock;
+
+/// Seconds since boot, the door has no wall clock
+#[cfg(target_os = "espidf")]
+pub struct Uptime;
+
+#[cfg(target_os = "espidf")]
+impl Clock for Uptime {
+    fn now() -> u64 {
+        let micros = unsafe { esp_idf_svc::sys::esp_timer_get_time() };
+        u64::try_from(micros / 1_000_000).unwrap_or_default()
+    }
+}
+
+/// The current unix time, the door learns it from authenticated commands of the bridge
+#[derive(Default)]
+pub struct WallClock {
+    // unix time at boot
+    boot: Option<u64>,
+}
+
+impl WallClock {
+    /// Set the time, `C` is the clock that counts the seconds since boot
+    pub fn set<C: Clock>(&mut self, now: u64) {
+        self.boot = Some(now.saturating_sub(C::now()));
+    }
+
+    /// The current unix time, if it's known
+    pub fn now<C: Clock>(&self) -> Option<u64> {
+        self.boot.map(|boot| boot + C::now())
+    }
+}
+
+/// The pending challenge of each ble connection
+///
+/// Reading issues a new challenge for the connection, it can be used for a single solve attempt
+/// and only on the connection it has been issued for.
+#[derive(Default)]
+pub struct Sessions {
+    challenges: Vec<(u16, Challenge)>,
+}
+
+impl Sessions {
+    /// Issue a new challenge for the connection, replacing its previous one
+    pub fn issue<R: crypto::Rng, C: Clock>(
+        &mut self,
+        conn: u16,
+        salsa: &crypto::SalsaBox,
+    ) -> Result<&Challenge> {
+        let chall = Challenge::generate::<R>(salsa)?;
+        Ok(self.insert::<C>(conn, chall))
+    }
+
+    /// Keep a challenge that has been generated elsewhere for the connection
+    pub fn insert<C: Clock>(&mut self, conn: u16, mut chall: Challenge) -> &Challenge {
+        chall.issued = C::now();
+
+        self.disconnect(conn);
+        if self.challenges.len() >= MAX_SESSIONS {
+            self.challenges.remove(0);
+        }
+        self.challenges.push((conn, chall));
+        &self.challenges[self.challenges.len() - 1].1
+    }
+
+    /// Take the challenge of the connection for a solve attempt, it can't be used again afterwards
+    pub fn take<C: Clock>(&mut self, conn: u16) -> Result<Challenge> {
+        let idx = self
+            .challenges
+            .iter()
+            .position(|(c, _)| *c == conn)
+            .ok_or(ProtocolError::NoChallenge)?;
+        let (_, chall) = self.challenges.remove(idx);
+        if chall.is_expired(C::now(), CHALL_TTL) {
+            return Err(ProtocolError::ChallengeExpired.into());
+        }
+        Ok(chall)
+    }
+
+    /// Check a solution that has been written on the connection, returns the output to open
+    ///
+    /// The challenge is used up by any attempt. The solution is either written as-is, or
+    /// encrypted together with the output, which needs to be one of the `num_outputs` outputs.
+    pub fn solve<C: Clock>(
+        &mut self,
+        conn: u16,
+        salsa: &crypto::SalsaBox,
+        buf: &[u8],
+        num_outputs: usize,
+    ) -> Result<u8> {
+        let chall = self.take::<C>(conn)?;
+        let output = outputs::verify(salsa, &chall, buf)?;
+        if usize::from(output) >= num_outputs {
+            return Err(ProtocolError::InvalidField.into());
+        }
+        Ok(output)
+    }
+
+    /// The pending challenge of the connection, without using it up
+    pub fn get(&self, conn: u16) -> Option<&Challenge> {
+        self.challenges
+            .iter()
+            .find(|(c, _)| *c == conn)
+            .map(|(_, chall)| chall)
+    }
+
+    /// Forget the challenge of a connection that has been closed
+    pub fn disconnect(&mut self, conn: u16) {
+        self.challenges.retain(|(c, _)| *c != conn);
+    }
+
+    pub fn len(&self) -> usize {
+        self.challenges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.challenges.is_empty()
+    }
+}
+
+/// The code that is sent to the client for a failed write
+pub fn write_result(err: &Error) -> WriteResult {
+    match err {
+        Error::Protocol(ProtocolError::NoChallenge | ProtocolError::ChallengeExpired) => {
+            WriteResult::NoChallenge
+        }
+        _ => WriteResult::Rejected,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use d3xs_protocol::outputs as protocol_outputs;
+    use d3xs_protocol::testing;
+    use std::cell::Cell;
+
+    thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(100) };
+    }
+
+    struct TestClock;
+
+    impl TestClock {
+        fn advance(secs: u64) {
+            NOW.with(|now| now.set(now.get() + secs));
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now() -> u64 {
+            NOW.with(Cell::get)
+        }
+    }
+
+    struct Setup {
+        keys: testing::Setup,
+        sessions: Sessions,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            Setup {
+                keys: testing::Setup::new(),
+                sessions: Sessions::default(),
+            }
+        }
+
+        /// Read a challenge on the connection and solve it like the bridge does
+        fn read(&mut self, conn: u16) -> Vec<u8> {
+            let chall = self
+                .sessions
+                .issue::<Random, TestClock>(conn, &self.keys.door)
+                .unwrap();
+            self.keys.solve(chall).to_vec()
+        }
+
+        fn write(&mut self, conn: u16, buf: &[u8]) -> Result<u8> {
+            self.sessions
+                .solve::<TestClock>(conn, &self.keys.door, buf, 2)
+        }
+    }
+
+    #[test]
+    fn reject_replay() {
+        let mut setup = Setup::new();
+        let solution = setup.read(1);
+        assert_eq!(setup.write(1, &solution).unwrap(), 0);
+        // the challenge is gone after it has been used
+        assert!(setup.write(1, &solution).is_err());
+
+        // a failed attempt also uses up the challenge
+        let solution = setup.read(1);
+        let err = setup.write(1, &[0u8; 3]).unwrap_err();
+        assert_eq!(write_result(&err), WriteResult::Rejected);
+        let err = setup.write(1, &solution).unwrap_err();
+        assert_eq!(write_result(&err), WriteResult::NoChallenge);
+        assert!(setup.sessions.is_empty());
+
+        // reading again replaces the previous challenge
+        let old = setup.read(1);
+        let new = setup.read(1);
+        assert!(setup.write(1, &old).is_err());
+        setup.read(1);
+        assert!(setup.write(1, &new).is_err());
+    }
+
+    #[test]
+    fn select_output() {
+        let mut setup = Setup::new();
+        let code = setup.read(1);
+        let buf = protocol_outputs::solution::<Random>(&setup.keys.bridge, &code, Some(1)).unwrap();
+        assert_eq!(setup.write(1, &buf).unwrap(), 1);
+
+        // the door only has two outputs
+        let code = setup.read(1);
+        let buf = protocol_outputs::solution::<Random>(&setup.keys.bridge, &code, Some(2)).unwrap();
+        let err = setup.write(1, &buf).unwrap_err();
+        assert!(matches!(err, Error::Protocol(ProtocolError::InvalidField)));
+        assert_eq!(write_result(&err), WriteResult::Rejected);
+        assert!(setup.sessions.is_empty());
+    }
+
+    #[test]
+    fn challenge_expires() {
+        let mut setup = Setup::new();
+        let solution = setup.read(1);
+        TestClock::advance(CHALL_TTL + 1);
+        let err = setup.write(1, &solution).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Protocol(ProtocolError::ChallengeExpired)
+        ));
+        assert_eq!(write_result(&err), WriteResult::NoChallenge);
+
+        let solution = setup.read(1);
+        TestClock::advance(CHALL_TTL);
+        assert_eq!(setup.write(1, &solution).unwrap(), 0);
+    }
+
+    #[test]
+    fn wall_clock() {
+        let mut clock = WallClock::default();
+        assert_eq!(clock.now::<TestClock>(), None);
+        clock.set::<TestClock>(1700000000);
+        TestClock::advance(60);
+        assert_eq!(clock.now::<TestClock>(), Some(1700000060));
+    }
+
+    #[test]
+    fn concurrent_connections() {
+        let mut setup = Setup::new();
+        let alice = setup.read(1);
+        let bob = setup.read(2);
+        assert_eq!(setup.sessions.len(), 2);
+
+        // a solution can't be used on a different connection
+        assert!(setup.write(2, &alice).is_err());
+        // the other connection is not affected by it
+        assert_eq!(setup.write(1, &alice).unwrap(), 0);
+        assert!(setup.write(2, &bob).is_err());
+
+        let bob = setup.read(2);
+        setup.sessions.disconnect(2);
+        assert!(setup.write(2, &bob).is_err());
+
+        // the oldest challenge is dropped if there are too many connections
+        let first = setup.read(1);
+        for conn in 2..=MAX_SESSIONS as u16 {
+            setup.read(conn);
+        }
+        let last = setup.read(100);
+        assert_eq!(setup.sessions.len(), MAX_SESSIONS);
+        assert!(setup.write(1, &first).is_err());
+        assert_eq!(setup.write(100, &last).unwrap(), 0);
+    }
+}
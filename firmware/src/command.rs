@@ -0,0 +1,152 @@
+//! Commands from the bridge, see `d3xs_protocol::command`.
+use crate::chall::WallClock;
+use crate::errors::*;
+use crate::outputs::Output;
+use d3xs_protocol::chall::{Challenge, Clock};
+use d3xs_protocol::command::{Command, Outcome, Request, MAX_OPEN_SECONDS};
+use d3xs_protocol::crypto;
+use d3xs_protocol::status::Status;
+
+/// Verify a command and build the encrypted result for the bridge
+///
+/// Returns the command if the main loop needs to act on it, `status` is only called if the
+/// bridge has asked for it. The clock is set to the time of the bridge.
+pub fn process<R: crypto::Rng, C: Clock, F: FnOnce() -> Status>(
+    salsa: &crypto::SalsaBox,
+    chall: &Challenge,
+    buf: &[u8],
+    outputs: &[Output],
+    clock: &mut WallClock,
+    status: F,
+) -> Result<(Option<Command>, Vec<u8>)> {
+    let request = Request::verify(salsa, chall, buf)?;
+    clock.set::<C>(request.time);
+
+    let (outcome, action, status) = match request.command {
+        Command::Open { output, .. } | Command::HoldOpen { output } | Command::Lock { output }
+            if usize::from(output) >= outputs.len() =>
+        {
+            (Outcome::InvalidOutput, None, None)
+        }
+        // the main loop is blocked while the output is switched on
+        Command::Open {
+            seconds: Some(seconds),
+            ..
+        } if seconds > MAX_OPEN_SECONDS => (Outcome::InvalidDuration, None, None),
+        Command::Status => (Outcome::Ok, None, Some(status())),
+        command => (Outcome::Ok, Some(command), None),
+    };
+
+    let response = request.respond(outcome, status).seal::<R>(salsa)?;
+    Ok((action, response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chall;
+    use crate::outputs;
+    use d3xs_protocol::chall::CHALL_SIZE;
+    use d3xs_protocol::command::Response;
+    use d3xs_protocol::testing;
+
+    struct Setup {
+        keys: testing::Setup,
+        outputs: Vec<Output>,
+        clock: WallClock,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            Setup {
+                keys: testing::Setup::new(),
+                outputs: outputs::parse("4,5:10:low").unwrap(),
+                clock: WallClock::default(),
+            }
+        }
+
+        fn send(&mut self, command: Command) -> Result<(Option<Command>, Response)> {
+            let chall = Challenge::generate::<chall::Random>(&self.keys.door)?;
+            let code = self.keys.solve(&chall);
+            let request = Request {
+                code,
+                command,
+                time: 1700000000,
+            };
+            let buf = request.seal::<crypto::Random>(&self.keys.bridge)?;
+
+            let (action, response) = process::<chall::Random, chall::Uptime, _>(
+                &self.keys.door,
+                &chall,
+                &buf,
+                &self.outputs,
+                &mut self.clock,
+                || Status {
+                    version: "0.1.0".to_string(),
+                    uptime: 42,
+                    last_open: None,
+                    failed_attempts: 0,
+                    contact: None,
+                },
+            )?;
+            let response = Response::open(&self.keys.bridge, &request, &response)?;
+            Ok((action, response))
+        }
+    }
+
+    #[test]
+    fn process_commands() -> Result<()> {
+        let mut setup = Setup::new();
+
+        let open = Command::Open {
+            output: 1,
+            seconds: Some(20),
+        };
+        let (action, response) = setup.send(open)?;
+        assert_eq!(action, Some(open));
+        assert_eq!(response.outcome, Outcome::Ok);
+        assert_eq!(response.status, None);
+
+        let (action, response) = setup.send(Command::Status)?;
+        assert_eq!(action, None);
+        assert_eq!(response.status.map(|s| s.uptime), Some(42));
+
+        let (action, response) = setup.send(Command::HoldOpen { output: 2 })?;
+        assert_eq!(action, None);
+        assert_eq!(response.outcome, Outcome::InvalidOutput);
+
+        let (action, response) = setup.send(Command::Open {
+            output: 0,
+            seconds: Some(MAX_OPEN_SECONDS + 1),
+        })?;
+        assert_eq!(action, None);
+        assert_eq!(response.outcome, Outcome::InvalidDuration);
+
+        // the door has learned the time from the bridge
+        assert!(setup.clock.now::<chall::Uptime>() >= Some(1700000000));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unauthenticated() -> Result<()> {
+        let mut setup = Setup::new();
+        let chall = Challenge::generate::<chall::Random>(&setup.keys.door)?;
+        let request = Request {
+            code: [0u8; CHALL_SIZE],
+            command: Command::Identify,
+            time: 1700000000,
+        };
+        let buf = request.seal::<crypto::Random>(&setup.keys.bridge)?;
+        let ret = process::<chall::Random, chall::Uptime, _>(
+            &setup.keys.door,
+            &chall,
+            &buf,
+            &setup.outputs,
+            &mut setup.clock,
+            || unreachable!(),
+        );
+        assert!(ret.is_err());
+        assert_eq!(setup.clock.now::<chall::Uptime>(), None);
+        Ok(())
+    }
+}
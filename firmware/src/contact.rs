@@ -0,0 +1,75 @@
+//! An optional door contact (reed switch) to detect if the door is open.
+//!
+//! The format is `<gpio>[:high|low]`, the level is the one the pin reads while the door is open.
+//! With a reed switch to ground and the internal pull-up this is `high`, which is the default.
+//! `none` means there's no contact attached.
+use crate::errors::*;
+use crate::outputs;
+
+pub const DEFAULT_CONTACT: &str = "none";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    /// The gpio pin the contact is connected to
+    pub pin: u8,
+    /// If the pin reads high (or low) while the door is open
+    pub open_high: bool,
+}
+
+impl Contact {
+    /// Interpret the level of the pin
+    pub fn is_open(&self, high: bool) -> bool {
+        high == self.open_high
+    }
+}
+
+pub fn parse(s: &str) -> Result<Option<Contact>> {
+    let s = s.trim();
+    if s == "none" {
+        return Ok(None);
+    }
+
+    let mut parts = s.split(':');
+    let pin = parts
+        .next()
+        .and_then(|pin| pin.parse().ok())
+        .ok_or(Error::InvalidContact("invalid gpio pin"))?;
+    let pin = outputs::check_pin(pin).map_err(Error::InvalidContact)?;
+    let open_high = match parts.next() {
+        Some("high") | None => true,
+        Some("low") => false,
+        Some(_) => return Err(Error::InvalidContact("level needs to be `high` or `low`")),
+    };
+    if parts.next().is_some() {
+        return Err(Error::InvalidContact("too many fields"));
+    }
+    Ok(Some(Contact { pin, open_high }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_contact() -> Result<()> {
+        assert_eq!(parse(DEFAULT_CONTACT)?, None);
+        assert_eq!(
+            parse("5")?,
+            Some(Contact {
+                pin: 5,
+                open_high: true
+            })
+        );
+        let contact = parse("5:low")?.unwrap();
+        assert!(!contact.open_high);
+        assert!(contact.is_open(false));
+        assert!(!contact.is_open(true));
+
+        assert!(parse("").is_err());
+        assert!(parse("gpio5").is_err());
+        assert!(parse("5:up").is_err());
+        assert!(parse("5:low:1").is_err());
+        assert!(parse("9").is_err());
+        Ok(())
+    }
+}
@@ -8,5 +8,9 @@ pub enum Error {
     AuthError,
     #[error("failed to call esp api: {0}")]
     EspError(&'static str),
+    #[error("invalid output configuration: {0}")]
+    InvalidOutputs(&'static str),
+    #[error("invalid contact configuration: {0}")]
+    InvalidContact(&'static str),
 }
 pub type Result<T> = core::result::Result<T, Error>;
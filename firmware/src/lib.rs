@@ -1,2 +1,7 @@
 pub mod chall;
+pub mod command;
+pub mod contact;
 pub mod errors;
+pub mod outputs;
+pub mod settings;
+pub mod status;
@@ -2,42 +2,118 @@
 
 mod keys;
 
-use d3xs_firmware::chall;
+use d3xs_firmware::chall::{self, Sessions, WallClock};
+use d3xs_firmware::command;
+use d3xs_firmware::contact;
 use d3xs_firmware::errors::*;
-use d3xs_protocol::chall::Challenge;
-use d3xs_protocol::crypto;
+use d3xs_firmware::outputs;
+use d3xs_firmware::settings::Settings;
+use d3xs_firmware::status::Tracker;
+use d3xs_protocol::chall::{self as protocol_chall, WriteResult};
+use d3xs_protocol::command::Command;
+use d3xs_protocol::offline::OfflineDoor;
+use d3xs_protocol::{crypto, provision};
 use data_encoding::BASE64;
 use esp32_nimble::utilities::{mutex::Condvar, mutex::Mutex, BleUuid};
 use esp32_nimble::{BLEDevice, NimbleProperties};
-use esp_idf_svc::hal::gpio::PinDriver;
-use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, Level, PinDriver, Pull};
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
 use esp_idf_svc::sys;
 use smart_leds::hsv::RGB;
 use smart_leds::SmartLedsWrite;
 use std::fmt::Write;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
 
 const SERVICE_UUID: BleUuid = BleUuid::Uuid16(0xffff);
 const CHAR_UUID: BleUuid = BleUuid::Uuid16(0xaaaa);
+const OFFLINE_CHAR_UUID: BleUuid = BleUuid::Uuid16(0xaaab);
+const PROVISION_CHAR_UUID: BleUuid = BleUuid::Uuid16(0xaaac);
+const STATUS_CHAR_UUID: BleUuid = BleUuid::Uuid16(0xaaad);
+const COMMAND_CHAR_UUID: BleUuid = BleUuid::Uuid16(0xaaae);
+const NVS_NAMESPACE: &str = "d3xs";
 const BLE_NAME: Option<&str> = option_env!("D3XS_BLE_NAME");
-const BUZZ_SECONDS: usize = 8;
+const OUTPUTS: Option<&str> = option_env!("D3XS_OUTPUTS");
+const CONTACT: Option<&str> = option_env!("D3XS_CONTACT");
+// how long the main loop sleeps if nothing happens
+const IDLE_INTERVAL: Duration = Duration::from_secs(5);
+// how often the door contact is read, if there is one
+const CONTACT_INTERVAL: Duration = Duration::from_millis(500);
 
 const LED_RED: RGB<u8> = RGB::new(16, 0, 0);
 const LED_GREEN: RGB<u8> = RGB::new(0, 16, 0);
-// const LED_YELLOW: RGB<u8> = RGB::new(10, 10, 0);
+const LED_YELLOW: RGB<u8> = RGB::new(10, 10, 0);
 const LED_OFF: RGB<u8> = RGB::new(0, 0, 0);
 
 #[derive(PartialEq)]
 pub enum MainAction {
-    LedSuccess,
+    /// Switch on the output with this index, for the configured time if no seconds are given
+    LedSuccess(u8, Option<u32>),
+    /// Switch on the output with this index until it's locked
+    HoldOpen(u8),
+    /// Switch off the output with this index
+    Lock(u8),
+    /// Blink the led so the door controller can be found
+    Identify,
     LedFail,
+    /// New settings have been written, reboot to apply them
+    Restart,
 }
 
-#[inline(always)]
-fn ble_name() -> &'static str {
-    BLE_NAME.unwrap_or("esp32c3-d3xs")
+fn queue_action(main_action: &Mutex<Option<MainAction>>, notify: &Condvar, action: MainAction) {
+    let mut guard = main_action.lock();
+    // never replace a pending authenticated action, a failure only replaces another failure
+    if matches!(*guard, None | Some(MainAction::LedFail)) {
+        *guard = Some(action);
+    }
+    // notify subscribers about a value being available
+    notify.notify_all();
+}
+
+fn default_settings() -> Settings {
+    let settings = Settings {
+        door_key: keys::door_key(),
+        bridge_key: keys::bridge_key(),
+        ble_name: BLE_NAME.unwrap_or("esp32c3-d3xs").to_string(),
+        outputs: outputs::parse(OUTPUTS.unwrap_or(outputs::DEFAULT_OUTPUTS))
+            .expect("Invalid D3XS_OUTPUTS"),
+        contact: contact::parse(CONTACT.unwrap_or(contact::DEFAULT_CONTACT))
+            .expect("Invalid D3XS_CONTACT"),
+    };
+    settings
+        .check_pins()
+        .expect("D3XS_CONTACT uses the pin of an output");
+    settings
+}
+
+fn set_output(
+    switch: &mut PinDriver<'static, AnyOutputPin, esp_idf_svc::hal::gpio::Output>,
+    output: &outputs::Output,
+    on: bool,
+) {
+    let level = if on == output.active_high {
+        Level::High
+    } else {
+        Level::Low
+    };
+    switch.set_level(level).unwrap();
+}
+
+fn open_nvs() -> Result<EspDefaultNvs> {
+    let open = || -> Result<EspDefaultNvs> {
+        let partition =
+            EspDefaultNvsPartition::take().map_err(|_| Error::EspError("nvs_flash_init"))?;
+        EspDefaultNvs::new(partition, NVS_NAMESPACE, true).map_err(|_| Error::EspError("nvs_open"))
+    };
+    open().or_else(|err| {
+        // e.g. the partition is full or has been written by a newer esp-idf version
+        println!("[💾] failed to open nvs ({err}), erasing it");
+        if unsafe { sys::nvs_flash_erase() } != sys::ESP_OK {
+            return Err(Error::EspError("nvs_flash_erase"));
+        }
+        open()
+    })
 }
 
 fn detect_ble_mac() -> Result<String> {
@@ -67,8 +143,23 @@ fn main() -> ! {
     esp_idf_svc::log::EspLogger::initialize_default();
 
     println!("[✨] hello, world!");
-    let self_secret_key = keys::door_key();
-    let salsa = Arc::new(crypto::SalsaBox::new(&keys::bridge_key(), &self_secret_key));
+    let booted = Instant::now();
+    let uptime = move || booted.elapsed().as_secs() as u32;
+    // without storage the door still works with the compiled-in settings, it can't be provisioned
+    let nvs = open_nvs()
+        .inspect_err(|err| println!("[💾] nvs is not available, using defaults: {err}"))
+        .ok();
+    let settings = match &nvs {
+        Some(nvs) => Settings::load(nvs, default_settings()),
+        None => default_settings(),
+    };
+    let nvs = Arc::new(Mutex::new(nvs));
+
+    let self_secret_key = settings.door_key.clone();
+    let salsa = Arc::new(crypto::SalsaBox::new(
+        &settings.bridge_key,
+        &self_secret_key,
+    ));
 
     let self_public_key = self_secret_key.public_key();
     println!(
@@ -79,14 +170,55 @@ fn main() -> ! {
         println!("[🔑] ble mac: {}", mac);
     }
 
-    let peripherals = Peripherals::take().unwrap();
-    let mut switch = PinDriver::output(peripherals.pins.gpio4).unwrap();
-    switch.set_low().unwrap();
+    let current_settings = settings.clone();
+    let outputs = settings.outputs;
+    let mut switches = outputs
+        .iter()
+        .map(|output| {
+            // SAFETY: the pins are reserved for outputs by the settings and only driven from here
+            let pin = unsafe { AnyOutputPin::new(i32::from(output.pin)) };
+            let mut switch = PinDriver::output(pin).unwrap();
+            set_output(&mut switch, output, false);
+            switch
+        })
+        .collect::<Vec<_>>();
+    for (i, output) in outputs.iter().enumerate() {
+        println!(
+            "[🔌] output {i}: gpio{} for {}s (active {})",
+            output.pin,
+            output.seconds,
+            if output.active_high { "high" } else { "low" }
+        );
+    }
+    let num_outputs = outputs.len();
+
+    let contact = settings.contact.map(|contact| {
+        // SAFETY: the pin is reserved for the contact by the settings and only read from here
+        let pin = unsafe { AnyIOPin::new(i32::from(contact.pin)) };
+        let mut input = PinDriver::input(pin).unwrap();
+        let pull = if contact.open_high {
+            Pull::Up
+        } else {
+            Pull::Down
+        };
+        input.set_pull(pull).unwrap();
+        println!(
+            "[🚪] door contact: gpio{} (open {})",
+            contact.pin,
+            if contact.open_high { "high" } else { "low" }
+        );
+        (contact, input)
+    });
 
     let mut ws2812 = Ws2812Esp32Rmt::new(0, 8).unwrap();
     ws2812.write([LED_OFF].into_iter()).unwrap();
 
-    let latest_nonce: Arc<Mutex<Option<Challenge>>> = Arc::new(Mutex::new(None));
+    let sessions: Arc<Mutex<Sessions>> = Arc::new(Mutex::new(Sessions::default()));
+    let offline_sessions: Arc<Mutex<Sessions>> = Arc::new(Mutex::new(Sessions::default()));
+    let provision_sessions: Arc<Mutex<Sessions>> = Arc::new(Mutex::new(Sessions::default()));
+    // the encrypted result of the last command, by connection
+    let command_results: Arc<Mutex<Vec<(u16, Vec<u8>)>>> = Arc::new(Mutex::new(Vec::new()));
+    let clock: Arc<Mutex<WallClock>> = Arc::new(Mutex::new(WallClock::default()));
     let main_action: Arc<Mutex<Option<MainAction>>> = Arc::new(Mutex::new(None));
     let notify: Arc<Condvar> = Arc::new(Condvar::new());
     let notify_mutex = Mutex::new(());
@@ -103,8 +235,22 @@ fn main() -> ! {
         // Multi-connect support: start advertising
         ble_device.get_advertising().start().unwrap();
     });
-    server.on_disconnect(|_desc, reason| {
+    let sessions_disconnect = sessions.clone();
+    let offline_sessions_disconnect = offline_sessions.clone();
+    let provision_sessions_disconnect = provision_sessions.clone();
+    let command_results_disconnect = command_results.clone();
+    server.on_disconnect(move |desc, reason| {
         println!("[✌️] client disconnected ({:X})", reason);
+        sessions_disconnect.lock().disconnect(desc.conn_handle);
+        offline_sessions_disconnect
+            .lock()
+            .disconnect(desc.conn_handle);
+        provision_sessions_disconnect
+            .lock()
+            .disconnect(desc.conn_handle);
+        command_results_disconnect
+            .lock()
+            .retain(|(conn, _)| *conn != desc.conn_handle);
     });
     let service = server.create_service(SERVICE_UUID);
 
@@ -113,17 +259,71 @@ fn main() -> ! {
         .lock()
         .create_characteristic(CHAR_UUID, NimbleProperties::READ | NimbleProperties::WRITE);
 
-    let latest_nonce_read = latest_nonce.clone();
-    let latest_nonce_write = latest_nonce.clone();
+    let sessions_read = sessions.clone();
+    let sessions_write = sessions.clone();
+    let salsa_read = salsa.clone();
     let main_action_write = main_action.clone();
     let notify_write = notify.clone();
+    let salsa_write = salsa.clone();
 
     characteristic
         .lock()
-        .on_read(move |attr, _| {
+        .on_read(move |attr, desc| {
             println!("[🎲] sending nonce");
 
-            if let Some(chall) = &*latest_nonce_read.lock() {
+            // every read issues a new challenge for this connection
+            let mut sessions = sessions_read.lock();
+            match sessions.issue::<chall::Random, chall::Uptime>(desc.conn_handle, &salsa_read) {
+                Ok(chall) => attr.set_value(&chall.encrypted),
+                Err(_) => attr.set_value(&[]),
+            }
+        })
+        .on_write(move |args| {
+            let buf = args.recv_data;
+            println!("[🔍] wrote to writable characteristic: {buf:?}");
+
+            let solved = sessions_write.lock().solve::<chall::Uptime>(
+                args.desc.conn_handle,
+                &salsa_write,
+                buf,
+                num_outputs,
+            );
+
+            let (action, ret) = match solved {
+                Ok(output) => {
+                    println!("[✅] success (output={output})");
+                    (MainAction::LedSuccess(output, None), WriteResult::Ok)
+                }
+                Err(err) => {
+                    println!("[❌] solution rejected: {err}");
+                    (MainAction::LedFail, chall::write_result(&err))
+                }
+            };
+            queue_action(&main_action_write, &notify_write, action);
+
+            // complete ble write operation, the bridge reads the result from the response
+            args.reject_with_error_code(ret.code());
+        });
+
+    // Opening without the bridge, using a token the bridge has issued to the user
+    let offline = OfflineDoor::new(&settings.bridge_key, self_secret_key.clone());
+    let offline_characteristic = service.lock().create_characteristic(
+        OFFLINE_CHAR_UUID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+
+    let offline_read = offline_sessions.clone();
+    let offline_write = offline_sessions.clone();
+    let clock_offline = clock.clone();
+    let main_action_offline = main_action.clone();
+    let notify_offline = notify.clone();
+
+    offline_characteristic
+        .lock()
+        .on_read(move |attr, desc| {
+            println!("[🎲] sending offline challenge");
+
+            if let Some(chall) = offline_read.lock().get(desc.conn_handle) {
                 attr.set_value(&chall.encrypted);
             } else {
                 attr.set_value(&[]);
@@ -131,62 +331,289 @@ fn main() -> ! {
         })
         .on_write(move |args| {
             let buf = args.recv_data;
-            println!("[🔍] wrote to writable characteristic: {buf:?}");
-
-            let solved = if let Some(chall) = &*latest_nonce_write.lock() {
-                chall.verify(buf).is_ok()
+            let conn = args.desc.conn_handle;
+            println!("[🔍] wrote to offline characteristic: {buf:?}");
+
+            // the client either writes a token, or the solution to the challenge it has been sent
+            let mut sessions = offline_write.lock();
+            let written = if buf.len() == protocol_chall::CHALL_SIZE {
+                sessions
+                    .take::<chall::Uptime>(conn)
+                    .and_then(|pending| Ok(pending.verify(buf)?))
+                    .map(|_| true)
             } else {
-                false
+                // a new token replaces the previous challenge, even if it's refused
+                sessions.disconnect(conn);
+                // tokens are refused until the bridge has told the door the time
+                let now = clock_offline.lock().now::<chall::Uptime>();
+                offline
+                    .issue::<chall::Random>(buf, now)
+                    .map(|pending| {
+                        sessions.insert::<chall::Uptime>(conn, pending);
+                        false
+                    })
+                    .map_err(Error::from)
+            };
+            drop(sessions);
+
+            let ret = match written {
+                Ok(true) => {
+                    println!("[✅] offline success");
+                    queue_action(
+                        &main_action_offline,
+                        &notify_offline,
+                        MainAction::LedSuccess(0, None),
+                    );
+                    WriteResult::Ok
+                }
+                Ok(false) => {
+                    println!("[🎫] received offline token");
+                    WriteResult::Ok
+                }
+                Err(err) => {
+                    println!("[❌] offline open rejected: {err}");
+                    queue_action(&main_action_offline, &notify_offline, MainAction::LedFail);
+                    chall::write_result(&err)
+                }
             };
 
-            let (action, ret) = if solved {
-                println!("[✅] success");
-                (MainAction::LedSuccess, 0)
-            } else {
-                (MainAction::LedFail, 1)
+            // complete ble write operation
+            args.reject_with_error_code(ret.code());
+        });
+
+    // Updating keys and settings, authenticated by the current bridge key
+    let provision_characteristic = service.lock().create_characteristic(
+        PROVISION_CHAR_UUID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+
+    let provision_read = provision_sessions.clone();
+    let provision_write = provision_sessions.clone();
+    let salsa_provision_read = salsa.clone();
+    let salsa_provision_write = salsa.clone();
+    let main_action_provision = main_action.clone();
+    let notify_provision = notify.clone();
+
+    provision_characteristic
+        .lock()
+        .on_read(move |attr, desc| {
+            println!("[🎲] sending provisioning challenge");
+
+            // every read issues a new challenge for this connection
+            let mut sessions = provision_read.lock();
+            match sessions
+                .issue::<chall::Random, chall::Uptime>(desc.conn_handle, &salsa_provision_read)
+            {
+                Ok(chall) => attr.set_value(&chall.encrypted),
+                Err(_) => attr.set_value(&[]),
+            }
+        })
+        .on_write(move |args| {
+            let buf = args.recv_data;
+            println!("[🔍] wrote to provisioning characteristic");
+
+            let update = || -> Result<()> {
+                // the challenge can only be used once
+                let chall = provision_write
+                    .lock()
+                    .take::<chall::Uptime>(args.desc.conn_handle)?;
+                let update = provision::Settings::open(&salsa_provision_write, &chall, buf)?;
+                let mut nvs = nvs.lock();
+                let nvs = nvs
+                    .as_mut()
+                    .ok_or(Error::EspError("nvs is not available"))?;
+                Settings::store(nvs, &current_settings, &update)
             };
 
-            let mut guard = main_action_write.lock();
-            // never replace a pending success operation
-            if *guard != Some(MainAction::LedSuccess) {
-                *guard = Some(action);
+            let (action, ret) = match update() {
+                Ok(()) => {
+                    println!("[💾] settings have been updated");
+                    (MainAction::Restart, WriteResult::Ok)
+                }
+                Err(err) => {
+                    println!("[❌] failed to update settings: {err}");
+                    (MainAction::LedFail, chall::write_result(&err))
+                }
+            };
+            queue_action(&main_action_provision, &notify_provision, action);
+
+            // complete ble write operation
+            args.reject_with_error_code(ret.code());
+        });
+
+    // Status for health checks of the bridge, this is not authenticated
+    let tracker = Arc::new(Mutex::new(Tracker::default()));
+    let status_characteristic = service.lock().create_characteristic(
+        STATUS_CHAR_UUID,
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+
+    let tracker_read = tracker.clone();
+    status_characteristic.lock().on_read(move |attr, _| {
+        println!("[🩺] sending status");
+        let status = tracker_read.lock().status(uptime());
+        attr.set_value(&status.encode().unwrap_or_default());
+    });
+
+    // Authenticated commands, the encrypted result can be read afterwards
+    let command_characteristic = service.lock().create_characteristic(
+        COMMAND_CHAR_UUID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+
+    let command_results_read = command_results.clone();
+    let sessions_command = sessions.clone();
+    let salsa_command = salsa.clone();
+    let tracker_command = tracker.clone();
+    let main_action_command = main_action.clone();
+    let notify_command = notify.clone();
+    let outputs_command = outputs.clone();
+    let clock_command = clock.clone();
+
+    command_characteristic
+        .lock()
+        .on_read(move |attr, desc| {
+            println!("[📨] sending command result");
+            let results = command_results_read.lock();
+            let result = results
+                .iter()
+                .find(|(conn, _)| *conn == desc.conn_handle)
+                .map(|(_, result)| &result[..])
+                .unwrap_or_default();
+            attr.set_value(result);
+        })
+        .on_write(move |args| {
+            let buf = args.recv_data;
+            let conn = args.desc.conn_handle;
+            println!("[🔍] wrote to command characteristic");
+
+            // the challenge of this connection can only be used once
+            let pending = sessions_command.lock().take::<chall::Uptime>(conn);
+            let processed = pending.and_then(|pending| {
+                command::process::<chall::Random, chall::Uptime, _>(
+                    &salsa_command,
+                    &pending,
+                    buf,
+                    &outputs_command,
+                    &mut clock_command.lock(),
+                    || tracker_command.lock().status(uptime()),
+                )
+            });
+
+            let (action, ret) = match processed {
+                Ok((command, result)) => {
+                    println!("[✅] command accepted: {command:?}");
+                    let mut results = command_results.lock();
+                    results.retain(|(c, _)| *c != conn);
+                    results.push((conn, result));
+                    let action = match command {
+                        Some(Command::Open { output, seconds }) => {
+                            Some(MainAction::LedSuccess(output, seconds.map(u32::from)))
+                        }
+                        Some(Command::HoldOpen { output }) => Some(MainAction::HoldOpen(output)),
+                        Some(Command::Lock { output }) => Some(MainAction::Lock(output)),
+                        Some(Command::Identify) => Some(MainAction::Identify),
+                        Some(Command::Status) | None => None,
+                    };
+                    (action, WriteResult::Ok)
+                }
+                Err(err) => {
+                    println!("[❌] command rejected: {err}");
+                    command_results.lock().retain(|(c, _)| *c != conn);
+                    (Some(MainAction::LedFail), chall::write_result(&err))
+                }
+            };
+            if let Some(action) = action {
+                queue_action(&main_action_command, &notify_command, action);
             }
-            // notify subscribers about a value being available
-            notify_write.notify_all();
 
             // complete ble write operation
-            args.reject_with_error_code(ret);
+            args.reject_with_error_code(ret.code());
         });
 
     let ble_advertising = ble_device.get_advertising();
-    ble_advertising.name(ble_name());
+    ble_advertising.name(&settings.ble_name);
 
     println!("[📻] starting ble server");
     ble_advertising.start().unwrap();
 
+    let send_status = |tracker: &Tracker| {
+        let status = tracker.status(uptime());
+        status_characteristic
+            .lock()
+            .set_value(&status.encode().unwrap_or_default())
+            .notify();
+    };
+
+    let mut held = vec![false; outputs.len()];
     loop {
-        if let Ok(chall) = Challenge::generate::<chall::Random>(&salsa) {
-            *latest_nonce.lock() = Some(chall);
+        if let Some((contact, input)) = &contact {
+            let open = contact.is_open(input.is_high());
+            let mut tracker = tracker.lock();
+            if tracker.update_contact(open, uptime()) {
+                println!("[🚪] door is {}", if open { "open" } else { "closed" });
+                send_status(&tracker);
+            }
         }
 
         // lock mutex, read action and immediately release mutex
         let action = { main_action.lock().take() };
 
         if let Some(action) = action {
+            match &action {
+                MainAction::LedSuccess(..) | MainAction::HoldOpen(_) => {
+                    tracker.lock().record_open(uptime())
+                }
+                MainAction::LedFail => tracker.lock().record_failure(),
+                MainAction::Lock(_) | MainAction::Identify | MainAction::Restart => (),
+            }
+            send_status(&tracker.lock());
+
             match action {
-                MainAction::LedSuccess => {
-                    switch.set_high().unwrap();
-                    for _ in 0..BUZZ_SECONDS {
+                MainAction::LedSuccess(idx, seconds) => {
+                    let idx = usize::from(idx);
+                    let output = &outputs[idx];
+                    let switch = &mut switches[idx];
+
+                    set_output(switch, output, true);
+                    // each blink takes half a second
+                    for _ in 0..seconds.unwrap_or(output.seconds) * 2 {
                         ws2812.write([LED_GREEN].into_iter()).unwrap();
                         esp_idf_hal::delay::FreeRtos::delay_ms(250);
                         ws2812.write([LED_OFF].into_iter()).unwrap();
                         esp_idf_hal::delay::FreeRtos::delay_ms(250);
                     }
-                    switch.set_low().unwrap();
+                    // an output that is held open stays on until it's locked
+                    if !held[idx] {
+                        set_output(switch, output, false);
+                    }
 
                     // remove any action queued while the door was open
                     *main_action.lock() = None;
                 }
+                MainAction::HoldOpen(idx) => {
+                    let idx = usize::from(idx);
+                    println!("[🔓] holding output {idx} open");
+                    held[idx] = true;
+                    set_output(&mut switches[idx], &outputs[idx], true);
+                    ws2812.write([LED_GREEN].into_iter()).unwrap();
+                    esp_idf_hal::delay::FreeRtos::delay_ms(1000);
+                    ws2812.write([LED_OFF].into_iter()).unwrap();
+                }
+                MainAction::Lock(idx) => {
+                    let idx = usize::from(idx);
+                    println!("[🔒] locking output {idx}");
+                    held[idx] = false;
+                    set_output(&mut switches[idx], &outputs[idx], false);
+                }
+                MainAction::Identify => {
+                    for _ in 0..10 {
+                        ws2812.write([LED_YELLOW].into_iter()).unwrap();
+                        esp_idf_hal::delay::FreeRtos::delay_ms(250);
+                        ws2812.write([LED_OFF].into_iter()).unwrap();
+                        esp_idf_hal::delay::FreeRtos::delay_ms(250);
+                    }
+                }
                 MainAction::LedFail => {
                     for _ in 0..2 {
                         ws2812.write([LED_RED].into_iter()).unwrap();
@@ -195,9 +622,20 @@ fn main() -> ! {
                         esp_idf_hal::delay::FreeRtos::delay_ms(250);
                     }
                 }
+                MainAction::Restart => {
+                    println!("[🔄] restarting to apply new settings");
+                    // give the ble stack some time to send the write response
+                    esp_idf_hal::delay::FreeRtos::delay_ms(500);
+                    esp_idf_svc::hal::reset::restart();
+                }
             }
         } else {
-            notify.wait_timeout(notify_mutex.lock(), Duration::from_secs(5));
+            let timeout = if contact.is_some() {
+                CONTACT_INTERVAL
+            } else {
+                IDLE_INTERVAL
+            };
+            notify.wait_timeout(notify_mutex.lock(), timeout);
         }
     }
 }
@@ -0,0 +1,123 @@
+//! The outputs (relays) a door controller can switch, configured at build time.
+//!
+//! The format is a comma separated list of `<gpio>[:<seconds>[:high|low]]`, e.g. `4:8:high,5:3:low`.
+//! The first entry is output 0, which is also the one used for offline opens.
+use crate::errors::*;
+
+pub const DEFAULT_OUTPUTS: &str = "4:4:high";
+const DEFAULT_SECONDS: u32 = 4;
+// the highest gpio pin of the esp32-c3
+const MAX_PIN: u8 = 21;
+// strapping pins (2, 8, 9), the led (8), the spi flash (12-17) and usb (18, 19)
+const RESERVED_PINS: &[u8] = &[2, 8, 9, 12, 13, 14, 15, 16, 17, 18, 19];
+
+/// Check if a gpio pin can be used for an output or the door contact
+pub fn check_pin(pin: u8) -> core::result::Result<u8, &'static str> {
+    if pin > MAX_PIN {
+        Err("gpio pin does not exist")
+    } else if RESERVED_PINS.contains(&pin) {
+        Err("gpio pin is reserved")
+    } else {
+        Ok(pin)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Output {
+    /// The gpio pin this output is connected to
+    pub pin: u8,
+    /// How many seconds the output stays switched on
+    pub seconds: u32,
+    /// If the output is switched on by pulling the pin high (or low)
+    pub active_high: bool,
+}
+
+impl Output {
+    fn parse(s: &str) -> Result<Self> {
+        let mut parts = s.trim().split(':');
+        let pin = parts
+            .next()
+            .and_then(|pin| pin.parse().ok())
+            .ok_or(Error::InvalidOutputs("invalid gpio pin"))?;
+        let pin = check_pin(pin).map_err(Error::InvalidOutputs)?;
+        let seconds = match parts.next() {
+            Some(seconds) => seconds
+                .parse()
+                .map_err(|_| Error::InvalidOutputs("invalid number of seconds"))?,
+            None => DEFAULT_SECONDS,
+        };
+        let active_high = match parts.next() {
+            Some("high") | None => true,
+            Some("low") => false,
+            Some(_) => return Err(Error::InvalidOutputs("level needs to be `high` or `low`")),
+        };
+        if parts.next().is_some() {
+            return Err(Error::InvalidOutputs("too many fields"));
+        }
+        Ok(Output {
+            pin,
+            seconds,
+            active_high,
+        })
+    }
+}
+
+pub fn parse(s: &str) -> Result<Vec<Output>> {
+    let outputs = s
+        .split(',')
+        .map(Output::parse)
+        .collect::<Result<Vec<_>>>()?;
+    if outputs.len() > usize::from(u8::MAX) + 1 {
+        return Err(Error::InvalidOutputs("too many outputs"));
+    }
+    for (i, output) in outputs.iter().enumerate() {
+        if outputs[..i].iter().any(|o| o.pin == output.pin) {
+            return Err(Error::InvalidOutputs("gpio pin is used twice"));
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_outputs() -> Result<()> {
+        assert_eq!(
+            parse("4:8:high, 5:3:low,6")?,
+            vec![
+                Output {
+                    pin: 4,
+                    seconds: 8,
+                    active_high: true,
+                },
+                Output {
+                    pin: 5,
+                    seconds: 3,
+                    active_high: false,
+                },
+                Output {
+                    pin: 6,
+                    seconds: DEFAULT_SECONDS,
+                    active_high: true,
+                },
+            ]
+        );
+        assert_eq!(parse(DEFAULT_OUTPUTS)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_outputs() {
+        assert!(parse("").is_err());
+        assert!(parse("gpio4").is_err());
+        assert!(parse("4:eight").is_err());
+        assert!(parse("4:8:up").is_err());
+        assert!(parse("4:8:high:1").is_err());
+        assert!(parse("4,5,4").is_err());
+        assert!(parse("8").is_err());
+        assert!(parse("4,12:4:low").is_err());
+        assert!(parse("22").is_err());
+    }
+}
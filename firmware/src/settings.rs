@@ -0,0 +1,309 @@
+//! Keys and settings of the door, loaded from flash with the compiled-in values as fallback.
+use crate::contact::{self, Contact};
+use crate::errors::*;
+use crate::outputs::{self, Output};
+use d3xs_protocol::{crypto, provision};
+
+const KEY_SETTINGS: &str = "settings";
+const SETTINGS_VERSION: u8 = 1;
+// every field can be up to 255 bytes
+const MAX_SETTINGS_SIZE: usize = 1 + 5 * (2 + 255);
+
+/// Persistent key/value storage, this is nvs on the microcontroller
+pub trait Storage {
+    fn get<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>>;
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()>;
+}
+
+#[derive(Clone)]
+pub struct Settings {
+    pub door_key: crypto::SecretKey,
+    pub bridge_key: crypto::PublicKey,
+    pub ble_name: String,
+    pub outputs: Vec<Output>,
+    pub contact: Option<Contact>,
+}
+
+fn check_pins(outputs: &[Output], contact: Option<&Contact>) -> Result<()> {
+    if let Some(contact) = contact {
+        if outputs.iter().any(|output| output.pin == contact.pin) {
+            return Err(Error::InvalidContact("gpio pin is used by an output"));
+        }
+    }
+    Ok(())
+}
+
+// the settings written by the bridge, each of them replaces a compiled-in value
+fn load_stored<S: Storage>(storage: &S) -> Option<provision::Settings> {
+    let mut buf = [0u8; MAX_SETTINGS_SIZE];
+    let Ok(Some(buf)) = storage.get(KEY_SETTINGS, &mut buf) else {
+        return None;
+    };
+    let (&version, fields) = buf.split_first()?;
+    if version != SETTINGS_VERSION {
+        return None;
+    }
+    provision::Settings::decode_fields(fields).ok()
+}
+
+impl Settings {
+    /// Load the settings from storage, anything that's missing or invalid is taken from `defaults`
+    ///
+    /// If the stored outputs and contact conflict with each other, both are taken from `defaults`.
+    pub fn load<S: Storage>(storage: &S, defaults: Settings) -> Self {
+        let Some(stored) = load_stored(storage) else {
+            return defaults;
+        };
+        let (default_outputs, default_contact) = (defaults.outputs.clone(), defaults.contact);
+        let settings = defaults.apply(&stored);
+        match settings.check_pins() {
+            Ok(()) => settings,
+            Err(_) => Settings {
+                outputs: default_outputs,
+                contact: default_contact,
+                ..settings
+            },
+        }
+    }
+
+    /// Make sure the contact doesn't use the pin of an output
+    pub fn check_pins(&self) -> Result<()> {
+        check_pins(&self.outputs, self.contact.as_ref())
+    }
+
+    // replace the settings that are set in `update` and valid
+    fn apply(self, update: &provision::Settings) -> Self {
+        Settings {
+            door_key: update.door_key.clone().unwrap_or(self.door_key),
+            bridge_key: update.bridge_key.clone().unwrap_or(self.bridge_key),
+            ble_name: update.ble_name.clone().unwrap_or(self.ble_name),
+            outputs: update
+                .outputs
+                .as_deref()
+                .and_then(|s| outputs::parse(s).ok())
+                .unwrap_or(self.outputs),
+            contact: update
+                .contact
+                .as_deref()
+                .and_then(|s| contact::parse(s).ok())
+                .unwrap_or(self.contact),
+        }
+    }
+
+    /// Write settings received from the bridge, `current` are the settings the door runs with
+    ///
+    /// The update is validated before anything is written. All settings are stored in a single
+    /// blob, so an interrupted write can't leave a mix of old and new settings behind.
+    pub fn store<S: Storage>(
+        storage: &mut S,
+        current: &Settings,
+        update: &provision::Settings,
+    ) -> Result<()> {
+        let outputs = match &update.outputs {
+            Some(outputs) => outputs::parse(outputs)?,
+            None => current.outputs.clone(),
+        };
+        let contact = match &update.contact {
+            Some(contact) => contact::parse(contact)?,
+            None => current.contact,
+        };
+        check_pins(&outputs, contact.as_ref())?;
+
+        let mut stored = load_stored(storage).unwrap_or_default();
+        stored.merge(update);
+        let mut buf = vec![SETTINGS_VERSION];
+        stored.encode_fields(&mut buf)?;
+        storage.set(KEY_SETTINGS, &buf)
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl Storage for esp_idf_svc::nvs::EspDefaultNvs {
+    fn get<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>> {
+        self.get_raw(key, buf)
+            .map_err(|_| Error::EspError("nvs_get_blob"))
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.set_raw(key, value)
+            .map_err(|_| Error::EspError("nvs_set_blob"))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct MemoryStorage(HashMap<String, Vec<u8>>);
+
+    impl Storage for MemoryStorage {
+        fn get<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>> {
+            let Some(value) = self.0.get(key) else {
+                return Ok(None);
+            };
+            let buf = buf
+                .get_mut(..value.len())
+                .ok_or(Error::EspError("buffer too small"))?;
+            buf.copy_from_slice(value);
+            Ok(Some(buf))
+        }
+
+        fn set(&mut self, key: &str, value: &[u8]) -> Result<()> {
+            self.0.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    fn defaults() -> Settings {
+        Settings {
+            door_key: crypto::SecretKey::from([1u8; 32]),
+            bridge_key: crypto::PublicKey::from([2u8; 32]),
+            ble_name: "esp32c3-d3xs".to_string(),
+            outputs: outputs::parse(outputs::DEFAULT_OUTPUTS).unwrap(),
+            contact: None,
+        }
+    }
+
+    #[test]
+    fn fallback_to_defaults() {
+        let storage = MemoryStorage::default();
+        let settings = Settings::load(&storage, defaults());
+        assert_eq!(settings.door_key.to_bytes(), [1u8; 32]);
+        assert_eq!(settings.bridge_key, crypto::PublicKey::from([2u8; 32]));
+        assert_eq!(settings.ble_name, "esp32c3-d3xs");
+        assert_eq!(settings.outputs.len(), 1);
+        assert_eq!(settings.contact, None);
+    }
+
+    #[test]
+    fn store_and_load() -> Result<()> {
+        let mut storage = MemoryStorage::default();
+        Settings::store(
+            &mut storage,
+            &defaults(),
+            &provision::Settings {
+                bridge_key: Some(crypto::PublicKey::from([3u8; 32])),
+                ble_name: Some("d3xs1".to_string()),
+                outputs: Some("4,5:10:low".to_string()),
+                contact: Some("6:low".to_string()),
+                ..Default::default()
+            },
+        )?;
+        // everything is written at once
+        assert_eq!(storage.0.len(), 1);
+
+        let settings = Settings::load(&storage, defaults());
+        assert_eq!(settings.door_key.to_bytes(), [1u8; 32]);
+        assert_eq!(settings.bridge_key, crypto::PublicKey::from([3u8; 32]));
+        assert_eq!(settings.ble_name, "d3xs1");
+        assert_eq!(settings.outputs.len(), 2);
+        assert_eq!(
+            settings.contact,
+            Some(Contact {
+                pin: 6,
+                open_high: false
+            })
+        );
+
+        // the contact can be removed, even if there's one compiled in
+        Settings::store(
+            &mut storage,
+            &settings,
+            &provision::Settings {
+                contact: Some("none".to_string()),
+                ..Default::default()
+            },
+        )?;
+        let defaults = Settings {
+            contact: contact::parse("5")?,
+            ..defaults()
+        };
+        let settings = Settings::load(&storage, defaults);
+        assert_eq!(settings.contact, None);
+        // previous updates are kept
+        assert_eq!(settings.ble_name, "d3xs1");
+        assert_eq!(settings.outputs.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_settings() {
+        let mut storage = MemoryStorage::default();
+        let update = provision::Settings {
+            ble_name: Some("d3xs1".to_string()),
+            outputs: Some("gpio4".to_string()),
+            ..Default::default()
+        };
+        assert!(Settings::store(&mut storage, &defaults(), &update).is_err());
+
+        // the led can't be used as output
+        let update = provision::Settings {
+            outputs: Some("8".to_string()),
+            ..Default::default()
+        };
+        assert!(Settings::store(&mut storage, &defaults(), &update).is_err());
+
+        // the contact can't use the pin of an output, including the current ones
+        let update = provision::Settings {
+            contact: Some("4".to_string()),
+            ..Default::default()
+        };
+        assert!(Settings::store(&mut storage, &defaults(), &update).is_err());
+        let update = provision::Settings {
+            outputs: Some("5,6".to_string()),
+            contact: Some("6".to_string()),
+            ..Default::default()
+        };
+        assert!(Settings::store(&mut storage, &defaults(), &update).is_err());
+
+        // nothing has been written
+        assert!(storage.0.is_empty());
+    }
+
+    #[test]
+    fn ignore_invalid_values() {
+        let mut storage = MemoryStorage::default();
+        let mut buf = vec![SETTINGS_VERSION];
+        provision::Settings {
+            ble_name: Some("d3xs1".to_string()),
+            outputs: Some("4:eight".to_string()),
+            ..Default::default()
+        }
+        .encode_fields(&mut buf)
+        .unwrap();
+        storage.set(KEY_SETTINGS, &buf).unwrap();
+        let settings = Settings::load(&storage, defaults());
+        assert_eq!(settings.ble_name, "d3xs1");
+        assert_eq!(settings.outputs.len(), 1);
+
+        // outputs that conflict with the compiled-in contact are not used
+        let mut buf = vec![SETTINGS_VERSION];
+        provision::Settings {
+            ble_name: Some("d3xs1".to_string()),
+            outputs: Some("5".to_string()),
+            ..Default::default()
+        }
+        .encode_fields(&mut buf)
+        .unwrap();
+        storage.set(KEY_SETTINGS, &buf).unwrap();
+        let with_contact = Settings {
+            contact: contact::parse("5").unwrap(),
+            ..defaults()
+        };
+        let settings = Settings::load(&storage, with_contact);
+        assert_eq!(settings.ble_name, "d3xs1");
+        assert_eq!(settings.outputs[0].pin, 4);
+        assert_eq!(settings.contact.map(|contact| contact.pin), Some(5));
+        assert!(settings.check_pins().is_ok());
+
+        // settings of an unknown version are not used
+        buf[0] = SETTINGS_VERSION + 1;
+        storage.set(KEY_SETTINGS, &buf).unwrap();
+        let settings = Settings::load(&storage, defaults());
+        assert_eq!(settings.ble_name, "esp32c3-d3xs");
+    }
+}
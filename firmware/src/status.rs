@@ -0,0 +1,121 @@
+//! Keep track of what the door reports on its status characteristic.
+use d3xs_protocol::status::{Contact, Status};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Opening the door this many seconds after it has been unlocked is not a forced entry
+pub const UNLOCK_GRACE_SECS: u32 = 60;
+
+#[derive(Debug, Default)]
+pub struct Tracker {
+    // seconds since boot
+    last_open: Option<u32>,
+    failed_attempts: u32,
+    contact: Option<ContactState>,
+}
+
+#[derive(Debug)]
+struct ContactState {
+    // seconds since boot
+    opened: Option<u32>,
+    forced_entries: u32,
+}
+
+impl Tracker {
+    pub fn record_open(&mut self, uptime: u32) {
+        self.last_open = Some(uptime);
+    }
+
+    pub fn record_failure(&mut self) {
+        self.failed_attempts = self.failed_attempts.saturating_add(1);
+    }
+
+    /// Update the state of the door contact, returns true if it has changed
+    pub fn update_contact(&mut self, open: bool, uptime: u32) -> bool {
+        let Some(contact) = &mut self.contact else {
+            // the first reading after boot, we don't know how the door has been opened
+            self.contact = Some(ContactState {
+                opened: open.then_some(uptime),
+                forced_entries: 0,
+            });
+            return true;
+        };
+
+        match (contact.opened, open) {
+            (None, true) => {
+                let unlocked = self
+                    .last_open
+                    .is_some_and(|secs| uptime.saturating_sub(secs) <= UNLOCK_GRACE_SECS);
+                if !unlocked {
+                    contact.forced_entries = contact.forced_entries.saturating_add(1);
+                }
+                contact.opened = Some(uptime);
+                true
+            }
+            (Some(_), false) => {
+                contact.opened = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn status(&self, uptime: u32) -> Status {
+        Status {
+            version: VERSION.to_string(),
+            uptime,
+            last_open: self.last_open.map(|secs| uptime.saturating_sub(secs)),
+            failed_attempts: self.failed_attempts,
+            contact: self.contact.as_ref().map(|contact| Contact {
+                open_since: contact.opened.map(|secs| uptime.saturating_sub(secs)),
+                forced_entries: contact.forced_entries,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn track_status() {
+        let mut tracker = Tracker::default();
+        let status = tracker.status(10);
+        assert_eq!(status.last_open, None);
+        assert_eq!(status.failed_attempts, 0);
+        assert_eq!(status.contact, None);
+
+        tracker.record_failure();
+        tracker.record_open(20);
+        let status = tracker.status(50);
+        assert_eq!(status.uptime, 50);
+        assert_eq!(status.last_open, Some(30));
+        assert_eq!(status.failed_attempts, 1);
+    }
+
+    #[test]
+    fn detect_forced_entry() {
+        let mut tracker = Tracker::default();
+        // the door is already open during boot
+        assert!(tracker.update_contact(true, 1));
+        assert!(!tracker.update_contact(true, 2));
+        let contact = tracker.status(11).contact.unwrap();
+        assert_eq!(contact.open_since, Some(10));
+        assert_eq!(contact.forced_entries, 0);
+
+        // opened after unlocking
+        assert!(tracker.update_contact(false, 20));
+        tracker.record_open(30);
+        assert!(tracker.update_contact(true, 35));
+        assert!(tracker.update_contact(false, 40));
+        assert_eq!(tracker.status(40).contact.unwrap().forced_entries, 0);
+
+        // opened again after the grace period
+        assert!(tracker.update_contact(true, 30 + UNLOCK_GRACE_SECS + 1));
+        let contact = tracker.status(100).contact.unwrap();
+        assert_eq!(contact.forced_entries, 1);
+        assert_eq!(contact.open_since, Some(100 - 30 - UNLOCK_GRACE_SECS - 1));
+        assert!(tracker.update_contact(false, 100));
+        assert_eq!(tracker.status(100).contact.unwrap().open_since, None);
+    }
+}
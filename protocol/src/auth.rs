@@ -0,0 +1,112 @@
+//! Authentication of the bridge to the webserver.
+//!
+//! The webserver encrypts a challenge for the configured bridge key using a
+//! throw-away keypair, only the bridge is able to decrypt and send it back.
+//! The challenge is authenticated with a fixed label, so the webserver can't
+//! pass off a challenge of a user or door to have the bridge decrypt it.
+use crate::chall::{self, Challenge};
+use crate::crypto;
+use crate::errors::*;
+
+const AUTH_AD: &[u8] = b"d3xs bridge authentication";
+
+pub struct ServerChallenge {
+    public_key: crypto::PublicKey,
+    challenge: Challenge,
+}
+
+impl ServerChallenge {
+    pub fn generate<R: crypto::Rng>(bridge_key: &crypto::PublicKey) -> Result<Self> {
+        let secret_key = crypto::generate_secret_key::<R>();
+        let salsa = crypto::SalsaBox::new(bridge_key, &secret_key);
+        let challenge = Challenge::generate_with_ad::<R>(&salsa, AUTH_AD)?;
+        Ok(ServerChallenge {
+            public_key: secret_key.public_key(),
+            challenge,
+        })
+    }
+
+    /// The public key the bridge needs to decrypt the challenge
+    pub fn public_key(&self) -> &crypto::PublicKey {
+        &self.public_key
+    }
+
+    pub fn encrypted(&self) -> &[u8] {
+        &self.challenge.encrypted
+    }
+
+    pub fn verify(&self, code: &[u8]) -> Result<()> {
+        self.challenge.verify(code)?;
+        Ok(())
+    }
+}
+
+/// Decrypt the challenge of the webserver with the bridge key
+pub fn solve<'a>(
+    secret_key: &crypto::SecretKey,
+    server_key: &crypto::PublicKey,
+    challenge: &[u8],
+    dest: &'a mut [u8; chall::CHALL_SIZE],
+) -> Result<&'a [u8]> {
+    let salsa = crypto::SalsaBox::new(server_key, secret_key);
+    let code = crypto::decrypt_with_ad(&salsa, AUTH_AD, challenge, dest)?;
+    if code.len() != chall::CHALL_SIZE {
+        return Err(Error::BufferLimit);
+    }
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authenticate_bridge() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let server = ServerChallenge::generate::<crypto::Random>(&bridge.public_key())?;
+
+        let mut buf = [0u8; chall::CHALL_SIZE];
+        let code = solve(&bridge, server.public_key(), server.encrypted(), &mut buf)?;
+        server.verify(code)?;
+        Ok(())
+    }
+
+    #[test]
+    fn reject_other_bridge() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let mallory = crypto::generate_secret_key::<crypto::Random>();
+        let server = ServerChallenge::generate::<crypto::Random>(&bridge.public_key())?;
+
+        let mut buf = [0u8; chall::CHALL_SIZE];
+        assert!(solve(&mallory, server.public_key(), server.encrypted(), &mut buf).is_err());
+        assert!(server.verify(&[0u8; chall::CHALL_SIZE]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_other_challenges() -> Result<()> {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let user = crypto::generate_secret_key::<crypto::Random>();
+        let salsa = crypto::SalsaBox::new(&user.public_key(), &bridge);
+        let mut buf = [0u8; chall::CHALL_SIZE];
+
+        // a door challenge, encrypted for the bridge
+        let door = Challenge::generate::<crypto::Random>(&salsa)?;
+        assert!(solve(&bridge, &user.public_key(), &door.encrypted, &mut buf).is_err());
+
+        // a user challenge, encrypted with the bridge key and a binding
+        let chall = Challenge::issue::<crypto::Random, chall::SystemClock>(
+            &salsa,
+            &bridge.public_key(),
+            "alice",
+            "building",
+        )?;
+        assert!(solve(&bridge, &user.public_key(), &chall.encrypted, &mut buf).is_err());
+        assert!(solve(&bridge, &user.public_key(), &chall.message(), &mut buf).is_err());
+
+        // oversized input is refused instead of overflowing the buffer
+        let large = vec![0u8; 4096];
+        assert!(solve(&bridge, &user.public_key(), &large, &mut buf).is_err());
+        Ok(())
+    }
+}
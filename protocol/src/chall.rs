@@ -4,10 +4,17 @@ use sha3::{Digest, Sha3_256};
 use std::collections::HashMap;
 
 const RING_BUFFER_SIZE: usize = 4;
-const CHALL_SIZE: usize = 32;
+pub const CHALL_SIZE: usize = 32;
+/// How many seconds a challenge can be solved after it has been issued
+pub const DEFAULT_TTL: u64 = 120;
+/// How far the issue time of a challenge may be off for the client, this allows for a longer ttl
+/// on the bridge and a clock that is not quite right
+pub const MAX_CLIENT_SKEW: u64 = 600;
 const CHALL_ENCRYPTED_SIZE: usize =
     CHALL_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE;
+const BOUND_ENCRYPTED_SIZE: usize = CHALL_ENCRYPTED_SIZE + crypto::CRYPTO_AD_SIZE;
 const SHA3_SIZE: usize = 32;
+const BINDING_VERSION: u8 = 1;
 
 fn hash(bytes: &[u8], dest: &mut [u8; SHA3_SIZE]) {
     let mut hasher = Sha3_256::new();
@@ -15,10 +22,143 @@ fn hash(bytes: &[u8], dest: &mut [u8; SHA3_SIZE]) {
     hasher.finalize_into(dest.into());
 }
 
+/// How the door responds to a written solution or command
+///
+/// Anything but `Ok` is sent as att error, in the range that is reserved for applications.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteResult {
+    Ok = 0x00,
+    /// The solution is not valid for the challenge, e.g. because it's encrypted with the wrong key
+    Rejected = 0x80,
+    /// There was no challenge for the connection, or it has expired
+    NoChallenge = 0x81,
+}
+
+impl WriteResult {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0x00 => Some(WriteResult::Ok),
+            0x80 => Some(WriteResult::Rejected),
+            0x81 => Some(WriteResult::NoChallenge),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A source of time in seconds, the epoch is up to the implementation
+pub trait Clock {
+    fn now() -> u64;
+}
+
+pub struct SystemClock;
+
+#[cfg(not(target_os = "espidf"))]
+impl Clock for SystemClock {
+    fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// Who has issued a challenge for whom and which door
+///
+/// This is sent in plain text in front of the encrypted challenge and authenticated as associated
+/// data, so the client can check it's approving the door it has asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Binding {
+    /// The public key of the bridge
+    pub bridge: [u8; crypto::CRYPTO_PUBLIC_KEY_SIZE],
+    pub user: String,
+    pub door: String,
+    pub issued: u64,
+}
+
+impl Binding {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![BINDING_VERSION];
+        buf.extend_from_slice(&self.issued.to_le_bytes());
+        buf.extend_from_slice(&self.bridge);
+        for value in [&self.user, &self.door] {
+            let len = u8::try_from(value.len()).map_err(|_| Error::BufferLimit)?;
+            buf.push(len);
+            buf.extend_from_slice(value.as_bytes());
+        }
+        Ok(buf)
+    }
+
+    /// Decode the binding, returns it and the remaining buffer
+    pub fn decode(buf: &[u8]) -> Result<(Self, &[u8])> {
+        let (&version, buf) = buf.split_first().ok_or(Error::BufferLimit)?;
+        if version != BINDING_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if buf.len() < 8 + crypto::CRYPTO_PUBLIC_KEY_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let (issued, buf) = buf.split_at(8);
+        let (bridge, mut buf) = buf.split_at(crypto::CRYPTO_PUBLIC_KEY_SIZE);
+
+        let mut text = || -> Result<String> {
+            let (&len, rest) = buf.split_first().ok_or(Error::BufferLimit)?;
+            let len = usize::from(len);
+            if rest.len() < len {
+                return Err(Error::BufferLimit);
+            }
+            let (value, rest) = rest.split_at(len);
+            buf = rest;
+            let value = core::str::from_utf8(value).map_err(|_| Error::InvalidField)?;
+            Ok(value.to_string())
+        };
+        let user = text()?;
+        let door = text()?;
+
+        let binding = Binding {
+            bridge: bridge.try_into().map_err(|_| Error::BufferLimit)?,
+            user,
+            door,
+            issued: u64::from_le_bytes(issued.try_into().map_err(|_| Error::BufferLimit)?),
+        };
+        Ok((binding, buf))
+    }
+
+    /// Check on the client that the challenge is for the door it has asked for and recent
+    pub fn check(&self, door: &str, now: u64) -> Result<()> {
+        if self.door != door {
+            return Err(Error::WrongDoor);
+        }
+        if now.abs_diff(self.issued) > MAX_CLIENT_SKEW {
+            return Err(Error::ChallengeExpired);
+        }
+        Ok(())
+    }
+}
+
+/// The client side, decrypt a challenge that was issued with a binding
+pub fn open(salsa: &crypto::SalsaBox, message: &[u8]) -> Result<(Binding, [u8; CHALL_SIZE])> {
+    let (binding, encrypted) = Binding::decode(message)?;
+    if encrypted.len() != BOUND_ENCRYPTED_SIZE {
+        return Err(Error::BufferLimit);
+    }
+    let ad = &message[..message.len() - encrypted.len()];
+    let mut code = [0u8; CHALL_SIZE];
+    crypto::decrypt_with_ad(salsa, ad, encrypted, &mut code)?;
+    Ok((binding, code))
+}
+
 pub struct Challenge {
     // store this as sha256 so the attacker has less control over inputs of the compare
     code: [u8; SHA3_SIZE],
-    pub encrypted: [u8; CHALL_ENCRYPTED_SIZE],
+    pub encrypted: Vec<u8>,
+    /// When the challenge has been issued, 0 if it was generated without a clock
+    pub issued: u64,
+    /// The encoded binding the challenge is authenticated with, empty if there is none
+    pub binding: Vec<u8>,
 }
 
 impl Challenge {
@@ -29,10 +169,76 @@ impl Challenge {
         let mut encrypted = [0u8; CHALL_ENCRYPTED_SIZE];
         crypto::encrypt::<R>(salsa, &chall, &mut encrypted)?;
 
+        Ok(Challenge {
+            code: Self::hash_code(&chall),
+            encrypted: encrypted.to_vec(),
+            issued: 0,
+            binding: Vec::new(),
+        })
+    }
+
+    /// Generate a challenge that is authenticated with `ad`, so it can't be mistaken for one
+    /// of a different purpose
+    pub fn generate_with_ad<R: crypto::Rng>(salsa: &crypto::SalsaBox, ad: &[u8]) -> Result<Self> {
+        let mut chall = [0u8; CHALL_SIZE];
+        R::getrandom(&mut chall);
+
+        let mut encrypted = [0u8; BOUND_ENCRYPTED_SIZE];
+        crypto::encrypt_with_ad::<R>(salsa, ad, &chall, &mut encrypted)?;
+
+        Ok(Challenge {
+            code: Self::hash_code(&chall),
+            encrypted: encrypted.to_vec(),
+            issued: 0,
+            binding: Vec::new(),
+        })
+    }
+
+    fn hash_code(chall: &[u8]) -> [u8; SHA3_SIZE] {
         let mut code = [0u8; SHA3_SIZE];
-        hash(&chall, &mut code);
+        hash(chall, &mut code);
+        code
+    }
 
-        Ok(Challenge { code, encrypted })
+    /// Generate a challenge for a user and door, bound to the bridge and the time it's issued
+    pub fn issue<R: crypto::Rng, C: Clock>(
+        salsa: &crypto::SalsaBox,
+        bridge: &crypto::PublicKey,
+        user: &str,
+        door: &str,
+    ) -> Result<Self> {
+        let issued = C::now();
+        let binding = Binding {
+            bridge: *bridge.as_bytes(),
+            user: user.to_string(),
+            door: door.to_string(),
+            issued,
+        };
+        let binding = binding.encode()?;
+
+        let mut chall = [0u8; CHALL_SIZE];
+        R::getrandom(&mut chall);
+
+        let mut encrypted = [0u8; BOUND_ENCRYPTED_SIZE];
+        crypto::encrypt_with_ad::<R>(salsa, &binding, &chall, &mut encrypted)?;
+
+        Ok(Challenge {
+            code: Self::hash_code(&chall),
+            encrypted: encrypted.to_vec(),
+            issued,
+            binding,
+        })
+    }
+
+    /// The challenge as it's sent to the client, the binding followed by the encrypted challenge
+    pub fn message(&self) -> Vec<u8> {
+        let mut buf = self.binding.clone();
+        buf.extend_from_slice(&self.encrypted);
+        buf
+    }
+
+    pub fn is_expired(&self, now: u64, ttl: u64) -> bool {
+        now.saturating_sub(self.issued) > ttl
     }
 
     pub fn verify(&self, code: &[u8]) -> Result<&Self> {
@@ -53,38 +259,40 @@ pub struct RingBuffer {
 }
 
 impl RingBuffer {
-    pub fn new<R: crypto::Rng>(salsa: &crypto::SalsaBox) -> RingBuffer {
-        let mut ring = RingBuffer::default();
-        ring.challenges[0] = Some(Challenge::generate::<R>(salsa).unwrap());
-        ring
-    }
-
-    pub fn current(&self) -> &Challenge {
-        self.challenges[self.cursor].as_ref().unwrap()
-    }
-
-    pub fn generate_next<R: crypto::Rng>(&mut self, salsa: &crypto::SalsaBox) -> &Challenge {
+    pub fn push(&mut self, chall: Challenge) -> &Challenge {
         if self.challenges.len() - 1 == self.cursor {
             self.cursor = 0;
         } else {
             self.cursor += 1;
         }
-        self.challenges[self.cursor] = Some(Challenge::generate::<R>(salsa).unwrap());
-        self.challenges[self.cursor].as_ref().unwrap()
+        self.challenges[self.cursor].insert(chall)
     }
 
-    pub fn verify(&self, secret: &[u8]) -> Result<()> {
-        for chall in self.challenges.iter().flatten() {
-            if chall.verify(secret).is_ok() {
-                return Ok(());
+    /// Find the challenge for this solution and consume it, it's only accepted if it's younger than `ttl`
+    pub fn verify<C: Clock>(&mut self, secret: &[u8], ttl: u64) -> Result<()> {
+        let now = C::now();
+        let mut ret = Err(Error::AuthError);
+        for slot in &mut self.challenges {
+            let Some(chall) = slot else { continue };
+            let expired = chall.is_expired(now, ttl);
+            let solved = chall.verify(secret).is_ok();
+            // expired challenges can never be solved anymore, drop them too
+            if expired || solved {
+                *slot = None;
+            }
+            if solved {
+                ret = if expired {
+                    Err(Error::ChallengeExpired)
+                } else {
+                    Ok(())
+                };
             }
         }
-
-        Err(Error::AuthError)
+        ret
     }
 
-    pub fn reset<R: crypto::Rng>(&mut self, salsa: &crypto::SalsaBox) {
-        *self = RingBuffer::new::<R>(salsa)
+    pub fn is_empty(&self) -> bool {
+        self.challenges.iter().all(Option::is_none)
     }
 }
 
@@ -94,29 +302,223 @@ pub struct UserDoorMap {
 }
 
 impl UserDoorMap {
-    pub fn generate_next<R: crypto::Rng>(
+    pub fn generate_next<R: crypto::Rng, C: Clock>(
         &mut self,
         user: String,
         door: String,
         salsa: &crypto::SalsaBox,
-    ) -> &Challenge {
-        let ring = self
+        bridge: &crypto::PublicKey,
+    ) -> Result<&Challenge> {
+        let chall = Challenge::issue::<R, C>(salsa, bridge, &user, &door)?;
+        let ring = self.map.entry((user, door)).or_default();
+        Ok(ring.push(chall))
+    }
+
+    /// Verify a solution, the challenge is consumed even if the door is not opened afterwards
+    pub fn verify<C: Clock>(
+        &mut self,
+        user: String,
+        door: String,
+        secret: &[u8],
+        ttl: u64,
+    ) -> Result<String> {
+        let key = (user, door);
+        let Some(ring) = self.map.get_mut(&key) else {
+            return Err(Error::AuthError);
+        };
+        let ret = ring.verify::<C>(secret, ttl);
+        if ring.is_empty() {
+            self.map.remove(&key);
+        }
+        ret?;
+        Ok(key.1)
+    }
+
+    /// Invalidate all challenges that have been issued for this user and door
+    pub fn reset(&mut self, user: String, door: String) {
+        self.map.remove(&(user, door));
+    }
+
+    /// Only keep the challenges of user/door pairs the predicate returns true for
+    pub fn retain<F: FnMut(&str, &str) -> bool>(&mut self, mut f: F) {
+        self.map.retain(|(user, door), _| f(user, door));
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    thread_local! {
+        static NOW: Cell<u64> = const { Cell::new(1_700_000_000) };
+    }
+
+    struct TestClock;
+
+    impl TestClock {
+        fn advance(secs: u64) {
+            NOW.with(|now| now.set(now.get() + secs));
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now() -> u64 {
+            NOW.with(Cell::get)
+        }
+    }
+
+    struct Setup {
+        bridge: crypto::PublicKey,
+        /// The box the bridge encrypts with
+        issuer: crypto::SalsaBox,
+        /// The box the user decrypts with
+        client: crypto::SalsaBox,
+    }
+
+    fn setup() -> Setup {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let user = crypto::generate_secret_key::<crypto::Random>();
+        Setup {
+            bridge: bridge.public_key(),
+            issuer: crypto::SalsaBox::new(&user.public_key(), &bridge),
+            client: crypto::SalsaBox::new(&bridge.public_key(), &user),
+        }
+    }
+
+    fn issue(map: &mut UserDoorMap, setup: &Setup, user: &str, door: &str) -> Vec<u8> {
+        map.generate_next::<crypto::Random, TestClock>(
+            user.to_string(),
+            door.to_string(),
+            &setup.issuer,
+            &setup.bridge,
+        )
+        .unwrap()
+        .message()
+    }
+
+    fn fetch(map: &mut UserDoorMap, setup: &Setup) -> [u8; CHALL_SIZE] {
+        let message = issue(map, setup, "alice", "home");
+        let (_, code) = open(&setup.client, &message).unwrap();
+        code
+    }
+
+    fn solve(map: &mut UserDoorMap, code: &[u8]) -> Result<String> {
+        map.verify::<TestClock>("alice".to_string(), "home".to_string(), code, DEFAULT_TTL)
+    }
+
+    #[test]
+    fn retain_user_door_map() {
+        let setup = setup();
+        let mut map = UserDoorMap::default();
+        issue(&mut map, &setup, "alice", "home");
+        issue(&mut map, &setup, "alice", "building");
+        issue(&mut map, &setup, "bob", "home");
+        assert_eq!(map.len(), 3);
+
+        map.retain(|user, door| user == "alice" && door == "home");
+        assert_eq!(map.len(), 1);
+        assert!(map
             .map
-            .entry((user, door))
-            .or_insert_with(|| RingBuffer::new::<R>(salsa));
-        ring.generate_next::<R>(salsa)
+            .contains_key(&("alice".to_string(), "home".to_string())));
+
+        map.clear();
+        assert!(map.is_empty());
     }
 
-    pub fn verify(&self, user: String, door: String, secret: &[u8]) -> Result<String> {
-        if let Some(ring) = self.map.get(&(user, door.clone())) {
-            ring.verify(secret)?;
-            Ok(door)
-        } else {
-            Err(Error::AuthError)
+    #[test]
+    fn challenge_is_single_use() {
+        let setup = setup();
+        let mut map = UserDoorMap::default();
+        let first = fetch(&mut map, &setup);
+        let second = fetch(&mut map, &setup);
+
+        assert_eq!(solve(&mut map, &first).unwrap(), "home");
+        assert!(matches!(solve(&mut map, &first), Err(Error::AuthError)));
+        // other challenges are not affected
+        assert_eq!(solve(&mut map, &second).unwrap(), "home");
+        assert!(map.is_empty());
+
+        // the ring only remembers the most recent challenges
+        let oldest = fetch(&mut map, &setup);
+        for _ in 0..RING_BUFFER_SIZE {
+            fetch(&mut map, &setup);
         }
+        assert!(solve(&mut map, &oldest).is_err());
+    }
+
+    #[test]
+    fn challenge_expires() {
+        let setup = setup();
+        let mut map = UserDoorMap::default();
+        let old = fetch(&mut map, &setup);
+        TestClock::advance(DEFAULT_TTL);
+        let fresh = fetch(&mut map, &setup);
+        let last = fetch(&mut map, &setup);
+        assert_eq!(solve(&mut map, &old).unwrap(), "home");
+
+        TestClock::advance(DEFAULT_TTL + 1);
+        assert!(matches!(
+            solve(&mut map, &fresh),
+            Err(Error::ChallengeExpired)
+        ));
+        assert!(matches!(solve(&mut map, &fresh), Err(Error::AuthError)));
+        // expired challenges are dropped by any attempt
+        assert!(matches!(solve(&mut map, &last), Err(Error::AuthError)));
     }
 
-    pub fn reset<R: crypto::Rng>(&mut self, user: String, door: String, salsa: &crypto::SalsaBox) {
-        self.map.insert((user, door), RingBuffer::new::<R>(salsa));
+    #[test]
+    fn challenge_is_bound() {
+        let setup = setup();
+        let mut map = UserDoorMap::default();
+        let message = issue(&mut map, &setup, "alice", "home");
+
+        let (binding, code) = open(&setup.client, &message).unwrap();
+        assert_eq!(
+            binding,
+            Binding {
+                bridge: *setup.bridge.as_bytes(),
+                user: "alice".to_string(),
+                door: "home".to_string(),
+                issued: TestClock::now(),
+            }
+        );
+
+        // claiming the challenge is for a different door breaks the authentication
+        let mut forged = Binding {
+            door: "building".to_string(),
+            ..binding.clone()
+        }
+        .encode()
+        .unwrap();
+        forged.extend_from_slice(&message[message.len() - BOUND_ENCRYPTED_SIZE..]);
+        assert!(open(&setup.client, &forged).is_err());
+
+        // the client refuses challenges for other doors, or that are not recent
+        let now = TestClock::now();
+        assert!(binding.check("home", now + 60).is_ok());
+        assert!(matches!(
+            binding.check("building", now),
+            Err(Error::WrongDoor)
+        ));
+        assert!(matches!(
+            binding.check("home", now + MAX_CLIENT_SKEW + 1),
+            Err(Error::ChallengeExpired)
+        ));
+        assert!(binding.check("home", now - MAX_CLIENT_SKEW - 1).is_err());
+
+        assert_eq!(solve(&mut map, &code).unwrap(), "home");
     }
 }
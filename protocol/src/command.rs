@@ -0,0 +1,383 @@
+//! Commands the bridge sends to a door, and the results the door sends back.
+//!
+//! A command is encrypted together with the solution of the door's current challenge, so it
+//! can only be used once and can't be modified in transit. The door answers with a result
+//! that is bound to the same solution, so an old result can't be replayed to the bridge.
+use crate::chall::{self, Challenge};
+use crate::crypto;
+use crate::errors::*;
+use crate::status::{self, Status};
+
+const MESSAGE_VERSION: u8 = 2;
+// the message type is authenticated too, so a result can't be sent back as a command
+const TYPE_REQUEST: u8 = 1;
+const TYPE_RESPONSE: u8 = 2;
+const HEADER_SIZE: usize = 1 + 1 + chall::CHALL_SIZE;
+
+pub const REQUEST_SIZE: usize = HEADER_SIZE + 1 + 1 + 2 + 8;
+pub const REQUEST_ENCRYPTED_SIZE: usize =
+    REQUEST_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE;
+pub const MAX_RESPONSE_SIZE: usize = HEADER_SIZE + 1 + status::MAX_STATUS_SIZE;
+pub const MAX_RESPONSE_ENCRYPTED_SIZE: usize =
+    MAX_RESPONSE_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE;
+
+const ACTION_OPEN: u8 = 1;
+const ACTION_HOLD_OPEN: u8 = 2;
+const ACTION_LOCK: u8 = 3;
+const ACTION_STATUS: u8 = 4;
+const ACTION_IDENTIFY: u8 = 5;
+/// Doors refuse to open for longer than this, use [`Command::HoldOpen`] instead
+pub const MAX_OPEN_SECONDS: u16 = 300;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Switch on an output for some seconds, `None` uses the duration configured on the door
+    Open { output: u8, seconds: Option<u16> },
+    /// Switch on an output until it's locked again
+    HoldOpen { output: u8 },
+    /// Switch off an output, this ends a hold open
+    Lock { output: u8 },
+    /// Report the status of the door
+    Status,
+    /// Blink the led, to find a door controller
+    Identify,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Ok,
+    /// The door has no output with this index
+    InvalidOutput,
+    /// The command is not supported by this door
+    Unsupported,
+    /// The door refuses to open for this long, see [`MAX_OPEN_SECONDS`]
+    InvalidDuration,
+}
+
+impl Outcome {
+    fn to_byte(self) -> u8 {
+        match self {
+            Outcome::Ok => 0,
+            Outcome::InvalidOutput => 1,
+            Outcome::Unsupported => 2,
+            Outcome::InvalidDuration => 3,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(Outcome::Ok),
+            1 => Ok(Outcome::InvalidOutput),
+            2 => Ok(Outcome::Unsupported),
+            3 => Ok(Outcome::InvalidDuration),
+            _ => Err(Error::InvalidField),
+        }
+    }
+}
+
+fn header(kind: u8, code: &[u8; chall::CHALL_SIZE]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(REQUEST_SIZE);
+    buf.push(MESSAGE_VERSION);
+    buf.push(kind);
+    buf.extend_from_slice(code);
+    buf
+}
+
+fn parse_header(kind: u8, buf: &[u8]) -> Result<([u8; chall::CHALL_SIZE], &[u8])> {
+    let Some((&version, _)) = buf.split_first() else {
+        return Err(Error::BufferLimit);
+    };
+    if version != MESSAGE_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+    if buf.len() < HEADER_SIZE {
+        return Err(Error::BufferLimit);
+    }
+    if buf[1] != kind {
+        return Err(Error::InvalidField);
+    }
+    let mut code = [0u8; chall::CHALL_SIZE];
+    code.copy_from_slice(&buf[2..HEADER_SIZE]);
+    Ok((code, &buf[HEADER_SIZE..]))
+}
+
+fn seal<R: crypto::Rng>(salsa: &crypto::SalsaBox, buf: &[u8]) -> Result<Vec<u8>> {
+    let mut encrypted = [0u8; MAX_RESPONSE_ENCRYPTED_SIZE];
+    let encrypted = crypto::encrypt::<R>(salsa, buf, &mut encrypted)?;
+    Ok(encrypted.to_vec())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    /// The decrypted challenge of the door
+    pub code: [u8; chall::CHALL_SIZE],
+    pub command: Command,
+    /// Unix time of the bridge, the door uses it to check the expiry of offline tokens
+    pub time: u64,
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let (action, output, seconds) = match self.command {
+            Command::Open { output, seconds } => (ACTION_OPEN, output, seconds.unwrap_or(0)),
+            Command::HoldOpen { output } => (ACTION_HOLD_OPEN, output, 0),
+            Command::Lock { output } => (ACTION_LOCK, output, 0),
+            Command::Status => (ACTION_STATUS, 0, 0),
+            Command::Identify => (ACTION_IDENTIFY, 0, 0),
+        };
+        let mut buf = header(TYPE_REQUEST, &self.code);
+        buf.push(action);
+        buf.push(output);
+        buf.extend_from_slice(&seconds.to_le_bytes());
+        buf.extend_from_slice(&self.time.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let (code, body) = parse_header(TYPE_REQUEST, buf)?;
+        let [action, output, s0, s1, ref time @ ..] = *body else {
+            return Err(Error::BufferLimit);
+        };
+        let seconds = u16::from_le_bytes([s0, s1]);
+        let time = u64::from_le_bytes(time.try_into().map_err(|_| Error::BufferLimit)?);
+        let command = match action {
+            ACTION_OPEN => Command::Open {
+                output,
+                seconds: Some(seconds).filter(|secs| *secs > 0),
+            },
+            ACTION_HOLD_OPEN => Command::HoldOpen { output },
+            ACTION_LOCK => Command::Lock { output },
+            ACTION_STATUS => Command::Status,
+            ACTION_IDENTIFY => Command::Identify,
+            _ => return Err(Error::InvalidField),
+        };
+        Ok(Request {
+            code,
+            command,
+            time,
+        })
+    }
+
+    /// Encrypt the command for the door, `salsa` is the bridge/door box
+    pub fn seal<R: crypto::Rng>(&self, salsa: &crypto::SalsaBox) -> Result<Vec<u8>> {
+        seal::<R>(salsa, &self.encode())
+    }
+
+    pub fn open(salsa: &crypto::SalsaBox, encrypted: &[u8]) -> Result<Self> {
+        if encrypted.len() != REQUEST_ENCRYPTED_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let mut buf = [0u8; REQUEST_SIZE];
+        let buf = crypto::decrypt(salsa, encrypted, &mut buf)?;
+        Self::decode(buf)
+    }
+
+    /// The door side, decrypt a command and verify it has been issued for this challenge
+    pub fn verify(salsa: &crypto::SalsaBox, chall: &Challenge, encrypted: &[u8]) -> Result<Self> {
+        let request = Self::open(salsa, encrypted)?;
+        chall.verify(&request.code)?;
+        Ok(request)
+    }
+
+    /// The result for this command, bound to the same challenge
+    pub fn respond(&self, outcome: Outcome, status: Option<Status>) -> Response {
+        Response {
+            code: self.code,
+            outcome,
+            status,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub code: [u8; chall::CHALL_SIZE],
+    pub outcome: Outcome,
+    /// The status of the door, if it has been requested
+    pub status: Option<Status>,
+}
+
+impl Response {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = header(TYPE_RESPONSE, &self.code);
+        buf.push(self.outcome.to_byte());
+        if let Some(status) = &self.status {
+            buf.extend_from_slice(&status.encode()?);
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let (code, body) = parse_header(TYPE_RESPONSE, buf)?;
+        let (&outcome, status) = body.split_first().ok_or(Error::BufferLimit)?;
+        let outcome = Outcome::from_byte(outcome)?;
+        let status = if status.is_empty() {
+            None
+        } else {
+            Some(Status::decode(status)?)
+        };
+        Ok(Response {
+            code,
+            outcome,
+            status,
+        })
+    }
+
+    /// Encrypt the result for the bridge, `salsa` is the door/bridge box
+    pub fn seal<R: crypto::Rng>(&self, salsa: &crypto::SalsaBox) -> Result<Vec<u8>> {
+        seal::<R>(salsa, &self.encode()?)
+    }
+
+    /// The bridge side, decrypt the result and verify it belongs to the command that was sent
+    pub fn open(salsa: &crypto::SalsaBox, request: &Request, encrypted: &[u8]) -> Result<Self> {
+        if encrypted.len() > MAX_RESPONSE_ENCRYPTED_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+        let buf = crypto::decrypt(salsa, encrypted, &mut buf)?;
+        let response = Self::decode(buf)?;
+        if response.code != request.code {
+            return Err(Error::InvalidChallengeReponse);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Setup;
+
+    fn new_request(setup: &Setup, chall: &Challenge, command: Command) -> Request {
+        Request {
+            code: setup.solve(chall),
+            command,
+            time: 1700000000,
+        }
+    }
+
+    fn status() -> Status {
+        Status {
+            version: "0.1.0".to_string(),
+            uptime: 60,
+            last_open: None,
+            failed_attempts: 0,
+            contact: None,
+        }
+    }
+
+    #[test]
+    fn command_roundtrip() -> Result<()> {
+        let setup = Setup::new();
+        let commands = [
+            Command::Open {
+                output: 1,
+                seconds: Some(30),
+            },
+            Command::Open {
+                output: 0,
+                seconds: None,
+            },
+            Command::HoldOpen { output: 2 },
+            Command::Lock { output: 2 },
+            Command::Status,
+            Command::Identify,
+        ];
+        for command in commands {
+            let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+            let request = new_request(&setup, &chall, command);
+            let encrypted = request.seal::<crypto::Random>(&setup.bridge)?;
+            assert_eq!(encrypted.len(), REQUEST_ENCRYPTED_SIZE);
+
+            let received = Request::verify(&setup.door, &chall, &encrypted)?;
+            assert_eq!(received, request);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_wrong_challenge() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let other = Challenge::generate::<crypto::Random>(&setup.door)?;
+
+        let encrypted =
+            new_request(&setup, &other, Command::Identify).seal::<crypto::Random>(&setup.bridge)?;
+        assert!(Request::verify(&setup.door, &chall, &encrypted).is_err());
+
+        let mut encrypted =
+            new_request(&setup, &chall, Command::Identify).seal::<crypto::Random>(&setup.bridge)?;
+        encrypted[REQUEST_ENCRYPTED_SIZE - 1] ^= 1;
+        assert!(Request::verify(&setup.door, &chall, &encrypted).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn response_roundtrip() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let request = new_request(&setup, &chall, Command::Status);
+
+        let response = request.respond(Outcome::Ok, Some(status()));
+        let encrypted = response.seal::<crypto::Random>(&setup.door)?;
+        assert_eq!(
+            Response::open(&setup.bridge, &request, &encrypted)?,
+            response
+        );
+
+        let response = request.respond(Outcome::InvalidOutput, None);
+        let encrypted = response.seal::<crypto::Random>(&setup.door)?;
+        assert_eq!(
+            Response::open(&setup.bridge, &request, &encrypted)?,
+            response
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_replayed_response() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let old = new_request(&setup, &chall, Command::Status);
+        let encrypted = old
+            .respond(Outcome::Ok, None)
+            .seal::<crypto::Random>(&setup.door)?;
+
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let request = new_request(&setup, &chall, Command::Status);
+        assert!(matches!(
+            Response::open(&setup.bridge, &request, &encrypted),
+            Err(Error::InvalidChallengeReponse)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed() -> Result<()> {
+        let request = Request {
+            code: [7u8; chall::CHALL_SIZE],
+            command: Command::Lock { output: 0 },
+            time: 1700000000,
+        };
+        let buf = request.encode();
+        assert_eq!(buf.len(), REQUEST_SIZE);
+        assert_eq!(Request::decode(&buf)?, request);
+
+        assert!(Request::decode(&buf[..buf.len() - 1]).is_err());
+        let mut unknown = buf.clone();
+        unknown[HEADER_SIZE] = 0xff;
+        assert!(Request::decode(&unknown).is_err());
+        let mut version = buf.clone();
+        version[0] = 1;
+        assert!(matches!(
+            Request::decode(&version),
+            Err(Error::UnsupportedVersion(1))
+        ));
+
+        // a response can't be used as a request
+        let response = request.respond(Outcome::Ok, None).encode()?;
+        assert!(Request::decode(&response).is_err());
+        assert!(Response::decode(&buf).is_err());
+        Ok(())
+    }
+}
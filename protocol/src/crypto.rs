@@ -2,11 +2,14 @@ use crate::errors::*;
 use crypto_box::{aead::AeadInPlace, Nonce, Tag};
 pub use crypto_box::{PublicKey, SalsaBox, SecretKey};
 use data_encoding::BASE64;
+use sha3::{Digest, Sha3_256};
 
 pub const CRYPTO_TAG_SIZE: usize = 16;
 pub const CRYPTO_NONCE_SIZE: usize = 24;
 pub const CRYPTO_SECRET_KEY_SIZE: usize = 32;
 pub const CRYPTO_PUBLIC_KEY_SIZE: usize = 32;
+/// The extra bytes that are encrypted to authenticate the associated data
+pub const CRYPTO_AD_SIZE: usize = 32;
 
 pub trait Rng {
     fn getrandom(buf: &mut [u8]);
@@ -22,19 +25,27 @@ impl Rng for Random {
 }
 
 pub fn encrypt<'a, R: Rng>(salsa: &SalsaBox, src: &[u8], dest: &'a mut [u8]) -> Result<&'a [u8]> {
+    let mut nonce = [0u8; CRYPTO_NONCE_SIZE];
+    R::getrandom(&mut nonce);
+    encrypt_with_nonce(salsa, &nonce, src, dest)
+}
+
+/// Encrypt with a caller provided nonce, the nonce must never be used twice for different messages
+pub fn encrypt_with_nonce<'a>(
+    salsa: &SalsaBox,
+    nonce: &[u8; CRYPTO_NONCE_SIZE],
+    src: &[u8],
+    dest: &'a mut [u8],
+) -> Result<&'a [u8]> {
     let buffer_size = dest.len();
     if buffer_size < src.len() + CRYPTO_NONCE_SIZE + CRYPTO_TAG_SIZE {
         return Err(Error::BufferLimit);
     }
 
     let length = {
-        let (nonce, cursor) = dest.split_at_mut(CRYPTO_NONCE_SIZE);
-        let nonce = {
-            let mut buf = [0u8; CRYPTO_NONCE_SIZE];
-            R::getrandom(&mut buf);
-            nonce.copy_from_slice(&buf);
-            Nonce::from(buf)
-        };
+        let (nonce_buf, cursor) = dest.split_at_mut(CRYPTO_NONCE_SIZE);
+        nonce_buf.copy_from_slice(nonce);
+        let nonce = Nonce::from(*nonce);
 
         let (buf, cursor) = cursor.split_at_mut(src.len());
         buf.copy_from_slice(src);
@@ -50,7 +61,8 @@ pub fn encrypt<'a, R: Rng>(salsa: &SalsaBox, src: &[u8], dest: &'a mut [u8]) ->
 }
 
 pub fn decrypt<'a>(salsa: &SalsaBox, src: &[u8], dest: &'a mut [u8]) -> Result<&'a [u8]> {
-    if src.len() < CRYPTO_NONCE_SIZE + CRYPTO_TAG_SIZE {
+    let overhead = CRYPTO_NONCE_SIZE + CRYPTO_TAG_SIZE;
+    if src.len() < overhead || dest.len() < src.len() - overhead {
         return Err(Error::BufferLimit);
     }
 
@@ -76,6 +88,50 @@ pub fn decrypt<'a>(salsa: &SalsaBox, src: &[u8], dest: &'a mut [u8]) -> Result<&
     Ok(dest)
 }
 
+fn hash_ad(ad: &[u8]) -> [u8; CRYPTO_AD_SIZE] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(ad);
+    hasher.finalize().into()
+}
+
+/// Encrypt and authenticate `ad` along with it, `ad` itself is not part of the output
+///
+/// The boxes don't support associated data, instead its hash is encrypted after the message.
+pub fn encrypt_with_ad<'a, R: Rng>(
+    salsa: &SalsaBox,
+    ad: &[u8],
+    src: &[u8],
+    dest: &'a mut [u8],
+) -> Result<&'a [u8]> {
+    let mut buf = src.to_vec();
+    buf.extend_from_slice(&hash_ad(ad));
+    encrypt::<R>(salsa, &buf, dest)
+}
+
+/// Decrypt a message from `encrypt_with_ad`, this fails if `ad` doesn't match
+pub fn decrypt_with_ad<'a>(
+    salsa: &SalsaBox,
+    ad: &[u8],
+    src: &[u8],
+    dest: &'a mut [u8],
+) -> Result<&'a [u8]> {
+    let overhead = CRYPTO_NONCE_SIZE + CRYPTO_TAG_SIZE + CRYPTO_AD_SIZE;
+    if src.len() < overhead || dest.len() < src.len() - overhead {
+        return Err(Error::BufferLimit);
+    }
+
+    let mut buf = vec![0u8; src.len() - CRYPTO_NONCE_SIZE - CRYPTO_TAG_SIZE];
+    let decrypted = decrypt(salsa, src, &mut buf)?;
+    let (msg, hash) = decrypted.split_at(decrypted.len() - CRYPTO_AD_SIZE);
+    if hash != hash_ad(ad) {
+        return Err(Error::AuthError);
+    }
+
+    let dest = &mut dest[..msg.len()];
+    dest.copy_from_slice(msg);
+    Ok(dest)
+}
+
 pub fn generate_secret_key<R: Rng>() -> SecretKey {
     let mut buf = [0u8; crypto_box::KEY_SIZE];
     R::getrandom(&mut buf);
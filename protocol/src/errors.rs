@@ -14,5 +14,19 @@ pub enum Error {
     AuthError,
     #[error("buffer size exceeded")]
     BufferLimit,
+    #[error("unsupported version: {0}")]
+    UnsupportedVersion(u8),
+    #[error("token has expired")]
+    TokenExpired,
+    #[error("current time is not known")]
+    UnknownTime,
+    #[error("challenge has expired")]
+    ChallengeExpired,
+    #[error("no pending challenge")]
+    NoChallenge,
+    #[error("invalid field in message")]
+    InvalidField,
+    #[error("challenge has been issued for a different door")]
+    WrongDoor,
 }
 pub type Result<T> = core::result::Result<T, Error>;
@@ -14,11 +14,26 @@ pub struct Config {
 pub struct User {
     #[serde(default)]
     pub authorize: Vec<String>,
+    /// Tokens to open doors without the bridge, by door id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub offline: HashMap<String, String>,
+    /// Tokens sealed with the next bridge key while it's being rotated, by door id
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub offline_next: HashMap<String, String>,
+    /// Unix timestamp after which the user is no longer valid (guest links)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Door {
     pub label: String,
+    /// Only set for doors that can be opened without the bridge
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    /// The bridge has failed to reach the door during its last health check
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unreachable: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -26,6 +41,7 @@ pub struct Door {
 pub enum Event {
     Config,
     Challenge(Challenge),
+    OpenResult(OpenResult),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -34,11 +50,39 @@ pub struct Challenge {
     pub challenge: String,
 }
 
+/// The outcome of a solve attempt, sent back to the user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OpenResult {
+    pub user: String,
+    pub door: String,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Opened,
+    BleUnreachable,
+    Timeout,
+    Unauthorized,
+    InvalidSolution,
+    /// The door has refused the solution of the bridge
+    Rejected,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ClientRequest {
     Fetch(Fetch),
     Solve(Solve),
+    /// Sent by the webserver, not the websocket clients
+    Auth(Auth),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Auth {
+    pub public_key: String,
+    pub challenge: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
@@ -59,6 +103,7 @@ pub struct Solve {
 pub enum ClientResponse {
     Config(UiConfig),
     Challenge(Challenge),
+    OpenResult(OpenResult),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -71,20 +116,55 @@ pub struct UiConfig {
 pub struct UiDoor {
     pub id: String,
     pub label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline: Option<UiOffline>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unreachable: bool,
 }
 
 impl UiDoor {
-    pub fn new(id: String, config: Door) -> Self {
+    pub fn new(
+        id: String,
+        config: Door,
+        token: Option<String>,
+        next_token: Option<String>,
+    ) -> Self {
+        let offline = match (config.public_key, token) {
+            (Some(public_key), Some(token)) => Some(UiOffline {
+                public_key,
+                token,
+                next_token,
+            }),
+            _ => None,
+        };
         Self {
             id,
             label: config.label,
+            offline,
+            unreachable: config.unreachable,
         }
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UiOffline {
+    pub public_key: String,
+    pub token: String,
+    /// Tried if the door refuses `token`, it may already trust the next bridge key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_token: Option<String>,
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum BridgeResponse {
     Config(Config),
     Challenge(Challenge),
+    Auth(AuthResponse),
+    OpenResult(OpenResult),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthResponse {
+    pub code: String,
 }
@@ -1,9 +1,18 @@
+pub mod auth;
 pub mod chall;
+pub mod command;
 pub mod crypto;
 pub mod errors;
+pub mod offline;
+pub mod outputs;
+pub mod provision;
+pub mod status;
 
 #[cfg(feature = "ipc")]
 pub mod ipc;
 
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
 #[cfg(target_arch = "wasm32")]
 pub mod wasm;
@@ -0,0 +1,210 @@
+//! Opening a door without the bridge, e.g. from a browser using Web Bluetooth.
+//!
+//! The bridge issues a token to the user that's encrypted for the door. The user
+//! writes it to the door, the door then sends a challenge encrypted for the user
+//! and opens once the user has written back the decrypted challenge.
+use crate::chall::Challenge;
+use crate::crypto;
+use crate::errors::*;
+use sha3::{Digest, Sha3_256};
+
+const TOKEN_VERSION: u8 = 1;
+pub const TOKEN_SIZE: usize = 1 + crypto::CRYPTO_PUBLIC_KEY_SIZE + 8;
+pub const TOKEN_ENCRYPTED_SIZE: usize =
+    TOKEN_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// The public key of the user the door is going to send a challenge to
+    pub user: crypto::PublicKey,
+    /// Unix timestamp after which the token is no longer valid
+    pub expires: u64,
+}
+
+impl Token {
+    pub fn encode(&self) -> [u8; TOKEN_SIZE] {
+        let mut buf = [0u8; TOKEN_SIZE];
+        buf[0] = TOKEN_VERSION;
+        buf[1..33].copy_from_slice(self.user.as_bytes());
+        buf[33..].copy_from_slice(&self.expires.to_le_bytes());
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != TOKEN_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        if buf[0] != TOKEN_VERSION {
+            return Err(Error::UnsupportedVersion(buf[0]));
+        }
+
+        let mut user = [0u8; crypto::CRYPTO_PUBLIC_KEY_SIZE];
+        user.copy_from_slice(&buf[1..33]);
+        let mut expires = [0u8; 8];
+        expires.copy_from_slice(&buf[33..]);
+
+        Ok(Token {
+            user: crypto::PublicKey::from(user),
+            expires: u64::from_le_bytes(expires),
+        })
+    }
+
+    /// Encrypt the token for the door, `salsa` is the bridge/door box.
+    ///
+    /// The nonce is derived from the token, so issuing the same token twice gives the same
+    /// ciphertext and the bridge doesn't need to keep track of tokens it has handed out.
+    pub fn seal(&self, salsa: &crypto::SalsaBox) -> Result<[u8; TOKEN_ENCRYPTED_SIZE]> {
+        let token = self.encode();
+
+        let mut hasher = Sha3_256::new();
+        hasher.update(b"d3xs offline token");
+        hasher.update(token);
+        let hash = hasher.finalize();
+        let mut nonce = [0u8; crypto::CRYPTO_NONCE_SIZE];
+        nonce.copy_from_slice(&hash[..crypto::CRYPTO_NONCE_SIZE]);
+
+        let mut encrypted = [0u8; TOKEN_ENCRYPTED_SIZE];
+        crypto::encrypt_with_nonce(salsa, &nonce, &token, &mut encrypted)?;
+        Ok(encrypted)
+    }
+
+    pub fn open(salsa: &crypto::SalsaBox, encrypted: &[u8]) -> Result<Self> {
+        if encrypted.len() != TOKEN_ENCRYPTED_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let mut buf = [0u8; TOKEN_SIZE];
+        let buf = crypto::decrypt(salsa, encrypted, &mut buf)?;
+        Self::decode(buf)
+    }
+}
+
+/// The door side of an offline open
+pub struct OfflineDoor {
+    bridge: crypto::SalsaBox,
+    secret_key: crypto::SecretKey,
+}
+
+impl OfflineDoor {
+    pub fn new(bridge_key: &crypto::PublicKey, secret_key: crypto::SecretKey) -> Self {
+        let bridge = crypto::SalsaBox::new(bridge_key, &secret_key);
+        OfflineDoor { bridge, secret_key }
+    }
+
+    /// Check a token the client has written and generate a challenge for the user it belongs to.
+    ///
+    /// `now` is the current unix time, tokens are refused while the door doesn't know it since
+    /// their expiry can't be checked. The caller keeps the challenge until the client writes
+    /// the solution, it's checked with [`Challenge::verify`].
+    pub fn issue<R: crypto::Rng>(&self, buf: &[u8], now: Option<u64>) -> Result<Challenge> {
+        let now = now.ok_or(Error::UnknownTime)?;
+        let token = Token::open(&self.bridge, buf)?;
+        if token.expires < now {
+            return Err(Error::TokenExpired);
+        }
+
+        let salsa = crypto::SalsaBox::new(&token.user, &self.secret_key);
+        Challenge::generate::<R>(&salsa)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1700000000;
+
+    struct Setup {
+        bridge: crypto::SecretKey,
+        door: crypto::SecretKey,
+        user: crypto::SecretKey,
+    }
+
+    impl Setup {
+        fn new() -> Self {
+            Setup {
+                bridge: crypto::generate_secret_key::<crypto::Random>(),
+                door: crypto::generate_secret_key::<crypto::Random>(),
+                user: crypto::generate_secret_key::<crypto::Random>(),
+            }
+        }
+
+        fn issue(&self, expires: u64) -> [u8; TOKEN_ENCRYPTED_SIZE] {
+            let salsa = crypto::SalsaBox::new(&self.door.public_key(), &self.bridge);
+            let token = Token {
+                user: self.user.public_key(),
+                expires,
+            };
+            token.seal(&salsa).unwrap()
+        }
+
+        fn door(&self) -> OfflineDoor {
+            OfflineDoor::new(&self.bridge.public_key(), self.door.clone())
+        }
+
+        fn solve(&self, challenge: &[u8]) -> Vec<u8> {
+            let salsa = crypto::SalsaBox::new(&self.door.public_key(), &self.user);
+            let mut buf = [0u8; 4096];
+            crypto::decrypt(&salsa, challenge, &mut buf)
+                .unwrap()
+                .to_vec()
+        }
+    }
+
+    #[test]
+    fn token_roundtrip() -> Result<()> {
+        let setup = Setup::new();
+        let token = setup.issue(1700000000);
+        assert_eq!(token, setup.issue(1700000000));
+        assert_ne!(token, setup.issue(1700000001));
+
+        let salsa = crypto::SalsaBox::new(&setup.bridge.public_key(), &setup.door);
+        let token = Token::open(&salsa, &token)?;
+        assert_eq!(
+            token,
+            Token {
+                user: setup.user.public_key(),
+                expires: 1700000000,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn offline_open() -> Result<()> {
+        let setup = Setup::new();
+        let door = setup.door();
+
+        let chall = door.issue::<crypto::Random>(&setup.issue(NOW + 60), Some(NOW))?;
+        let code = setup.solve(&chall.encrypted);
+        chall.verify(&code)?;
+        assert!(chall.verify(&[0u8; 32]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_forged_token() {
+        let setup = Setup::new();
+        let mallory = Setup::new();
+        let door = setup.door();
+        assert!(door
+            .issue::<crypto::Random>(&mallory.issue(NOW + 60), Some(NOW))
+            .is_err());
+    }
+
+    #[test]
+    fn reject_expired_token() {
+        let setup = Setup::new();
+        let door = setup.door();
+        let token = setup.issue(NOW);
+        assert!(matches!(
+            door.issue::<crypto::Random>(&token, Some(NOW + 1)),
+            Err(Error::TokenExpired)
+        ));
+        assert!(door.issue::<crypto::Random>(&token, Some(NOW)).is_ok());
+        // the door doesn't know the time, so the expiry can't be checked
+        assert!(matches!(
+            door.issue::<crypto::Random>(&token, None),
+            Err(Error::UnknownTime)
+        ));
+    }
+}
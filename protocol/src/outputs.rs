@@ -0,0 +1,146 @@
+//! Opening one of several outputs (relays) of a door controller.
+//!
+//! Instead of writing back the plain solution, the bridge encrypts the solution together
+//! with the index of the output, so the output can't be changed in transit. Writing the
+//! plain solution is still supported and opens the first output.
+use crate::chall::{self, Challenge};
+use crate::crypto;
+use crate::errors::*;
+
+const REQUEST_VERSION: u8 = 1;
+pub const REQUEST_SIZE: usize = 1 + chall::CHALL_SIZE + 1;
+pub const REQUEST_ENCRYPTED_SIZE: usize =
+    REQUEST_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpenRequest {
+    /// The decrypted challenge of the door
+    pub code: [u8; chall::CHALL_SIZE],
+    /// Index of the output to trigger
+    pub output: u8,
+}
+
+impl OpenRequest {
+    pub fn encode(&self) -> [u8; REQUEST_SIZE] {
+        let mut buf = [0u8; REQUEST_SIZE];
+        buf[0] = REQUEST_VERSION;
+        buf[1..33].copy_from_slice(&self.code);
+        buf[33] = self.output;
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != REQUEST_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        if buf[0] != REQUEST_VERSION {
+            return Err(Error::UnsupportedVersion(buf[0]));
+        }
+
+        let mut code = [0u8; chall::CHALL_SIZE];
+        code.copy_from_slice(&buf[1..33]);
+        Ok(OpenRequest {
+            code,
+            output: buf[33],
+        })
+    }
+
+    /// Encrypt the request for the door, `salsa` is the bridge/door box
+    pub fn seal<R: crypto::Rng>(
+        &self,
+        salsa: &crypto::SalsaBox,
+    ) -> Result<[u8; REQUEST_ENCRYPTED_SIZE]> {
+        let mut encrypted = [0u8; REQUEST_ENCRYPTED_SIZE];
+        crypto::encrypt::<R>(salsa, &self.encode(), &mut encrypted)?;
+        Ok(encrypted)
+    }
+
+    pub fn open(salsa: &crypto::SalsaBox, encrypted: &[u8]) -> Result<Self> {
+        if encrypted.len() != REQUEST_ENCRYPTED_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let mut buf = [0u8; REQUEST_SIZE];
+        let buf = crypto::decrypt(salsa, encrypted, &mut buf)?;
+        Self::decode(buf)
+    }
+}
+
+/// Build the value the bridge writes to the door, the plain solution if no output is selected
+pub fn solution<R: crypto::Rng>(
+    salsa: &crypto::SalsaBox,
+    code: &[u8],
+    output: Option<u8>,
+) -> Result<Vec<u8>> {
+    let Some(output) = output else {
+        return Ok(code.to_vec());
+    };
+    if code.len() != chall::CHALL_SIZE {
+        return Err(Error::BufferLimit);
+    }
+    let mut request = OpenRequest {
+        code: [0u8; chall::CHALL_SIZE],
+        output,
+    };
+    request.code.copy_from_slice(code);
+    Ok(request.seal::<R>(salsa)?.to_vec())
+}
+
+/// The door side, verify a write and return the output that should be triggered
+pub fn verify(salsa: &crypto::SalsaBox, chall: &Challenge, buf: &[u8]) -> Result<u8> {
+    if buf.len() == chall::CHALL_SIZE {
+        chall.verify(buf)?;
+        return Ok(0);
+    }
+
+    let request = OpenRequest::open(salsa, buf)?;
+    chall.verify(&request.code)?;
+    Ok(request.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Setup;
+
+    #[test]
+    fn select_output() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let code = setup.solve(&chall);
+
+        let buf = solution::<crypto::Random>(&setup.bridge, &code, Some(2))?;
+        assert_eq!(buf.len(), REQUEST_ENCRYPTED_SIZE);
+        assert_eq!(verify(&setup.door, &chall, &buf)?, 2);
+
+        // the plain solution opens the first output
+        let buf = solution::<crypto::Random>(&setup.bridge, &code, None)?;
+        assert_eq!(buf, code);
+        assert_eq!(verify(&setup.door, &chall, &buf)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_modified_output() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let code = setup.solve(&chall);
+
+        let mut buf = solution::<crypto::Random>(&setup.bridge, &code, Some(1))?;
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(verify(&setup.door, &chall, &buf).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_wrong_solution() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let other = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let code = setup.solve(&other);
+
+        let buf = solution::<crypto::Random>(&setup.bridge, &code, Some(1))?;
+        assert!(verify(&setup.door, &chall, &buf).is_err());
+        Ok(())
+    }
+}
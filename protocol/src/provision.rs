@@ -0,0 +1,282 @@
+//! Updating the keys and settings of a door over bluetooth.
+//!
+//! The door sends a challenge encrypted for its current bridge key, the bridge replies with
+//! the new settings encrypted together with the solution. Only the current bridge can change
+//! the settings, and a recorded message can't be replayed.
+use crate::chall::{self, Challenge};
+use crate::crypto;
+use crate::errors::*;
+
+const MESSAGE_VERSION: u8 = 1;
+pub const MAX_MESSAGE_SIZE: usize = 256;
+pub const MAX_ENCRYPTED_SIZE: usize =
+    MAX_MESSAGE_SIZE + crypto::CRYPTO_NONCE_SIZE + crypto::CRYPTO_TAG_SIZE;
+/// The bluetooth name needs to fit into the advertisement
+pub const MAX_BLE_NAME_SIZE: usize = 29;
+
+const FIELD_DOOR_KEY: u8 = 1;
+const FIELD_BRIDGE_KEY: u8 = 2;
+const FIELD_BLE_NAME: u8 = 3;
+const FIELD_OUTPUTS: u8 = 4;
+const FIELD_CONTACT: u8 = 5;
+
+/// The settings to change, fields that are `None` are left as they are
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub door_key: Option<crypto::SecretKey>,
+    pub bridge_key: Option<crypto::PublicKey>,
+    pub ble_name: Option<String>,
+    /// Outputs in the format of `D3XS_OUTPUTS`
+    pub outputs: Option<String>,
+    /// Door contact in the format of `D3XS_CONTACT`
+    pub contact: Option<String>,
+}
+
+fn push_field(buf: &mut Vec<u8>, field: u8, value: &[u8]) -> Result<()> {
+    let len = u8::try_from(value.len()).map_err(|_| Error::BufferLimit)?;
+    buf.push(field);
+    buf.push(len);
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+fn key_bytes(value: &[u8]) -> Result<[u8; 32]> {
+    value
+        .try_into()
+        .map_err(|_| Error::InvalidKeyLength(value.len()))
+}
+
+fn text(value: &[u8]) -> Result<String> {
+    let s = core::str::from_utf8(value).map_err(|_| Error::InvalidField)?;
+    Ok(s.to_string())
+}
+
+impl Settings {
+    pub fn encode(&self, code: &[u8]) -> Result<Vec<u8>> {
+        if code.len() != chall::CHALL_SIZE {
+            return Err(Error::BufferLimit);
+        }
+
+        let mut buf = vec![MESSAGE_VERSION];
+        buf.extend_from_slice(code);
+        self.encode_fields(&mut buf)?;
+
+        if buf.len() > MAX_MESSAGE_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        Ok(buf)
+    }
+
+    /// Append the fields that are set, this is also used by the door to store its settings
+    pub fn encode_fields(&self, buf: &mut Vec<u8>) -> Result<()> {
+        if let Some(door_key) = &self.door_key {
+            push_field(buf, FIELD_DOOR_KEY, &door_key.to_bytes())?;
+        }
+        if let Some(bridge_key) = &self.bridge_key {
+            push_field(buf, FIELD_BRIDGE_KEY, bridge_key.as_bytes())?;
+        }
+        if let Some(ble_name) = &self.ble_name {
+            if ble_name.len() > MAX_BLE_NAME_SIZE {
+                return Err(Error::BufferLimit);
+            }
+            push_field(buf, FIELD_BLE_NAME, ble_name.as_bytes())?;
+        }
+        if let Some(outputs) = &self.outputs {
+            push_field(buf, FIELD_OUTPUTS, outputs.as_bytes())?;
+        }
+        if let Some(contact) = &self.contact {
+            push_field(buf, FIELD_CONTACT, contact.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Decode a message, returns the solution it has been bound to and the settings
+    pub fn decode(buf: &[u8]) -> Result<([u8; chall::CHALL_SIZE], Self)> {
+        let (&version, buf) = buf.split_first().ok_or(Error::BufferLimit)?;
+        if version != MESSAGE_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if buf.len() < chall::CHALL_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let (code, buf) = buf.split_at(chall::CHALL_SIZE);
+        let code = key_bytes(code)?;
+        let settings = Self::decode_fields(buf)?;
+        Ok((code, settings))
+    }
+
+    /// Decode the fields written by `encode_fields`
+    pub fn decode_fields(mut buf: &[u8]) -> Result<Self> {
+        let mut settings = Settings::default();
+        while let [field, len, rest @ ..] = buf {
+            let len = usize::from(*len);
+            if rest.len() < len {
+                return Err(Error::BufferLimit);
+            }
+            let (value, rest) = rest.split_at(len);
+            match *field {
+                FIELD_DOOR_KEY => {
+                    settings.door_key = Some(crypto::SecretKey::from(key_bytes(value)?));
+                }
+                FIELD_BRIDGE_KEY => {
+                    settings.bridge_key = Some(crypto::PublicKey::from(key_bytes(value)?));
+                }
+                FIELD_BLE_NAME => {
+                    if value.is_empty() || value.len() > MAX_BLE_NAME_SIZE {
+                        return Err(Error::InvalidField);
+                    }
+                    settings.ble_name = Some(text(value)?);
+                }
+                FIELD_OUTPUTS => settings.outputs = Some(text(value)?),
+                FIELD_CONTACT => settings.contact = Some(text(value)?),
+                _ => return Err(Error::InvalidField),
+            }
+            buf = rest;
+        }
+        if !buf.is_empty() {
+            return Err(Error::BufferLimit);
+        }
+        Ok(settings)
+    }
+
+    /// Apply an update on top of these settings, fields that are set in `update` are replaced
+    pub fn merge(&mut self, update: &Settings) {
+        if let Some(door_key) = &update.door_key {
+            self.door_key = Some(door_key.clone());
+        }
+        if let Some(bridge_key) = &update.bridge_key {
+            self.bridge_key = Some(bridge_key.clone());
+        }
+        if let Some(ble_name) = &update.ble_name {
+            self.ble_name = Some(ble_name.clone());
+        }
+        if let Some(outputs) = &update.outputs {
+            self.outputs = Some(outputs.clone());
+        }
+        if let Some(contact) = &update.contact {
+            self.contact = Some(contact.clone());
+        }
+    }
+
+    /// Encrypt the settings for the door, bound to the solution of its provisioning challenge
+    pub fn seal<R: crypto::Rng>(&self, salsa: &crypto::SalsaBox, code: &[u8]) -> Result<Vec<u8>> {
+        let buf = self.encode(code)?;
+        let mut encrypted = [0u8; MAX_ENCRYPTED_SIZE];
+        let encrypted = crypto::encrypt::<R>(salsa, &buf, &mut encrypted)?;
+        Ok(encrypted.to_vec())
+    }
+
+    /// The door side, decrypt the settings and verify they have been issued for this challenge
+    pub fn open(salsa: &crypto::SalsaBox, chall: &Challenge, encrypted: &[u8]) -> Result<Self> {
+        if encrypted.len() > MAX_ENCRYPTED_SIZE {
+            return Err(Error::BufferLimit);
+        }
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+        let buf = crypto::decrypt(salsa, encrypted, &mut buf)?;
+        let (code, settings) = Self::decode(buf)?;
+        chall.verify(&code)?;
+        Ok(settings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Setup;
+
+    #[test]
+    fn provision_settings() -> Result<()> {
+        let setup = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let code = setup.solve(&chall);
+
+        let door_key = crypto::generate_secret_key::<crypto::Random>();
+        let bridge_key = crypto::generate_secret_key::<crypto::Random>().public_key();
+        let settings = Settings {
+            door_key: Some(door_key.clone()),
+            bridge_key: Some(bridge_key.clone()),
+            ble_name: Some("d3xs1".to_string()),
+            outputs: Some("4:4:high,5:10:low".to_string()),
+            contact: Some("6:high".to_string()),
+        };
+        let encrypted = settings.seal::<crypto::Random>(&setup.bridge, &code)?;
+
+        let settings = Settings::open(&setup.door, &chall, &encrypted)?;
+        assert_eq!(settings.door_key.unwrap().to_bytes(), door_key.to_bytes());
+        assert_eq!(settings.bridge_key, Some(bridge_key));
+        assert_eq!(settings.ble_name.as_deref(), Some("d3xs1"));
+        assert_eq!(settings.outputs.as_deref(), Some("4:4:high,5:10:low"));
+        assert_eq!(settings.contact.as_deref(), Some("6:high"));
+        Ok(())
+    }
+
+    #[test]
+    fn partial_settings() -> Result<()> {
+        let settings = Settings {
+            ble_name: Some("d3xs2".to_string()),
+            ..Default::default()
+        };
+        let buf = settings.encode(&[7u8; chall::CHALL_SIZE])?;
+        let (code, settings) = Settings::decode(&buf)?;
+        assert_eq!(code, [7u8; chall::CHALL_SIZE]);
+        assert!(settings.door_key.is_none());
+        assert!(settings.bridge_key.is_none());
+        assert_eq!(settings.ble_name.as_deref(), Some("d3xs2"));
+        assert!(settings.outputs.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_other_bridge() -> Result<()> {
+        let setup = Setup::new();
+        let mallory = Setup::new();
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let code = setup.solve(&chall);
+
+        let settings = Settings {
+            ble_name: Some("pwned".to_string()),
+            ..Default::default()
+        };
+        let encrypted = settings.seal::<crypto::Random>(&mallory.bridge, &code)?;
+        assert!(Settings::open(&setup.door, &chall, &encrypted).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_replay() -> Result<()> {
+        let setup = Setup::new();
+        let old = Challenge::generate::<crypto::Random>(&setup.door)?;
+        let code = setup.solve(&old);
+        let encrypted = Settings::default().seal::<crypto::Random>(&setup.bridge, &code)?;
+        assert!(Settings::open(&setup.door, &old, &encrypted).is_ok());
+
+        let chall = Challenge::generate::<crypto::Random>(&setup.door)?;
+        assert!(Settings::open(&setup.door, &chall, &encrypted).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed() {
+        let code = [0u8; chall::CHALL_SIZE];
+        let mut buf = vec![MESSAGE_VERSION];
+        buf.extend_from_slice(&code);
+
+        let mut unknown = buf.clone();
+        unknown.extend_from_slice(&[99, 1, 0]);
+        assert!(Settings::decode(&unknown).is_err());
+
+        let mut truncated = buf.clone();
+        truncated.extend_from_slice(&[FIELD_BLE_NAME, 5, b'd']);
+        assert!(Settings::decode(&truncated).is_err());
+
+        let mut short_key = buf.clone();
+        short_key.extend_from_slice(&[FIELD_BRIDGE_KEY, 1, 0]);
+        assert!(Settings::decode(&short_key).is_err());
+
+        let long_name = Settings {
+            ble_name: Some("x".repeat(MAX_BLE_NAME_SIZE + 1)),
+            ..Default::default()
+        };
+        assert!(long_name.encode(&code).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Status the door reports on a separate characteristic, so the bridge can check on it.
+//!
+//! This is not authenticated, it's only used to display the health of a door.
+use crate::errors::*;
+
+// version 2 added the door contact
+const STATUS_VERSION: u8 = 2;
+const STATUS_HEADER_SIZE: usize = 1 + 4 + 4 + 4 + 1 + 4 + 4 + 1;
+pub const MAX_STATUS_SIZE: usize = STATUS_HEADER_SIZE + u8::MAX as usize;
+
+// used for fields that are not set
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    /// Version of the firmware
+    pub version: String,
+    /// Seconds since boot
+    pub uptime: u32,
+    /// Seconds since the door has last been opened, if it has been opened since boot
+    pub last_open: Option<u32>,
+    /// Number of failed attempts since boot
+    pub failed_attempts: u32,
+    /// State of the door contact, if a sensor is attached
+    pub contact: Option<Contact>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contact {
+    /// Seconds the door has been open for, `None` if it's closed
+    pub open_since: Option<u32>,
+    /// How often the door has been opened without being unlocked first, since boot
+    pub forced_entries: u32,
+}
+
+impl Contact {
+    pub fn is_open(&self) -> bool {
+        self.open_since.is_some()
+    }
+}
+
+impl Status {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let version = self.version.as_bytes();
+        let len = u8::try_from(version.len()).map_err(|_| Error::BufferLimit)?;
+
+        let mut buf = Vec::with_capacity(STATUS_HEADER_SIZE + version.len());
+        buf.push(STATUS_VERSION);
+        buf.extend_from_slice(&self.uptime.to_le_bytes());
+        buf.extend_from_slice(&self.last_open.unwrap_or(NONE).to_le_bytes());
+        buf.extend_from_slice(&self.failed_attempts.to_le_bytes());
+        if let Some(contact) = &self.contact {
+            buf.push(1);
+            buf.extend_from_slice(&contact.open_since.unwrap_or(NONE).to_le_bytes());
+            buf.extend_from_slice(&contact.forced_entries.to_le_bytes());
+        } else {
+            buf.push(0);
+            buf.extend_from_slice(&[0u8; 8]);
+        }
+        buf.push(len);
+        buf.extend_from_slice(version);
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let Some((&version, _)) = buf.split_first() else {
+            return Err(Error::BufferLimit);
+        };
+        if version != STATUS_VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if buf.len() < STATUS_HEADER_SIZE {
+            return Err(Error::BufferLimit);
+        }
+
+        let u32_at = |offset: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&buf[offset..offset + 4]);
+            u32::from_le_bytes(bytes)
+        };
+        let optional = |value: u32| Some(value).filter(|value| *value != NONE);
+        let uptime = u32_at(1);
+        let last_open = optional(u32_at(5));
+        let failed_attempts = u32_at(9);
+        let contact = match buf[13] {
+            0 => None,
+            1 => Some(Contact {
+                open_since: optional(u32_at(14)),
+                forced_entries: u32_at(18),
+            }),
+            _ => return Err(Error::InvalidField),
+        };
+
+        let len = usize::from(buf[22]);
+        let version = buf.get(STATUS_HEADER_SIZE..).ok_or(Error::BufferLimit)?;
+        if version.len() != len {
+            return Err(Error::BufferLimit);
+        }
+        let version = core::str::from_utf8(version).map_err(|_| Error::InvalidField)?;
+
+        Ok(Status {
+            version: version.to_string(),
+            uptime,
+            last_open,
+            failed_attempts,
+            contact,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_roundtrip() -> Result<()> {
+        let status = Status {
+            version: "0.1.0".to_string(),
+            uptime: 3600,
+            last_open: Some(120),
+            failed_attempts: 3,
+            contact: Some(Contact {
+                open_since: Some(30),
+                forced_entries: 1,
+            }),
+        };
+        assert_eq!(Status::decode(&status.encode()?)?, status);
+
+        let status = Status {
+            contact: Some(Contact {
+                open_since: None,
+                forced_entries: 0,
+            }),
+            ..status
+        };
+        assert_eq!(Status::decode(&status.encode()?)?, status);
+
+        let status = Status {
+            last_open: None,
+            contact: None,
+            ..status
+        };
+        assert_eq!(Status::decode(&status.encode()?)?, status);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed() -> Result<()> {
+        let buf = Status {
+            version: "0.1.0".to_string(),
+            uptime: 1,
+            last_open: None,
+            failed_attempts: 0,
+            contact: None,
+        }
+        .encode()?;
+
+        assert!(Status::decode(&[]).is_err());
+        assert!(Status::decode(&buf[..buf.len() - 1]).is_err());
+        // the layout without the door contact is not supported anymore
+        let mut version = buf.clone();
+        version[0] = 1;
+        assert!(matches!(
+            Status::decode(&version),
+            Err(Error::UnsupportedVersion(1))
+        ));
+        let mut contact = buf;
+        contact[13] = 7;
+        assert!(Status::decode(&contact).is_err());
+        Ok(())
+    }
+}
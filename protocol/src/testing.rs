@@ -0,0 +1,35 @@
+//! Fixtures for tests, shared with the firmware.
+use crate::chall::{Challenge, CHALL_SIZE};
+use crate::crypto;
+
+/// A bridge and a door that trust each other
+pub struct Setup {
+    /// The box the bridge uses to talk to the door
+    pub bridge: crypto::SalsaBox,
+    /// The box the door uses to talk to the bridge
+    pub door: crypto::SalsaBox,
+}
+
+impl Setup {
+    pub fn new() -> Self {
+        let bridge = crypto::generate_secret_key::<crypto::Random>();
+        let door = crypto::generate_secret_key::<crypto::Random>();
+        Setup {
+            bridge: crypto::SalsaBox::new(&door.public_key(), &bridge),
+            door: crypto::SalsaBox::new(&bridge.public_key(), &door),
+        }
+    }
+
+    /// Decrypt a challenge of the door, like the bridge does
+    pub fn solve(&self, chall: &Challenge) -> [u8; CHALL_SIZE] {
+        let mut code = [0u8; CHALL_SIZE];
+        crypto::decrypt(&self.bridge, &chall.encrypted, &mut code).unwrap();
+        code
+    }
+}
+
+impl Default for Setup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
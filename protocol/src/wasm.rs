@@ -1,4 +1,6 @@
+use crate::chall;
 use crate::crypto;
+use crate::errors::Error;
 use data_encoding::{BASE64, HEXLOWER_PERMISSIVE as HEX};
 use wasm_bindgen::prelude::*;
 
@@ -44,7 +46,7 @@ pub fn read_key_from_location() -> Option<crypto::SecretKey> {
         return None;
     };
 
-    let secret_key = crypto::secret_key(&key).ok()?;
+    let secret_key = crypto::secret_key(key).ok()?;
     Some(secret_key)
 }
 
@@ -79,7 +81,38 @@ pub fn write_solution_to_html(response: &str) -> Option<()> {
     Some(())
 }
 
-pub fn solve_challenge_to_html(secret_key: &crypto::SecretKey) -> Option<String> {
+/// What a challenge has been issued for, so the ui can check it's for the door the user picked
+#[wasm_bindgen(getter_with_clone)]
+pub struct ChallengeInfo {
+    /// The public key of the bridge, base64
+    pub bridge: String,
+    pub user: String,
+    pub door: String,
+    /// Unix timestamp of when the bridge has issued the challenge
+    pub issued: f64,
+    /// Why the challenge has not been solved, `wrong_door` or `expired`
+    pub refused: Option<String>,
+}
+
+impl From<chall::Binding> for ChallengeInfo {
+    fn from(binding: chall::Binding) -> Self {
+        ChallengeInfo {
+            bridge: BASE64.encode(&binding.bridge),
+            user: binding.user,
+            door: binding.door,
+            issued: binding.issued as f64,
+            refused: None,
+        }
+    }
+}
+
+/// Solve the challenge from the html, the solution is only written if it's for `door` and recent
+///
+/// Without a door, e.g. for a challenge that has been pasted by hand, only the time is checked.
+pub fn solve_challenge_to_html(
+    secret_key: &crypto::SecretKey,
+    door: Option<&str>,
+) -> Option<ChallengeInfo> {
     let public_key = read_public_key_from_html()?;
 
     let challenge = read_challenge_from_html()?;
@@ -92,14 +125,30 @@ pub fn solve_challenge_to_html(secret_key: &crypto::SecretKey) -> Option<String>
     };
 
     let salsa = crypto::SalsaBox::new(&public_key, secret_key);
+    let (binding, code) = chall::open(&salsa, &challenge).ok()?;
+    if binding.bridge != *public_key.as_bytes() {
+        console_log!("Challenge has been issued by a different bridge");
+        return None;
+    }
 
-    let mut decrypted = [0u8; 4096];
-    let decrypted = crypto::decrypt(&salsa, &challenge, &mut decrypted).ok()?;
-
-    let response = BASE64.encode(&decrypted);
-    write_solution_to_html(&response)?;
+    let now = (web_sys::js_sys::Date::now() / 1000.0) as u64;
+    let refused = match binding.check(door.unwrap_or(&binding.door), now) {
+        Ok(()) => None,
+        Err(Error::WrongDoor) => Some("wrong_door"),
+        Err(err) => {
+            console_log!("Refusing challenge: {err}");
+            Some("expired")
+        }
+    };
+    if refused.is_none() {
+        let response = BASE64.encode(&code);
+        write_solution_to_html(&response)?;
+    }
 
-    Some(response)
+    Some(ChallengeInfo {
+        refused: refused.map(String::from),
+        ..binding.into()
+    })
 }
 
 #[wasm_bindgen]
@@ -107,10 +156,24 @@ pub fn validate_key() -> bool {
     read_key_from_location().is_some()
 }
 
+/// Solve the challenge from the html if it's for `door`, returns what it has been issued for
 #[wasm_bindgen]
-pub fn solve_challenge() -> bool {
-    let Some(key) = read_key_from_location() else {
-        return false;
-    };
-    solve_challenge_to_html(&key).is_some()
+pub fn solve_challenge(door: Option<String>) -> Option<ChallengeInfo> {
+    let key = read_key_from_location()?;
+    solve_challenge_to_html(&key, door.as_deref())
+}
+
+/// Decrypt a challenge a door has sent for an offline open, base64 in and out
+#[wasm_bindgen]
+pub fn solve_offline_challenge(challenge: &str, door_public_key: &str) -> Option<String> {
+    let secret_key = read_key_from_location()?;
+    let public_key = crypto::public_key(door_public_key).ok()?;
+    let challenge = BASE64.decode(challenge.as_bytes()).ok()?;
+
+    let salsa = crypto::SalsaBox::new(&public_key, &secret_key);
+
+    let mut decrypted = [0u8; 4096];
+    let decrypted = crypto::decrypt(&salsa, &challenge, &mut decrypted).ok()?;
+
+    Some(BASE64.encode(decrypted))
 }
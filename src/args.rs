@@ -1,12 +1,22 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
 #[derive(Debug, clap::Parser)]
 pub struct Args {
-    /// Shared secret to authenticate the bridge
-    pub uuid: String,
+    /// Shared secret to authenticate the bridge (for a single bridge serving users at `/<user>`)
+    pub uuid: Option<String>,
+    /// Public key of the bridge, it needs to prove it has the secret key before it's accepted
+    #[arg(long, env = "D3XS_BRIDGE_PUBLIC_KEY")]
+    pub bridge_key: Option<String>,
+    /// Load additional bridges from a config file
+    #[arg(short, long, env = "D3XS_CONFIG")]
+    pub config: Option<PathBuf>,
     /// Bind to this address for incoming connections
     #[arg(short = 'B', long, env = "D3XS_BIND")]
     pub bind: SocketAddr,
+    /// Serve prometheus metrics on this address, e.g. `127.0.0.1:9100`
+    #[arg(long, env = "D3XS_METRICS_BIND")]
+    pub metrics: Option<SocketAddr>,
     /// Increase logging output (can be used multiple times)
     #[arg(short, long, global = true, action(clap::ArgAction::Count))]
     pub verbose: u8,
@@ -0,0 +1,79 @@
+use crate::errors::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+
+// these would collide with other routes of the webserver
+const RESERVED_NAMES: &[&str] = &["assets", "bridge"];
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub bridges: HashMap<String, Bridge>,
+}
+
+impl Config {
+    pub async fn load_from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let buf = fs::read_to_string(path)
+            .await
+            .with_context(|| anyhow!("Failed to load config from {path:?}"))?;
+        Self::parse(&buf)
+    }
+
+    pub fn parse(buf: &str) -> Result<Self> {
+        let config = toml::from_str::<Self>(buf).context("Failed to load toml as config")?;
+        for name in config.bridges.keys() {
+            if RESERVED_NAMES.contains(&name.as_str()) {
+                bail!("Bridge name is reserved: {name:?}");
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct Bridge {
+    /// Shared secret to authenticate the bridge
+    pub uuid: String,
+    /// Public key of the bridge, the `[system]` key in its config
+    pub public_key: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bridges() -> Result<()> {
+        let config = Config::parse(
+            r#"[bridges.main]
+uuid = "2120a559-2fbd-4595-be57-4e78changeme"
+
+[bridges.annex]
+uuid = "b5a4bb0c-0a4e-4b0c-8a0e-5c6fchangeme"
+public_key = "uI8Tc7YbtR3WewfwOUta8CimjmVvOxRydjf3EA1LhUQ="
+"#,
+        )?;
+        assert_eq!(config.bridges.len(), 2);
+        assert_eq!(
+            config.bridges["annex"],
+            Bridge {
+                uuid: "b5a4bb0c-0a4e-4b0c-8a0e-5c6fchangeme".to_string(),
+                public_key: Some("uI8Tc7YbtR3WewfwOUta8CimjmVvOxRydjf3EA1LhUQ=".to_string()),
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_reserved_names() {
+        let err = Config::parse(
+            r#"[bridges.assets]
+uuid = "2120a559-2fbd-4595-be57-4e78changeme"
+"#,
+        );
+        assert!(err.is_err());
+    }
+}
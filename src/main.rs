@@ -1,12 +1,15 @@
 pub mod args;
 pub mod assets;
+pub mod config;
 pub mod errors;
+pub mod metrics;
+pub mod sites;
 pub mod ws;
 
 use crate::args::Args;
 use crate::errors::*;
+use crate::sites::{Sites, UserPath};
 use clap::Parser;
-use d3xs_protocol::ipc;
 use env_logger::Env;
 use handlebars::Handlebars;
 use serde_json::json;
@@ -14,8 +17,6 @@ use std::borrow::Cow;
 use std::env;
 use std::sync::Arc;
 use tokio::fs;
-use tokio::sync::broadcast;
-use tokio::sync::RwLock;
 use warp::{http::Response, http::StatusCode, Filter};
 
 async fn resolve_asset<'a>(
@@ -105,16 +106,19 @@ async fn show_wasm() -> Result<Box<dyn warp::Reply>, warp::Rejection> {
 }
 
 async fn show_page(
-    config: Arc<RwLock<Option<ipc::Config>>>,
+    sites: Arc<Sites>,
     hb: Arc<Handlebars<'_>>,
-    user: String,
+    path: UserPath,
 ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
-    let config = config.read().await;
+    let Some(site) = sites.get(path.site.as_deref()) else {
+        return Ok(Box::new(StatusCode::NOT_FOUND));
+    };
+    let config = site.config.read().await;
 
     let Some(config) = config.as_ref() else {
         return Ok(Box::new(StatusCode::NOT_FOUND));
     };
-    let Some(_config) = config.users.get(&user) else {
+    let Some(_config) = config.users.get(&path.user) else {
         return Ok(Box::new(StatusCode::NOT_FOUND));
     };
     let html = match hb.render(
@@ -147,20 +151,29 @@ async fn main() -> Result<()> {
     };
     env_logger::init_from_env(Env::default().default_filter_or(log_level));
 
-    let config = Arc::new(RwLock::new(None));
-    let config = warp::any().map(move || config.clone());
-
-    let uuid = Arc::new(args.uuid);
-    let uuid = warp::any().map(move || uuid.clone());
-
-    let request_tx = {
-        let (tx, _rx) = broadcast::channel(16);
-        warp::any().map(move || tx.clone())
-    };
-    let event_tx = {
-        let (tx, _rx) = broadcast::channel(16);
-        warp::any().map(move || tx.clone())
+    let config = if let Some(path) = &args.config {
+        config::Config::load_from_path(path).await?
+    } else {
+        config::Config::default()
     };
+    let sites = Arc::new(Sites::new(args.uuid, args.bridge_key.as_deref(), config)?);
+
+    // metrics are served on a separate address, so they don't need to be public
+    if let Some(addr) = args.metrics {
+        let sites = sites.clone();
+        let show_metrics = warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .map(move || {
+                Response::builder()
+                    .header("content-type", "text/plain; version=0.0.4")
+                    .body(metrics::render(&sites))
+            });
+        info!("Serving metrics at http://{addr}/metrics");
+        tokio::spawn(warp::serve(show_metrics).run(addr));
+    }
+
+    let sites = warp::any().map(move || sites.clone());
 
     let mut hb = Handlebars::new();
     hb.register_template_string("index.html", include_str!("index.html"))
@@ -169,10 +182,9 @@ async fn main() -> Result<()> {
     let hb = warp::any().map(move || hb.clone());
 
     let show_page = warp::get()
-        .and(config.clone())
+        .and(sites.clone())
         .and(hb)
-        .and(warp::path::param())
-        .and(warp::path::end())
+        .and(sites::user_path())
         .and_then(show_page);
     let show_script = warp::get()
         .and(warp::path("assets"))
@@ -205,18 +217,12 @@ async fn main() -> Result<()> {
         .and(warp::path::end())
         .and_then(show_wasm);
     let ws_user = warp::get()
-        .and(config.clone())
-        .and(event_tx.clone())
-        .and(request_tx.clone())
-        .and(warp::path::param())
-        .and(warp::path::end())
+        .and(sites.clone())
+        .and(sites::user_path())
         .and(warp::ws())
         .and_then(ws::user::websocket);
     let ws_bridge = warp::get()
-        .and(uuid)
-        .and(config)
-        .and(event_tx)
-        .and(request_tx)
+        .and(sites)
         .and(warp::path("bridge"))
         .and(warp::path::param())
         .and(warp::path::end())
@@ -230,8 +236,8 @@ async fn main() -> Result<()> {
             .or(show_appicon)
             .or(show_wasm)
             .or(show_wasm_bindgen)
-            .or(ws_user)
             .or(ws_bridge)
+            .or(ws_user)
             .or(show_page),
     );
 
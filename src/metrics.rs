@@ -0,0 +1,151 @@
+use crate::sites::Sites;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of a single site, rendered in the prometheus text format at `/metrics`
+#[derive(Debug, Default)]
+pub struct Metrics {
+    bridges_connected: AtomicU64,
+    pub challenges_relayed: AtomicU64,
+    pub malformed_json_bridge: AtomicU64,
+    pub malformed_json_user: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Mark the bridge as connected until the guard is dropped
+    pub fn bridge_connected(&self) -> BridgeConnected<'_> {
+        self.bridges_connected.fetch_add(1, Ordering::Relaxed);
+        BridgeConnected(self)
+    }
+}
+
+pub struct BridgeConnected<'a>(&'a Metrics);
+
+impl Drop for BridgeConnected<'_> {
+    fn drop(&mut self) {
+        self.0.bridges_connected.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").ok();
+    writeln!(out, "# TYPE {name} {kind}").ok();
+}
+
+pub fn render(sites: &Sites) -> String {
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "d3xs_user_sockets",
+        "gauge",
+        "Connected websocket clients",
+    );
+    for site in sites.iter() {
+        let site_label = escape(site.label());
+        let value = site.registry.sessions();
+        writeln!(out, "d3xs_user_sockets{{site=\"{site_label}\"}} {value}").ok();
+    }
+
+    header(
+        &mut out,
+        "d3xs_bridge_connected",
+        "gauge",
+        "Whether the bridge is connected",
+    );
+    for site in sites.iter() {
+        let site_label = escape(site.label());
+        let connected = site.metrics.bridges_connected.load(Ordering::Relaxed) > 0;
+        let value = u8::from(connected);
+        writeln!(
+            out,
+            "d3xs_bridge_connected{{site=\"{site_label}\"}} {value}"
+        )
+        .ok();
+    }
+
+    header(
+        &mut out,
+        "d3xs_challenges_relayed_total",
+        "counter",
+        "Challenges relayed from the bridge to users",
+    );
+    for site in sites.iter() {
+        let site_label = escape(site.label());
+        let value = site.metrics.challenges_relayed.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "d3xs_challenges_relayed_total{{site=\"{site_label}\"}} {value}"
+        )
+        .ok();
+    }
+
+    header(
+        &mut out,
+        "d3xs_malformed_json_total",
+        "counter",
+        "Websocket messages that failed to parse",
+    );
+    for site in sites.iter() {
+        let site_label = escape(site.label());
+        for (source, counter) in [
+            ("bridge", &site.metrics.malformed_json_bridge),
+            ("user", &site.metrics.malformed_json_user),
+        ] {
+            let value = counter.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "d3xs_malformed_json_total{{site=\"{site_label}\",source=\"{source}\"}} {value}"
+            )
+            .ok();
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+    use crate::errors::*;
+
+    #[test]
+    fn render_metrics() -> Result<()> {
+        let config = config::Config::parse(
+            r#"[bridges.annex]
+uuid = "b5a4bb0c-0a4e-4b0c-8a0e-5c6fchangeme"
+"#,
+        )?;
+        let sites = Sites::new(None, None, config)?;
+        let annex = sites.get(Some("annex")).unwrap();
+
+        let _session = annex.registry.register("alice".to_string());
+        let connected = annex.metrics.bridge_connected();
+        Metrics::inc(&annex.metrics.challenges_relayed);
+        Metrics::inc(&annex.metrics.malformed_json_user);
+
+        let metrics = render(&sites);
+        assert!(metrics.contains("d3xs_user_sockets{site=\"annex\"} 1\n"));
+        assert!(metrics.contains("d3xs_bridge_connected{site=\"annex\"} 1\n"));
+        assert!(metrics.contains("d3xs_challenges_relayed_total{site=\"annex\"} 1\n"));
+        assert!(metrics.contains("d3xs_malformed_json_total{site=\"annex\",source=\"bridge\"} 0\n"));
+        assert!(metrics.contains("d3xs_malformed_json_total{site=\"annex\",source=\"user\"} 1\n"));
+
+        drop(connected);
+        let metrics = render(&sites);
+        assert!(metrics.contains("d3xs_bridge_connected{site=\"annex\"} 0\n"));
+        Ok(())
+    }
+}
@@ -1,6 +1,31 @@
 let wasm = null;
 let ws = null;
 
+// used to open doors over bluetooth when the server is unreachable
+const BLE_SERVICE = 0xffff;
+const BLE_OFFLINE_CHARACTERISTIC = 0xaaab;
+
+// shown on the slider after the bridge has tried to open the door
+const OUTCOME_LABELS = {
+    opened: 'OPEN',
+    ble_unreachable: 'NO SIGNAL',
+    timeout: 'TIMEOUT',
+    unauthorized: 'DENIED',
+    invalid_solution: 'FAILED',
+    rejected: 'REJECTED',
+    wrong_door: 'WRONG DOOR',
+    expired: 'EXPIRED',
+};
+const OUTCOME_DURATION = 3000;
+
+function decodeBase64(data) {
+    return Uint8Array.from(atob(data), c => c.charCodeAt(0));
+}
+
+function encodeBase64(bytes) {
+    return btoa(String.fromCharCode(...bytes));
+}
+
 export default function() {
     let xTouchDown = null;
     const container = document.getElementById('container');
@@ -11,8 +36,44 @@ export default function() {
     const response = document.getElementById('response');
 
     let pendingChallenge = null;
+    let offlineDoors = {};
+    let sliders = {};
+    const configCacheKey = 'd3xs-config:' + document.location.pathname;
 
-    function createSlider(key, label) {
+    async function openOffline(key) {
+        const door = offlineDoors[key];
+        console.log('opening door over bluetooth:', key);
+
+        const device = await navigator.bluetooth.requestDevice({
+            filters: [{ services: [BLE_SERVICE] }],
+        });
+        const server = await device.gatt.connect();
+        try {
+            const service = await server.getPrimaryService(BLE_SERVICE);
+            const characteristic = await service.getCharacteristic(BLE_OFFLINE_CHARACTERISTIC);
+
+            try {
+                await characteristic.writeValueWithResponse(decodeBase64(door['token']));
+            } catch (err) {
+                // during a key rotation the door may already trust the next bridge key
+                if (!door['next_token']) {
+                    throw err;
+                }
+                await characteristic.writeValueWithResponse(decodeBase64(door['next_token']));
+            }
+            const chall = await characteristic.readValue();
+            const code = wasm.solve_offline_challenge(encodeBase64(new Uint8Array(chall.buffer)), door['public_key']);
+            if (!code) {
+                console.log('Web assembly failed to decrypt');
+                return;
+            }
+            await characteristic.writeValueWithResponse(decodeBase64(code));
+        } finally {
+            server.disconnect();
+        }
+    }
+
+    function createSlider(key, label, unreachable) {
         const slider = document.createElement('div');
         slider.className = 'slider';
 
@@ -50,7 +111,7 @@ export default function() {
 
         function dragRelease() {
             if (execute) {
-                if (ws) {
+                if (ws && ws.readyState === WebSocket.OPEN) {
                     const msg = JSON.stringify({
                         "type": "fetch",
                         "door": key,
@@ -58,6 +119,8 @@ export default function() {
                     pendingChallenge = key;
                     console.log('send cmd to websocket:', msg);
                     ws.send(msg);
+                } else if (offlineDoors[key] && navigator.bluetooth) {
+                    openOffline(key).catch(err => console.log('Bluetooth error:', err));
                 }
             }
 
@@ -66,6 +129,15 @@ export default function() {
             updateSlider(0);
         }
 
+        sliders[key] = function(outcome) {
+            slider.textContent = OUTCOME_LABELS[outcome] || outcome;
+            slider.classList.add(outcome === 'opened' ? 'opened' : 'failed');
+            setTimeout(function() {
+                slider.classList.remove('opened', 'failed');
+                updateSlider(0);
+            }, OUTCOME_DURATION);
+        };
+
         // touch events
         slider.addEventListener('touchstart', function(event) {
             xTouchDown = event.touches[0].clientX;
@@ -98,10 +170,32 @@ export default function() {
         updateSlider(0);
         const h1 = document.createElement('h1');
         h1.textContent = label;
+        if (unreachable) {
+            const status = document.createElement('span');
+            status.className = 'unreachable';
+            status.textContent = ' (door offline)';
+            h1.appendChild(status);
+        }
         container.appendChild(h1);
         container.appendChild(slider);
     }
 
+    function showConfig(data) {
+        while (container.firstChild) {
+            container.removeChild(container.lastChild);
+        }
+
+        public_key.value = data['public_key'];
+        offlineDoors = {};
+        sliders = {};
+        data['doors'].forEach(data => {
+            if (data['offline']) {
+                offlineDoors[data['id']] = data['offline'];
+            }
+            createSlider(data['id'], data['label'], data['unreachable']);
+        });
+    }
+
     function connect() {
         const websocketUrl = (document.location.protocol === 'https:' ? 'wss://' : 'ws://') + document.location.host + document.location.pathname;
         ws = new WebSocket(websocketUrl);
@@ -118,11 +212,21 @@ export default function() {
                 // put challenge to html
                 challenge.value = data['challenge'];
 
-                // invoke web assembly
-                if (!wasm.solve_challenge()) {
+                // invoke web assembly, it only approves the door the user has asked for
+                const info = wasm.solve_challenge(pendingChallenge);
+                if (!info) {
                     console.log("Web assembly failed to decrypt");
                     return;
                 }
+                if (info.refused) {
+                    console.log('Refusing challenge:', info.refused, info.door, pendingChallenge);
+                    const showResult = sliders[pendingChallenge];
+                    pendingChallenge = null;
+                    if (showResult) {
+                        showResult(info.refused);
+                    }
+                    return;
+                }
 
                 // read respnse
                 const code = response.value;
@@ -135,15 +239,15 @@ export default function() {
                 pendingChallenge = null;
                 console.log('send cmd to websocket:', msg);
                 ws.send(msg);
-            } else if (data['type'] === 'config') {
-                while (container.firstChild) {
-                    container.removeChild(container.lastChild);
+            } else if (data['type'] === 'open_result') {
+                console.log('result from bridge:', data['door'], data['outcome']);
+                const showResult = sliders[data['door']];
+                if (showResult) {
+                    showResult(data['outcome']);
                 }
-
-                public_key.value = data['public_key'];
-                data['doors'].forEach(data => {
-                    createSlider(data['id'], data['label']);
-                });
+            } else if (data['type'] === 'config') {
+                localStorage.setItem(configCacheKey, event.data);
+                showConfig(data);
             }
         };
 
@@ -167,6 +271,11 @@ export default function() {
         reconnectTimeout = setTimeout(connect, reconnectInterval);
     }
 
+    // show the last known doors until the websocket is connected
+    const cachedConfig = localStorage.getItem(configCacheKey);
+    if (cachedConfig) {
+        showConfig(JSON.parse(cachedConfig));
+    }
     connect();
 
     status.textContent = 'crypto: STARTING';
@@ -188,7 +297,11 @@ export default function() {
         });
 
         challenge.addEventListener('change', function() {
-            wasm.solve_challenge();
+            // pasted by hand, there's no door to compare with
+            const info = wasm.solve_challenge(null);
+            if (info) {
+                console.log('Challenge has been issued for door:', info.door, info.refused);
+            }
         });
 
         window.addEventListener('hashchange', validate_key);
@@ -0,0 +1,192 @@
+use crate::config;
+use crate::errors::*;
+use crate::metrics::Metrics;
+use crate::ws::registry::Registry;
+use d3xs_protocol::crypto;
+use d3xs_protocol::ipc;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, RwLock};
+use warp::Filter;
+
+/// Everything related to a single bridge and its users
+#[derive(Debug)]
+pub struct Site {
+    pub name: Option<String>,
+    pub uuid: String,
+    /// If set, the bridge needs to prove it has the secret key before it's accepted
+    pub public_key: Option<crypto::PublicKey>,
+    pub config: RwLock<Option<ipc::Config>>,
+    pub registry: Arc<Registry>,
+    pub request_tx: broadcast::Sender<ipc::ClientRequest>,
+    pub metrics: Metrics,
+}
+
+impl Site {
+    pub fn new(name: Option<String>, uuid: String, public_key: Option<&str>) -> Result<Self> {
+        let public_key = public_key
+            .map(|key| crypto::public_key(key).map_err(|_| anyhow!("Failed to decode public key")))
+            .transpose()?;
+        if public_key.is_none() {
+            warn!(
+                "Bridge is only authenticated by its uuid, consider configuring its public key (site={:?})",
+                name.as_deref().unwrap_or("default")
+            );
+        }
+
+        let (request_tx, _rx) = broadcast::channel(16);
+        Ok(Site {
+            name,
+            uuid,
+            public_key,
+            config: RwLock::new(None),
+            registry: Arc::new(Registry::default()),
+            request_tx,
+            metrics: Metrics::default(),
+        })
+    }
+
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or("default")
+    }
+}
+
+/// All configured bridges, the default one serves users at `/<user>`, named ones at `/<name>/<user>`
+#[derive(Debug, Default)]
+pub struct Sites {
+    default: Option<Arc<Site>>,
+    named: HashMap<String, Arc<Site>>,
+}
+
+impl Sites {
+    pub fn new(
+        uuid: Option<String>,
+        public_key: Option<&str>,
+        config: config::Config,
+    ) -> Result<Self> {
+        let default = uuid
+            .map(|uuid| Site::new(None, uuid, public_key))
+            .transpose()?
+            .map(Arc::new);
+        let mut sites = Sites {
+            default,
+            named: HashMap::new(),
+        };
+
+        for (name, bridge) in config.bridges {
+            if sites.by_uuid(&bridge.uuid).is_some() {
+                bail!("Bridge uuid is used more than once: {name:?}");
+            }
+            let site = Site::new(
+                Some(name.clone()),
+                bridge.uuid,
+                bridge.public_key.as_deref(),
+            )
+            .with_context(|| anyhow!("Failed to configure bridge {name:?}"))?;
+            sites.named.insert(name, Arc::new(site));
+        }
+
+        if sites.is_empty() {
+            bail!("No bridges have been configured");
+        }
+
+        Ok(sites)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.named.is_empty()
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Option<&Arc<Site>> {
+        if let Some(name) = name {
+            self.named.get(name)
+        } else {
+            self.default.as_ref()
+        }
+    }
+
+    pub fn by_uuid(&self, uuid: &str) -> Option<&Arc<Site>> {
+        self.iter().find(|site| site.uuid == uuid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Site>> {
+        self.default.iter().chain(self.named.values())
+    }
+}
+
+/// The path a user's page is served at
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserPath {
+    pub site: Option<String>,
+    pub user: String,
+}
+
+pub fn user_path() -> impl Filter<Extract = (UserPath,), Error = warp::Rejection> + Clone {
+    let default = warp::path::param()
+        .and(warp::path::end())
+        .map(|user| UserPath { site: None, user });
+    let named = warp::path::param()
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .map(|site, user| UserPath {
+            site: Some(site),
+            user,
+        });
+    default.or(named).unify()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_sites() -> Result<()> {
+        let config = config::Config::parse(
+            r#"[bridges.annex]
+uuid = "b5a4bb0c-0a4e-4b0c-8a0e-5c6fchangeme"
+public_key = "cW49lkXDeM0wOT8N7QxAWePmWs8xZK1FXt1uQT/pcG4="
+"#,
+        )?;
+        let sites = Sites::new(
+            Some("2120a559-2fbd-4595-be57-4e78changeme".to_string()),
+            None,
+            config,
+        )?;
+
+        let default = sites.get(None).unwrap();
+        assert_eq!(default.name, None);
+        assert!(default.public_key.is_none());
+        let annex = sites.get(Some("annex")).unwrap();
+        assert_eq!(annex.uuid, "b5a4bb0c-0a4e-4b0c-8a0e-5c6fchangeme");
+        assert!(annex.public_key.is_some());
+        assert!(sites.get(Some("main")).is_none());
+
+        let site = sites
+            .by_uuid("b5a4bb0c-0a4e-4b0c-8a0e-5c6fchangeme")
+            .unwrap();
+        assert_eq!(site.name.as_deref(), Some("annex"));
+        assert!(sites.by_uuid("changeme").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_duplicate_uuid() -> Result<()> {
+        let config = config::Config::parse(
+            r#"[bridges.annex]
+uuid = "2120a559-2fbd-4595-be57-4e78changeme"
+"#,
+        )?;
+        let sites = Sites::new(
+            Some("2120a559-2fbd-4595-be57-4e78changeme".to_string()),
+            None,
+            config,
+        );
+        assert!(sites.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_empty() {
+        assert!(Sites::new(None, None, config::Config::default()).is_err());
+    }
+}
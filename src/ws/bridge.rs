@@ -1,52 +1,130 @@
 use crate::errors::*;
+use crate::metrics::Metrics;
+use crate::sites::{Site, Sites};
 use crate::ws;
+use d3xs_protocol::auth;
+use d3xs_protocol::crypto;
 use d3xs_protocol::ipc;
+use data_encoding::BASE64;
 use futures_util::{FutureExt, SinkExt, StreamExt};
 use std::sync::Arc;
 use tokio::sync::broadcast;
-use tokio::sync::RwLock;
-use tokio::time;
+use tokio::time::{self, Duration};
 use warp::http::StatusCode;
 use warp::ws::Message;
 use warp::ws::WebSocket;
 
+// bridges that don't authenticate in time get disconnected
+const AUTH_TIMEOUT: Duration = Duration::from_secs(15);
+
+async fn update_config(site: &Site, data: ipc::Config) {
+    let mut config = site.config.write().await;
+    info!(
+        "Bridge has connected (site={:?}, public_key={:?})",
+        site.label(),
+        data.public_key
+    );
+    *config = Some(data);
+    site.registry.broadcast(ipc::Event::Config);
+}
+
+async fn request_auth(
+    ws: &mut WebSocket,
+    bridge_key: &crypto::PublicKey,
+) -> Result<auth::ServerChallenge> {
+    let auth = auth::ServerChallenge::generate::<crypto::Random>(bridge_key)
+        .map_err(|_| anyhow!("Failed to generate challenge"))?;
+    let request = ipc::ClientRequest::Auth(ipc::Auth {
+        public_key: BASE64.encode(auth.public_key().as_bytes()),
+        challenge: BASE64.encode(auth.encrypted()),
+    });
+    let data = serde_json::to_string(&request)?;
+    ws.send(Message::text(data)).await?;
+    Ok(auth)
+}
+
 async fn ws_connect(
     mut ws: WebSocket,
-    config: Arc<RwLock<Option<ipc::Config>>>,
-    event_tx: broadcast::Sender<ipc::Event>,
+    site: Arc<Site>,
     mut request_rx: broadcast::Receiver<ipc::ClientRequest>,
 ) -> Result<()> {
     let mut ping = time::interval(ws::WS_PING_INTERVAL);
 
+    let auth = if let Some(bridge_key) = &site.public_key {
+        Some(request_auth(&mut ws, bridge_key).await?)
+    } else {
+        None
+    };
+    // the config is only accepted after the bridge has proven it has the secret key
+    let mut authenticated = auth.is_none();
+    // held until the connection is closed
+    let mut _connected = authenticated.then(|| site.metrics.bridge_connected());
+    let mut pending_config = None;
+    let auth_timeout = time::sleep(AUTH_TIMEOUT);
+    tokio::pin!(auth_timeout);
+
     loop {
         tokio::select! {
             // ping clients at interval
             _ = ping.tick() => ws.send(Message::ping(vec![])).await?,
+            _ = &mut auth_timeout, if !authenticated => {
+                bail!("Bridge did not authenticate in time (site={:?})", site.label());
+            }
             // forward all messages from websocket clients to bridge
-            msg = request_rx.recv() => if let Ok(msg) = msg {
-                let data = serde_json::to_string(&msg)?;
-                ws.send(Message::text(data)).await?;
-            } else {
-                return Ok(());
+            msg = request_rx.recv() => match msg {
+                Ok(_) if !authenticated => (),
+                Ok(msg) => {
+                    let data = serde_json::to_string(&msg)?;
+                    ws.send(Message::text(data)).await?;
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Bridge connection is too slow, dropped {n} requests (site={:?})", site.label());
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
             },
-            // receive messages from bridge (config updates and challenges)
+            // receive messages from bridge (config updates, challenges and results)
             msg = ws.next() => if let Some(msg) = msg {
                 let Ok(msg) = msg else { continue };
                 let Ok(msg) = msg.to_str() else { continue };
                 let Ok(event) = serde_json::from_str::<ipc::BridgeResponse>(msg) else {
                     warn!("bridge sent malformed json");
+                    Metrics::inc(&site.metrics.malformed_json_bridge);
                     continue;
                 };
                 match event {
-                    ipc::BridgeResponse::Config(data) => {
-                        let mut config = config.write().await;
-                        info!("Bridge has connected (public_key={:?})", data.public_key);
-                        *config = Some(data);
-                        event_tx.send(ipc::Event::Config).ok();
+                    ipc::BridgeResponse::Config(data) => if authenticated {
+                        update_config(&site, data).await;
+                    } else {
+                        pending_config = Some(data);
+                    },
+                    ipc::BridgeResponse::Challenge(chall) => if authenticated {
+                        let user = chall.user.clone();
+                        site.registry.send_to(&user, ipc::Event::Challenge(chall));
+                        Metrics::inc(&site.metrics.challenges_relayed);
+                    } else {
+                        warn!("Bridge sent challenge before authenticating");
                     },
-                    ipc::BridgeResponse::Challenge(chall) => {
-                        // TODO: find a more efficient way than broadcasting the challenge to all connected clients
-                        event_tx.send(ipc::Event::Challenge(chall)).ok();
+                    ipc::BridgeResponse::OpenResult(result) => if authenticated {
+                        let user = result.user.clone();
+                        site.registry.send_to(&user, ipc::Event::OpenResult(result));
+                    },
+                    ipc::BridgeResponse::Auth(response) => {
+                        let Some(auth) = &auth else { continue };
+                        if authenticated {
+                            continue;
+                        }
+
+                        let code = BASE64.decode(response.code.as_bytes()).unwrap_or_default();
+                        if auth.verify(&code).is_err() {
+                            bail!("Bridge failed to authenticate (site={:?})", site.label());
+                        }
+                        debug!("Bridge has authenticated (site={:?})", site.label());
+                        authenticated = true;
+                        _connected = Some(site.metrics.bridge_connected());
+
+                        if let Some(data) = pending_config.take() {
+                            update_config(&site, data).await;
+                        }
                     }
                 }
             } else {
@@ -57,21 +135,18 @@ async fn ws_connect(
 }
 
 pub async fn websocket(
-    uuid: Arc<String>,
-    config: Arc<RwLock<Option<ipc::Config>>>,
-    event_tx: broadcast::Sender<ipc::Event>,
-    request_tx: broadcast::Sender<ipc::ClientRequest>,
-    bridge: String,
+    sites: Arc<Sites>,
+    uuid: String,
     ws: warp::ws::Ws,
 ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
-    if bridge != uuid.as_str() {
+    let Some(site) = sites.by_uuid(&uuid).cloned() else {
         return Ok(Box::new(StatusCode::NOT_FOUND));
-    }
-    debug!("Received bridge connection");
+    };
+    debug!("Received bridge connection (site={:?})", site.label());
 
-    let request_rx = request_tx.subscribe();
+    let request_rx = site.request_tx.subscribe();
     let reply = ws.on_upgrade(move |websocket| {
-        ws_connect(websocket, config, event_tx, request_rx).map(|result| {
+        ws_connect(websocket, site, request_rx).map(|result| {
             if let Err(err) = result {
                 error!("bridge websocket error: {err:#}");
             }
@@ -1,4 +1,5 @@
 pub mod bridge;
+pub mod registry;
 pub mod user;
 
 use tokio::time::Duration;
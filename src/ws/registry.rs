@@ -0,0 +1,126 @@
+use d3xs_protocol::ipc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+type Senders = HashMap<u64, mpsc::UnboundedSender<ipc::Event>>;
+
+/// Connected websocket clients, by user
+#[derive(Debug, Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    users: Mutex<HashMap<String, Senders>>,
+}
+
+impl Registry {
+    pub fn register(self: &Arc<Self>, user: String) -> Session {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let mut users = self.users.lock().unwrap();
+        users.entry(user.clone()).or_default().insert(id, tx);
+
+        Session {
+            id,
+            user,
+            registry: self.clone(),
+            rx,
+        }
+    }
+
+    fn unregister(&self, user: &str, id: u64) {
+        let mut users = self.users.lock().unwrap();
+        if let Some(sessions) = users.get_mut(user) {
+            sessions.remove(&id);
+            if sessions.is_empty() {
+                users.remove(user);
+            }
+        }
+    }
+
+    /// Send an event to all sessions of a specific user
+    pub fn send_to(&self, user: &str, event: ipc::Event) {
+        let users = self.users.lock().unwrap();
+        if let Some(sessions) = users.get(user) {
+            for tx in sessions.values() {
+                tx.send(event.clone()).ok();
+            }
+        }
+    }
+
+    /// Send an event to every connected session
+    pub fn broadcast(&self, event: ipc::Event) {
+        let users = self.users.lock().unwrap();
+        for tx in users.values().flat_map(|sessions| sessions.values()) {
+            tx.send(event.clone()).ok();
+        }
+    }
+
+    pub fn sessions(&self) -> usize {
+        let users = self.users.lock().unwrap();
+        users.values().map(|sessions| sessions.len()).sum()
+    }
+}
+
+/// A connected websocket client, removed from the registry when dropped
+#[derive(Debug)]
+pub struct Session {
+    id: u64,
+    user: String,
+    registry: Arc<Registry>,
+    pub rx: mpsc::UnboundedReceiver<ipc::Event>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.registry.unregister(&self.user, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge(user: &str) -> ipc::Event {
+        ipc::Event::Challenge(ipc::Challenge {
+            user: user.to_string(),
+            challenge: "abcd".to_string(),
+        })
+    }
+
+    #[test]
+    fn route_to_user() {
+        let registry = Arc::new(Registry::default());
+        let mut alice1 = registry.register("alice".to_string());
+        let mut alice2 = registry.register("alice".to_string());
+        let mut bob = registry.register("bob".to_string());
+        assert_eq!(registry.sessions(), 3);
+
+        registry.send_to("alice", challenge("alice"));
+        assert_eq!(alice1.rx.try_recv().unwrap(), challenge("alice"));
+        assert_eq!(alice2.rx.try_recv().unwrap(), challenge("alice"));
+        assert!(bob.rx.try_recv().is_err());
+
+        registry.broadcast(ipc::Event::Config);
+        assert_eq!(alice1.rx.try_recv().unwrap(), ipc::Event::Config);
+        assert_eq!(bob.rx.try_recv().unwrap(), ipc::Event::Config);
+
+        drop(alice1);
+        drop(bob);
+        assert_eq!(registry.sessions(), 1);
+        assert!(!registry.users.lock().unwrap().contains_key("bob"));
+    }
+
+    #[test]
+    fn no_lost_bursts() {
+        let registry = Arc::new(Registry::default());
+        let mut alice = registry.register("alice".to_string());
+        for _ in 0..1000 {
+            registry.send_to("alice", challenge("alice"));
+        }
+        for _ in 0..1000 {
+            assert_eq!(alice.rx.try_recv().unwrap(), challenge("alice"));
+        }
+    }
+}
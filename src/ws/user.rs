@@ -1,10 +1,12 @@
 use crate::errors::*;
+use crate::metrics::Metrics;
+use crate::sites::{Site, Sites, UserPath};
 use crate::ws;
+use crate::ws::registry::Session;
 use d3xs_protocol::ipc;
 use futures_util::{FutureExt, SinkExt, StreamExt};
 use std::sync::Arc;
-use tokio::sync::broadcast;
-use tokio::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::time;
 use warp::http::StatusCode;
 use warp::ws::Message;
@@ -25,6 +27,16 @@ fn generate_view(config: Option<&ipc::Config>, user: &str) -> Option<ipc::UiConf
     let Some(userdata) = config.users.get(user) else {
         return None;
     };
+    // guest links are hidden once they expire, even if the bridge hasn't sent an update yet
+    if let Some(expires) = userdata.expires {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        if now >= expires {
+            return None;
+        }
+    }
 
     let userdata = userdata.clone();
     debug!(
@@ -35,7 +47,9 @@ fn generate_view(config: Option<&ipc::Config>, user: &str) -> Option<ipc::UiConf
     let mut authorized = Vec::new();
     for auth in userdata.authorize {
         if let Some(door) = doors.remove(&auth) {
-            authorized.push(ipc::UiDoor::new(auth, door));
+            let token = userdata.offline.get(&auth).cloned();
+            let next_token = userdata.offline_next.get(&auth).cloned();
+            authorized.push(ipc::UiDoor::new(auth, door, token, next_token));
         }
     }
 
@@ -47,11 +61,10 @@ fn generate_view(config: Option<&ipc::Config>, user: &str) -> Option<ipc::UiConf
 
 async fn ws_connect(
     mut ws: WebSocket,
-    config: Arc<RwLock<Option<ipc::Config>>>,
+    site: Arc<Site>,
+    mut session: Session,
     user: String,
     view: ipc::UiConfig,
-    mut event_rx: broadcast::Receiver<ipc::Event>,
-    request_tx: broadcast::Sender<ipc::ClientRequest>,
 ) -> Result<()> {
     let json = serde_json::to_string(&ipc::ClientResponse::Config(view))?;
     ws.send(Message::text(json)).await?;
@@ -62,10 +75,10 @@ async fn ws_connect(
             // ping clients at interval
             _ = ping.tick() => ws.send(Message::ping(vec![])).await?,
             // subscribe to events from bridge
-            msg = event_rx.recv() => if let Ok(msg) = msg {
+            msg = session.rx.recv() => if let Some(msg) = msg {
                 match msg {
                     ipc::Event::Config => {
-                        let config = config.read().await;
+                        let config = site.config.read().await;
                         if let Some(ui) = generate_view(config.as_ref(), &user) {
                             let json = serde_json::to_string(&ipc::ClientResponse::Config(ui))?;
                             ws.send(Message::text(json)).await?;
@@ -74,10 +87,14 @@ async fn ws_connect(
                             return Ok(());
                         };
                     },
-                    ipc::Event::Challenge(chall) => if chall.user == user {
+                    ipc::Event::Challenge(chall) => {
                         let json = serde_json::to_string(&ipc::ClientResponse::Challenge(chall))?;
                         ws.send(Message::text(json)).await?;
                     }
+                    ipc::Event::OpenResult(result) => {
+                        let json = serde_json::to_string(&ipc::ClientResponse::OpenResult(result))?;
+                        ws.send(Message::text(json)).await?;
+                    }
                 }
             } else {
                 return Ok(());
@@ -88,14 +105,19 @@ async fn ws_connect(
                 let Ok(msg) = msg.to_str() else { continue };
                 let Ok(mut req) = serde_json::from_str::<ipc::ClientRequest>(msg) else {
                     warn!("websocket client sent invalid json");
+                    Metrics::inc(&site.metrics.malformed_json_user);
                     continue;
                 };
                 debug!("Received request: {req:?}");
                 match &mut req {
                     ipc::ClientRequest::Fetch(fetch) => fetch.user = Some(user.clone()),
                     ipc::ClientRequest::Solve(solve) => solve.user = Some(user.clone()),
+                    ipc::ClientRequest::Auth(_) => {
+                        warn!("websocket client sent auth request");
+                        continue;
+                    }
                 }
-                request_tx.send(req).ok();
+                site.request_tx.send(req).ok();
             } else {
                 return Ok(());
             }
@@ -104,23 +126,26 @@ async fn ws_connect(
 }
 
 pub async fn websocket(
-    config: Arc<RwLock<Option<ipc::Config>>>,
-    event_tx: broadcast::Sender<ipc::Event>,
-    request_tx: broadcast::Sender<ipc::ClientRequest>,
-    user: String,
+    sites: Arc<Sites>,
+    path: UserPath,
     ws: warp::ws::Ws,
 ) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let Some(site) = sites.get(path.site.as_deref()).cloned() else {
+        return Ok(Box::new(StatusCode::NOT_FOUND));
+    };
+    let user = path.user;
+
     let ui = {
-        let config = config.read().await;
+        let config = site.config.read().await;
         let Some(view) = generate_view(config.as_ref(), &user) else {
             return Ok(Box::new(StatusCode::NOT_FOUND));
         };
         view
     };
 
-    let event_rx = event_tx.subscribe();
+    let session = site.registry.register(user.clone());
     let reply = ws.on_upgrade(move |websocket| {
-        ws_connect(websocket, config, user, ui, event_rx, request_tx).map(|result| {
+        ws_connect(websocket, site, session, user, ui).map(|result| {
             if let Err(err) = result {
                 error!("client websocket error: {err:#}");
             }